@@ -0,0 +1,78 @@
+use crate::decomposition::Decomposition;
+use crate::sampling::Sampling;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Collects the compute time of every tile at each rebalancing check and
+/// hands all of them the same, possibly moved, decomposition back.
+///
+/// Checks are identified by the iteration they happen after. A tile that
+/// reports waits until every other tile has reported the same check, so all
+/// of them leave with identical cuts. Cuts stay on the edges of averaged
+/// output blocks, see `Sampling::align`.
+pub struct LoadBalancer {
+    threshold: f64,
+    sampling: Sampling,
+    state: Mutex<BalanceState>,
+    decided: watch::Sender<(usize, Decomposition)>,
+}
+
+struct BalanceState {
+    decomp: Decomposition,
+    busy: Vec<f64>,
+    reported: usize,
+}
+
+impl LoadBalancer {
+    pub fn new(decomp: Decomposition, threshold: f64, sampling: Sampling) -> Self {
+        let num_tiles = decomp.num_tiles();
+        let (decided, _) = watch::channel((0, decomp.clone()));
+        LoadBalancer {
+            threshold,
+            sampling,
+            state: Mutex::new(BalanceState {
+                decomp,
+                busy: vec![0.0; num_tiles],
+                reported: 0,
+            }),
+            decided,
+        }
+    }
+
+    /// Records that tile `tid` spent `busy` computing since the last check
+    /// and returns the decomposition to use from iteration `iter + 1` on.
+    pub async fn report(&self, iter: usize, tid: usize, busy: Duration) -> Decomposition {
+        let mut decided = self.decided.subscribe();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.busy[tid] = busy.as_secs_f64();
+            state.reported += 1;
+            if state.reported == state.busy.len() {
+                let next = state
+                    .decomp
+                    .rebalanced(&state.busy, self.threshold)
+                    .and_then(|next| self.sampling.align(&next, Some(&state.decomp)))
+                    .filter(|next| *next != state.decomp);
+                if let Some(next) = next {
+                    let mean = state.busy.iter().sum::<f64>() / state.busy.len() as f64;
+                    let max = state.busy.iter().cloned().fold(0.0, f64::max);
+                    println!(
+                        "Rebalanced after iteration {} (busiest tile {:.2}x the mean): {}",
+                        iter,
+                        max / mean,
+                        next
+                    );
+                    state.decomp = next;
+                }
+                state.reported = 0;
+                self.decided.send_replace((iter + 1, state.decomp.clone()));
+            }
+        }
+        let decided = decided
+            .wait_for(|(after, _)| *after > iter)
+            .await
+            .expect("load balancer dropped");
+        decided.1.clone()
+    }
+}
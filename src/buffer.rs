@@ -1,6 +1,33 @@
 use crate::controlblock::ControlBlock;
 use std::vec::Vec;
 
+/// Side length of the square blocks used to mask out obstacle interiors.
+pub const MASK_BLOCK: usize = 16;
+
+/// Half-open bounding box `[r0, r1) x [c0, c1)` in local grid coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveBox {
+    pub r0: usize,
+    pub r1: usize,
+    pub c0: usize,
+    pub c1: usize,
+}
+
+/// Axis along which tile boundaries move when tiles are rebalanced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Rows,
+    Cols,
+}
+
+/// Where `ArrBuffer::reslice` takes a row or column of the new grid from.
+enum Line<'s> {
+    /// a line of the old grid, by local index
+    Old(usize),
+    /// line `idx` of a strip holding `count` lines per plane
+    Strip(&'s [f64], usize, usize),
+}
+
 #[derive(Debug)]
 pub struct ArrBuffer<'a> {
     pub cb: &'a ControlBlock, // 这里是引用，生命周期由 'a 指定
@@ -16,61 +43,24 @@ pub struct ArrBuffer<'a> {
     pub prev_offset: usize,
     pub curr_offset: usize,
     pub next_offset: usize,
+    /// Box holding every non-zero value of the prev, cur and next planes;
+    /// `None` while the whole tile is still at rest. `compute_u` relies on
+    /// it: cells more than one stencil radius outside it stay exactly zero,
+    /// so only the box grown by one cell has to be computed. Anything that
+    /// writes a non-zero value must `mark_active` its cell.
+    pub active: Option<ActiveBox>,
+    pub sources: Vec<(usize, usize)>,
+    pub block_mask: Vec<bool>,
 }
 
 impl<'a> ArrBuffer<'a> {
     pub fn new(cb: &'a ControlBlock, t_id: i32) -> Self {
-        let (m, n, grid_m, grid_n);
-        let mut start_rows = Vec::new();
-        let mut start_cols = Vec::new();
-
-        // 根据传入的 px 和 py 来确定 m, n, grid_m, grid_n
-        if cb.px * cb.py == 1 {
-            m = cb.m;
-            n = cb.n;
-            grid_m = cb.m + 2;
-            grid_n = cb.n + 2;
-        } else {
-            n = cb.n / cb.px
-                + if Self::get_extra_col(t_id, &cb) {
-                    1
-                } else {
-                    0
-                };
-            m = cb.m / cb.py
-                + if Self::get_extra_row(t_id, &cb) {
-                    1
-                } else {
-                    0
-                };
-            grid_n = n + 2;
-            grid_m = m + 2;
-        }
-
-        // 计算 startRows 和 startCols
-        let mut start_c = 0;
-        for col in 0..cb.px {
-            start_cols.push(start_c);
-            start_c += cb.n / cb.px
-                + if Self::get_extra_col(col.try_into().unwrap(), &cb) {
-                    1
-                } else {
-                    0
-                };
-        }
-
-        let mut start_r = 0;
-        for row in 0..cb.py {
-            start_rows.push(start_r);
-            start_r += cb.m / cb.py
-                + if Self::get_extra_row(row.try_into().unwrap(), &cb) {
-                    1
-                } else {
-                    0
-                };
-        }
-        let start_row = start_rows[t_id as usize / cb.px as usize];
-        let start_col = start_cols[t_id as usize % cb.px as usize];
+        // 本 tile 负责的行列范围由 cb.decomp 决定
+        let (start_row, m) = cb.decomp.tile_rows(t_id as usize);
+        let (start_col, n) = cb.decomp.tile_cols(t_id as usize);
+        let grid_m = m + 2;
+        let grid_n = n + 2;
+
         // 计算内存池的大小，并初始化为零
         let total_size = 3 * grid_m * grid_n;
         let memory_pool = vec![0.0; total_size];
@@ -94,17 +84,12 @@ impl<'a> ArrBuffer<'a> {
             prev_offset,
             curr_offset,
             next_offset,
+            active: None,
+            sources: Vec::new(),
+            block_mask: Vec::new(),
         }
     }
 
-    fn get_extra_col(t_id: i32, cb: &ControlBlock) -> bool {
-        t_id as usize % cb.px < cb.n % cb.px
-    }
-
-    fn get_extra_row(t_id: i32, cb: &ControlBlock) -> bool {
-        t_id as usize / cb.px < cb.m % cb.py
-    }
-
     pub fn sum_sq(
         &self,
         r: usize,
@@ -158,6 +143,87 @@ impl<'a> ArrBuffer<'a> {
         self.next_offset = t;
     }
 
+    /// Grows the active box to cover local cell `(r, c)`.
+    pub fn mark_active(&mut self, r: usize, c: usize) {
+        self.active = Some(match self.active {
+            None => ActiveBox {
+                r0: r,
+                r1: r + 1,
+                c0: c,
+                c1: c + 1,
+            },
+            Some(b) => ActiveBox {
+                r0: b.r0.min(r),
+                r1: b.r1.max(r + 1),
+                c0: b.c0.min(c),
+                c1: b.c1.max(c + 1),
+            },
+        });
+    }
+
+    /// Widens the active box by `radius` cells after a time step. A box that
+    /// reaches the first interior row/column also takes in the ghost ring,
+    /// since the absorbing boundary reads the freshly computed edge values.
+    pub fn grow_active(&mut self, radius: usize) {
+        let (grid_m, grid_n) = (self.grid_m, self.grid_n);
+        if let Some(b) = self.active.as_mut() {
+            b.r0 = b.r0.saturating_sub(radius);
+            b.c0 = b.c0.saturating_sub(radius);
+            b.r1 = (b.r1 + radius).min(grid_m);
+            b.c1 = (b.c1 + radius).min(grid_n);
+            if b.r0 <= 1 {
+                b.r0 = 0;
+            }
+            if b.c0 <= 1 {
+                b.c0 = 0;
+            }
+            if b.r1 >= grid_m - 1 {
+                b.r1 = grid_m;
+            }
+            if b.c1 >= grid_n - 1 {
+                b.c1 = grid_n;
+            }
+        }
+    }
+
+    /// Remembers a stimulus position (global coordinates) so its block is
+    /// never masked out, even if it sits inside an obstacle.
+    pub fn add_source(&mut self, globr: usize, globc: usize) {
+        self.sources.push((globr, globc));
+    }
+
+    /// Marks every `MASK_BLOCK` x `MASK_BLOCK` block whose interior cells all
+    /// have zero alpha and which holds no stimulus. Such cells stay at zero
+    /// forever, so the kernel can skip them.
+    pub fn build_block_mask(&mut self) {
+        let block_rows = self.grid_m.div_ceil(MASK_BLOCK);
+        let block_cols = self.grid_n.div_ceil(MASK_BLOCK);
+        let mut mask = vec![true; block_rows * block_cols];
+        for r in 0..self.grid_m {
+            for c in 0..self.grid_n {
+                let interior = r >= 1 && r < self.grid_m - 1 && c >= 1 && c < self.grid_n - 1;
+                if interior && self.alp_v(r, c) != 0.0 {
+                    mask[(r / MASK_BLOCK) * block_cols + c / MASK_BLOCK] = false;
+                }
+            }
+        }
+        for &(globr, globc) in &self.sources {
+            if self.check_bounds(globr, globc) {
+                let (r, c) = self.map_to_local(globr as i32, globc as i32);
+                mask[(r / MASK_BLOCK) * block_cols + c / MASK_BLOCK] = false;
+            }
+        }
+        self.block_mask = mask;
+    }
+
+    pub fn is_masked_block(&self, br: usize, bc: usize) -> bool {
+        let block_cols = self.grid_n.div_ceil(MASK_BLOCK);
+        self.block_mask
+            .get(br * block_cols + bc)
+            .copied()
+            .unwrap_or(false)
+    }
+
     pub fn check_bounds(&self, r: usize, c: usize) -> bool {
         let start_r = self.start_row;
         let start_c = self.start_col;
@@ -199,9 +265,20 @@ impl<'a> ArrBuffer<'a> {
         let start = self.curr_offset + r * self.grid_n;
         self.memory_pool[start..start + self.grid_n].to_vec()
     }
+    /// Copies row `r` of the current plane into `out`, reusing its storage.
+    pub fn copy_row_into(&self, r: usize, out: &mut Vec<f64>) {
+        let start = self.curr_offset + r * self.grid_n;
+        out.clear();
+        out.extend_from_slice(&self.memory_pool[start..start + self.grid_n]);
+    }
     pub fn update_row(&mut self, r: usize, values: &[f64]) {
         let start = self.curr_offset + r * self.grid_n;
         self.memory_pool[start..start + self.grid_n].copy_from_slice(values);
+        for (c, &val) in values.iter().enumerate() {
+            if val.to_bits() != 0 {
+                self.mark_active(r, c);
+            }
+        }
     }
     pub fn extract_col(&self, c: usize) -> Vec<f64> {
         let mut col = Vec::with_capacity(self.grid_m);
@@ -211,10 +288,173 @@ impl<'a> ArrBuffer<'a> {
         }
         col
     }
+    /// Copies column `c` of the current plane into `out`, reusing its storage.
+    pub fn copy_col_into(&self, c: usize, out: &mut Vec<f64>) {
+        out.clear();
+        out.extend((0..self.grid_m).map(|r| self.memory_pool[self.curr_offset + r * self.grid_n + c]));
+    }
     pub fn update_col(&mut self, c: usize, values: &[f64]) {
         for (r, &val) in values.iter().enumerate() {
             let idx = self.curr_offset + r * self.grid_n + c;
             self.memory_pool[idx] = val;
+            if val.to_bits() != 0 {
+                self.mark_active(r, c);
+            }
+        }
+    }
+
+    /// Copies global rows (or columns) `g0..g1` of the prev, cur and alpha
+    /// planes, ghost cells across included, one plane after the other.
+    pub fn strip(&self, axis: Axis, g0: usize, g1: usize) -> Vec<f64> {
+        let planes = self.planes();
+        let mut out = Vec::with_capacity(3 * (g1 - g0) * self.grid_n.max(self.grid_m));
+        for data in planes {
+            match axis {
+                Axis::Rows => {
+                    for g in g0..g1 {
+                        let r = g - self.start_row + 1;
+                        out.extend_from_slice(&data[r * self.grid_n..(r + 1) * self.grid_n]);
+                    }
+                }
+                Axis::Cols => {
+                    for g in g0..g1 {
+                        let c = g - self.start_col + 1;
+                        out.extend((0..self.grid_m).map(|r| data[r * self.grid_n + c]));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Moves the tile to cover `new_len` rows (or columns) from global
+    /// `new_start`. Cells that stay are kept; `low` and `high` are the
+    /// `strip`s of the cells gained below and above the old range. The next
+    /// plane is cleared, and the active box and block mask are rebuilt.
+    pub fn reslice(
+        &mut self,
+        axis: Axis,
+        new_start: usize,
+        new_len: usize,
+        low: &[f64],
+        high: &[f64],
+    ) {
+        let (old_start, old_len) = match axis {
+            Axis::Rows => (self.start_row, self.m),
+            Axis::Cols => (self.start_col, self.n),
+        };
+        let (grid_m, grid_n) = match axis {
+            Axis::Rows => (new_len + 2, self.grid_n),
+            Axis::Cols => (self.grid_m, new_len + 2),
+        };
+        // one line is a row (Rows) or a column (Cols) of the new grid
+        let line = match axis {
+            Axis::Rows => grid_n,
+            Axis::Cols => grid_m,
+        };
+        let old_end = old_start + old_len;
+        let gained_low = old_start.saturating_sub(new_start);
+        let gained_high = (new_start + new_len).saturating_sub(old_end);
+        let mut pool = vec![0.0; 3 * grid_m * grid_n];
+        let mut alpha = vec![0.0; grid_m * grid_n];
+        let old_planes = [self.prev_offset, self.curr_offset];
+        for k in 0..new_len + 2 {
+            // ghost lines keep their old contents, interior ones follow their global index
+            let g = (new_start + k).wrapping_sub(1);
+            let source = if k == 0 {
+                Line::Old(0)
+            } else if k == new_len + 1 {
+                Line::Old(old_len + 1)
+            } else if g < old_start {
+                Line::Strip(low, g - new_start, gained_low)
+            } else if g >= old_end {
+                Line::Strip(high, g - old_end, gained_high)
+            } else {
+                Line::Old(g - old_start + 1)
+            };
+            for plane in 0..3 {
+                for i in 0..line {
+                    let v = match source {
+                        Line::Strip(strip, idx, count) => strip[(plane * count + idx) * line + i],
+                        Line::Old(old_k) => {
+                            let at = match axis {
+                                Axis::Rows => old_k * self.grid_n + i,
+                                Axis::Cols => i * self.grid_n + old_k,
+                            };
+                            if plane < 2 {
+                                self.memory_pool[old_planes[plane] + at]
+                            } else {
+                                self.alpha[at]
+                            }
+                        }
+                    };
+                    let at = match axis {
+                        Axis::Rows => k * grid_n + i,
+                        Axis::Cols => i * grid_n + k,
+                    };
+                    if plane < 2 {
+                        pool[plane * grid_m * grid_n + at] = v;
+                    } else {
+                        alpha[at] = v;
+                    }
+                }
+            }
+        }
+        match axis {
+            Axis::Rows => {
+                self.start_row = new_start;
+                self.m = new_len;
+            }
+            Axis::Cols => {
+                self.start_col = new_start;
+                self.n = new_len;
+            }
+        }
+        self.grid_m = grid_m;
+        self.grid_n = grid_n;
+        self.memory_pool = pool;
+        self.alpha = alpha;
+        self.prev_offset = 0;
+        self.curr_offset = grid_m * grid_n;
+        self.next_offset = 2 * grid_m * grid_n;
+        self.rescan_active();
+    }
+
+    /// The prev, cur and alpha planes, ghost cells included.
+    pub fn planes(&self) -> [&[f64]; 3] {
+        let size = self.grid_m * self.grid_n;
+        [
+            &self.memory_pool[self.prev_offset..self.prev_offset + size],
+            &self.memory_pool[self.curr_offset..self.curr_offset + size],
+            &self.alpha,
+        ]
+    }
+
+    /// Overwrites the prev, cur and alpha planes with saved ones of the same
+    /// shape. The next plane is cleared, and the active box and block mask
+    /// are rebuilt.
+    pub fn restore(&mut self, prev: &[f64], cur: &[f64], alpha: &[f64]) {
+        let size = self.grid_m * self.grid_n;
+        self.prev_offset = 0;
+        self.curr_offset = size;
+        self.next_offset = 2 * size;
+        self.memory_pool[..size].copy_from_slice(prev);
+        self.memory_pool[size..2 * size].copy_from_slice(cur);
+        self.memory_pool[2 * size..].fill(0.0);
+        self.alpha.copy_from_slice(alpha);
+        self.rescan_active();
+    }
+
+    // 扫描 prev/cur 中的非零值，重建活动区域和块掩码
+    fn rescan_active(&mut self) {
+        self.active = None;
+        for r in 0..self.grid_m {
+            for c in 0..self.grid_n {
+                if self.prev_v(r, c).to_bits() != 0 || self.cur_v(r, c).to_bits() != 0 {
+                    self.mark_active(r, c);
+                }
+            }
         }
+        self.build_block_mask();
     }
 }
@@ -0,0 +1,364 @@
+use crate::buffer::ArrBuffer;
+use crate::controlblock::ControlBlock;
+use crate::decomposition::Decomposition;
+use crate::transport::{bytes_to_f64s, f64s_to_bytes};
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Layout of a checkpoint file, all integers little endian:
+//   magic, version (u32), header length (u64), JSON `Header`,
+//   then one block per tile, in the order the tiles delivered them:
+//   tid, start_row, start_col, m, n, number of sources (u64 each),
+//   (source, tick) pairs (u64, f64), and the prev, cur and alpha planes
+//   of (m + 2) x (n + 2) f64 each.
+const MAGIC: &[u8; 8] = b"WAVE2DCK";
+pub const VERSION: u32 = 1;
+
+/// Run-wide part of a checkpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    /// The resolved settings of the run that wrote the checkpoint.
+    pub config: ControlBlock,
+    /// First iteration still to run.
+    pub next_iter: usize,
+    /// Number of the next frame written to the output.
+    pub next_frame: usize,
+    pub num_tiles: usize,
+}
+
+/// One tile's state between two iterations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileState {
+    pub tid: usize,
+    pub start_row: usize,
+    pub start_col: usize,
+    pub m: usize,
+    pub n: usize,
+    /// Tick of every source still running, by index in the config objects.
+    pub ticks: Vec<(usize, f64)>,
+    pub prev: Vec<f64>,
+    pub cur: Vec<f64>,
+    pub alpha: Vec<f64>,
+}
+
+impl TileState {
+    pub fn capture(tid: usize, buffers: &ArrBuffer<'_>, ticks: Vec<(usize, f64)>) -> Self {
+        let [prev, cur, alpha] = buffers.planes();
+        TileState {
+            tid,
+            start_row: buffers.start_row,
+            start_col: buffers.start_col,
+            m: buffers.m,
+            n: buffers.n,
+            ticks,
+            prev: prev.to_vec(),
+            cur: cur.to_vec(),
+            alpha: alpha.to_vec(),
+        }
+    }
+
+    fn plane_len(&self) -> usize {
+        (self.m + 2) * (self.n + 2)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for v in [
+            self.tid,
+            self.start_row,
+            self.start_col,
+            self.m,
+            self.n,
+            self.ticks.len(),
+        ] {
+            w.write_all(&(v as u64).to_le_bytes())?;
+        }
+        for &(source, tick) in &self.ticks {
+            w.write_all(&(source as u64).to_le_bytes())?;
+            w.write_all(&tick.to_le_bytes())?;
+        }
+        for plane in [&self.prev, &self.cur, &self.alpha] {
+            w.write_all(&f64s_to_bytes(plane))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a block up to its planes, which are left empty.
+    fn read_head<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut fields = [0usize; 6];
+        for v in fields.iter_mut() {
+            *v = read_u64(r)? as usize;
+        }
+        let [tid, start_row, start_col, m, n, num_ticks] = fields;
+        let mut ticks = Vec::with_capacity(num_ticks);
+        for _ in 0..num_ticks {
+            let source = read_u64(r)? as usize;
+            let tick = f64::from_bits(read_u64(r)?);
+            ticks.push((source, tick));
+        }
+        Ok(TileState {
+            tid,
+            start_row,
+            start_col,
+            m,
+            n,
+            ticks,
+            prev: vec![],
+            cur: vec![],
+            alpha: vec![],
+        })
+    }
+
+    fn read_planes<R: Read>(&mut self, r: &mut R) -> io::Result<()> {
+        let mut bytes = vec![0u8; self.plane_len() * 8];
+        for plane in [&mut self.prev, &mut self.cur, &mut self.alpha] {
+            r.read_exact(&mut bytes)?;
+            *plane = bytes_to_f64s(&bytes);
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut state = Self::read_head(r)?;
+        state.read_planes(r)?;
+        Ok(state)
+    }
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn open(path: &Path) -> io::Result<(Header, BufReader<File>)> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a wave_2d checkpoint".to_string()));
+    }
+    let mut version = [0u8; 4];
+    r.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(invalid(format!(
+            "checkpoint version {} is not supported (expected {})",
+            version, VERSION
+        )));
+    }
+    let mut json = vec![0u8; read_u64(&mut r)? as usize];
+    r.read_exact(&mut json)?;
+    let header = serde_json::from_slice(&json).map_err(|e| invalid(e.to_string()))?;
+    Ok((header, r))
+}
+
+/// Reads the header of the checkpoint at `path` together with the
+/// decomposition its tiles were in, which may differ from the one the run
+/// started with if it rebalanced.
+pub fn read_header(path: &Path) -> io::Result<(Header, Decomposition)> {
+    let (header, mut r) = open(path)?;
+    let (px, py) = (header.config.px, header.config.py);
+    let mut row_starts = vec![0; py + 1];
+    let mut col_starts = vec![0; px + 1];
+    row_starts[py] = header.config.m;
+    col_starts[px] = header.config.n;
+    let mut tiles = Vec::with_capacity(header.num_tiles);
+    for _ in 0..header.num_tiles {
+        let tile = TileState::read_head(&mut r)?;
+        r.seek_relative(3 * 8 * tile.plane_len() as i64)?;
+        if tile.tid >= header.num_tiles {
+            return Err(invalid(format!(
+                "checkpoint holds unknown tile {}",
+                tile.tid
+            )));
+        }
+        row_starts[tile.tid / px] = tile.start_row;
+        col_starts[tile.tid % px] = tile.start_col;
+        tiles.push(tile);
+    }
+    let decomp = Decomposition {
+        px,
+        py,
+        row_starts,
+        col_starts,
+    };
+    for tile in &tiles {
+        if decomp.tile_rows(tile.tid) != (tile.start_row, tile.m)
+            || decomp.tile_cols(tile.tid) != (tile.start_col, tile.n)
+        {
+            return Err(invalid(format!(
+                "tile {} does not fit the checkpoint's {}x{} layout",
+                tile.tid, px, py
+            )));
+        }
+    }
+    Ok((header, decomp))
+}
+
+/// Global rows (or columns) of the grid with ghost cells, shifted by one so
+/// the ghost line before the first row is 0, that a block spanning `len`
+/// lines from `start` provides: its own lines, plus the global ghost lines
+/// it borders.
+fn owned_lines(start: usize, len: usize, total: usize) -> Range<usize> {
+    let lo = if start == 0 { 0 } else { start + 1 };
+    let hi = if start + len == total {
+        total + 2
+    } else {
+        start + len + 1
+    };
+    lo..hi
+}
+
+fn overlap(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
+    a.start.max(b.start)..a.end.min(b.end)
+}
+
+/// Reads the state tile `tid` of `decomp` starts with, whatever layout wrote
+/// the checkpoint at `path`: its planes are put together from every saved
+/// block they overlap. Ghost cells between tiles hold the neighbours' values.
+pub fn read_resliced(path: &Path, decomp: &Decomposition, tid: usize) -> io::Result<TileState> {
+    let (header, mut r) = open(path)?;
+    let (total_m, total_n) = (header.config.m, header.config.n);
+    let (start_row, m) = decomp.tile_rows(tid);
+    let (start_col, n) = decomp.tile_cols(tid);
+    let rows = start_row..start_row + m + 2;
+    let cols = start_col..start_col + n + 2;
+    let size = (m + 2) * (n + 2);
+    let mut state = TileState {
+        tid,
+        start_row,
+        start_col,
+        m,
+        n,
+        ticks: vec![],
+        prev: vec![0.0; size],
+        cur: vec![0.0; size],
+        alpha: vec![0.0; size],
+    };
+    let mut filled = 0;
+    for k in 0..header.num_tiles {
+        let mut block = TileState::read_head(&mut r)?;
+        // every tile runs every source, so any block's ticks will do
+        if k == 0 {
+            state.ticks = block.ticks.clone();
+        }
+        let block_rows = overlap(&rows, &owned_lines(block.start_row, block.m, total_m));
+        let block_cols = overlap(&cols, &owned_lines(block.start_col, block.n, total_n));
+        if block_rows.is_empty() || block_cols.is_empty() {
+            r.seek_relative(3 * 8 * block.plane_len() as i64)?;
+            continue;
+        }
+        block.read_planes(&mut r)?;
+        let width = block_cols.len();
+        for g in block_rows.clone() {
+            let from = (g - block.start_row) * (block.n + 2) + block_cols.start - block.start_col;
+            let to = (g - start_row) * (n + 2) + block_cols.start - start_col;
+            for (dst, src) in [
+                (&mut state.prev, &block.prev),
+                (&mut state.cur, &block.cur),
+                (&mut state.alpha, &block.alpha),
+            ] {
+                dst[to..to + width].copy_from_slice(&src[from..from + width]);
+            }
+        }
+        filled += block_rows.len() * width;
+    }
+    if filled != size {
+        return Err(invalid(format!(
+            "checkpoint covers {} of the {} cells of tile {}",
+            filled, size, tid
+        )));
+    }
+    Ok(state)
+}
+
+/// Collects the tiles' states into a checkpoint file.
+///
+/// Blocks are streamed to `<path>.<next_iter>.part` as tiles deliver them;
+/// once the last tile of an iteration is in, the file replaces `path`, so a
+/// crash while writing leaves the previous checkpoint intact. Tiles are only
+/// held together by their halo exchanges, so a tile may hand in a later
+/// checkpoint before a distant one has finished an earlier one: each
+/// iteration has its own part file until it is complete.
+pub struct CheckpointWriter {
+    path: PathBuf,
+    config: ControlBlock,
+    pending: Mutex<BTreeMap<usize, Pending>>,
+}
+
+struct Pending {
+    file: BufWriter<File>,
+    written: usize,
+}
+
+impl CheckpointWriter {
+    pub fn new(cb: &ControlBlock) -> Self {
+        CheckpointWriter {
+            path: cb.checkpoint.clone(),
+            config: cb.clone(),
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn part_path(&self, next_iter: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}.part", next_iter));
+        PathBuf::from(name)
+    }
+
+    /// Adds one tile's state before iteration `next_iter`; `next_frame` is
+    /// the frame that iteration would write next.
+    pub fn add_tile(
+        &self,
+        next_iter: usize,
+        next_frame: usize,
+        tile: &TileState,
+    ) -> io::Result<()> {
+        let num_tiles = self.config.px * self.config.py;
+        let mut pending = self.pending.lock().unwrap();
+        let current = match pending.entry(next_iter) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let header = Header {
+                    config: self.config.clone(),
+                    next_iter,
+                    next_frame,
+                    num_tiles,
+                };
+                let json = serde_json::to_vec(&header).map_err(|e| invalid(e.to_string()))?;
+                let mut file = BufWriter::new(File::create(self.part_path(next_iter))?);
+                file.write_all(MAGIC)?;
+                file.write_all(&VERSION.to_le_bytes())?;
+                file.write_all(&(json.len() as u64).to_le_bytes())?;
+                file.write_all(&json)?;
+                entry.insert(Pending { file, written: 0 })
+            }
+        };
+        tile.write_to(&mut current.file)?;
+        current.written += 1;
+        if current.written == num_tiles {
+            // every tile hands its checkpoints in in order, so the earlier
+            // ones are complete by now
+            let done = pending.remove(&next_iter).unwrap();
+            let file = done.file.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(self.part_path(next_iter), &self.path)?;
+            println!(
+                "Checkpoint before iteration {} written to {}",
+                next_iter,
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,16 @@
-use clap::{value_parser, Arg, Command};
+use crate::checkpoint::read_header;
+use crate::decomposition::{choose_layout, Decomposition};
+use crate::fields::EXTRA_FIELDS;
+use crate::obstacle::active_mask;
+use crate::probe::{probes, Probe};
+use crate::sampling::Sampling;
+use crate::stimulus::DEFAULT_AMPLITUDE;
+use clap::error::ErrorKind;
+use clap::{value_parser, Arg, ArgMatches, Command};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize,Clone)]
 pub struct ControlBlock {
@@ -14,110 +22,1223 @@ pub struct ControlBlock {
     pub n: usize,
     pub stats_freq: usize,
     pub plot_freq: usize,
+    pub output_freq: usize,
+    /// File the frames are written to.
+    pub output: PathBuf,
+    /// CSV file the traces of `probes` go to; they also go to a `probes`
+    /// group of a netCDF output.
+    pub probe_output: PathBuf,
+    /// Cells whose `u` is recorded after every iteration, the config's
+    /// `probe` objects.
+    pub probes: Vec<Probe>,
+    /// Format of `output`: `netcdf`, `npy` (`data` alone), `npz`, `vtk`
+    /// (a .pvd collection of .vti frames), `png` (an image per frame) or
+    /// `y4m` (a video stream, to stdout if `output` is `-`).
+    pub format: String,
+    /// Frame rate of a `y4m` stream.
+    pub fps: usize,
+    /// How `png` and `y4m` frames are drawn: one of `COLORMAPS`, the values
+    /// at its ends (+-max|u| of each frame if none), and the colors of
+    /// obstacle cells and source marks.
+    pub colormap: String,
+    pub color_limits: Option<(f64, f64)>,
+    pub obstacle_color: [u8; 3],
+    pub source_color: [u8; 3],
+    /// Window `[output_start, output_stop)` of iterations that `output_freq`
+    /// picks frames from, counting from `output_start`.
+    pub output_start: usize,
+    pub output_stop: Option<usize>,
+    /// Iterations written as frames on top of those, in ascending order.
+    pub snapshots: Vec<usize>,
+    /// Grid spacing in metres and time step in seconds; they only scale the
+    /// coordinates written out, the scheme itself is dimensionless.
+    pub dx: f64,
+    pub dt: f64,
+    /// Deflate level of `data` (0: uncompressed) and whether it is shuffled.
+    pub deflate: i32,
+    pub shuffle: bool,
+    /// Chunk shape of `data` along time, y and x; the library's by default.
+    pub chunk: Option<Vec<usize>>,
+    /// Type `data` is stored as: `f64`, `f32` or `int16`.
+    pub precision: String,
+    /// `int16` packs `-pack_range..=pack_range` and clips whatever is beyond.
+    pub pack_range: f64,
+    /// Rectangle `[row, col, height, width]` of the grid the output shows,
+    /// the whole grid if none, and how it is reduced: every `stride`-th
+    /// cell, or the mean of `stride x stride` blocks if `average`.
+    pub crop: Option<[usize; 4]>,
+    pub stride: usize,
+    pub average: bool,
+    /// Fields written alongside `data`, out of `fields::EXTRA_FIELDS`.
+    pub fields: Vec<String>,
+    /// Tiles' blocks are written straight into the output one by one
+    /// instead of being gathered into whole frames first.
+    pub tile_writes: bool,
     pub px: usize,
     pub py: usize,
-    pub niters: usize
+    pub niters: usize,
+    pub transport: String,
+    pub port: u16,
+    pub socket_dir: Option<PathBuf>,
+    pub tile: Option<usize>,
+    pub decomp: Decomposition,
+    pub rebalance_freq: usize,
+    pub rebalance_threshold: f64,
+    pub verify: bool,
+    pub checkpoint: PathBuf,
+    pub checkpoint_freq: usize,
+    pub restart: Option<PathBuf>,
+    pub halo_timeout: u64,
+    /// Iteration and frame number the run starts from: 0 unless restarted.
+    pub first_iter: usize,
+    pub first_frame: usize,
+}
+
+/// Values `--format` takes.
+const OUTPUT_FORMATS: [&str; 6] = ["netcdf", "npy", "npz", "vtk", "png", "y4m"];
+
+/// `-x`/`-y` value: a tile count, or `auto` to derive it from the cores.
+fn parse_tiles(s: &str) -> Result<Option<usize>, String> {
+    if s == "auto" {
+        return Ok(None);
+    }
+    s.parse::<usize>()
+        .map(Some)
+        .map_err(|_| format!("expected a tile count or `auto`, got `{}`", s))
+}
+
+/// `--chunk` value: `frame` for one whole frame per chunk, or the chunk's
+/// extent along time, y and x.
+fn parse_chunk(spec: &str, m: usize, n: usize) -> Result<Vec<usize>, String> {
+    if spec == "frame" {
+        return Ok(vec![1, m, n]);
+    }
+    let sizes: Vec<usize> = spec
+        .split(',')
+        .map(|s| s.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("expected `frame` or TIME,Y,X chunk sizes, got `{}`", spec))?;
+    if sizes.len() != 3 || sizes.contains(&0) {
+        return Err(format!(
+            "a chunk needs three non-zero sizes (time, y, x), got `{}`",
+            spec
+        ));
+    }
+    Ok(sizes)
+}
+
+/// Values `--colormap` takes.
+pub const COLORMAPS: [&str; 3] = ["seismic", "viridis", "grayscale"];
+
+/// `--color-limits` value: `symmetric` for +-max|u| of every frame, or the
+/// fixed values at the two ends of the colormap.
+fn parse_color_limits(spec: &str) -> Result<Option<(f64, f64)>, String> {
+    if spec == "symmetric" {
+        return Ok(None);
+    }
+    let limits: Vec<f64> = spec
+        .split(',')
+        .map(|s| s.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("expected `symmetric` or LOW,HIGH, got `{}`", spec))?;
+    match limits[..] {
+        [low, high] if low < high && low.is_finite() && high.is_finite() => Ok(Some((low, high))),
+        _ => Err(format!("color limits need LOW < HIGH, got `{}`", spec)),
+    }
+}
+
+/// RRGGBB hex color, with or without a leading `#`.
+fn parse_color(spec: &str) -> Result<[u8; 3], String> {
+    let hex = spec.strip_prefix('#').unwrap_or(spec);
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!("expected an RRGGBB hex color, got `{}`", spec)),
+    }
+}
+
+/// `--crop` value: the first row and column of the rectangle and its size.
+fn parse_crop(spec: &str, m: usize, n: usize) -> Result<[usize; 4], String> {
+    let values: Vec<usize> = spec
+        .split(',')
+        .map(|s| s.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("expected ROW,COL,HEIGHT,WIDTH, got `{}`", spec))?;
+    let [row, col, height, width] = values[..] else {
+        return Err(format!("expected ROW,COL,HEIGHT,WIDTH, got `{}`", spec));
+    };
+    if height == 0 || width == 0 || row + height > m || col + width > n {
+        return Err(format!(
+            "the crop {} must be a non-empty part of the {}x{} grid",
+            spec, m, n
+        ));
+    }
+    Ok([row, col, height, width])
+}
+
+/// Sum of the amplitudes the config's sources are driven with, a rough
+/// bound on |u|.
+fn source_amplitudes(config: &Value) -> f64 {
+    config["objects"]
+        .as_array()
+        .map(|objects| {
+            objects
+                .iter()
+                .filter(|o| o["type"] == "sine")
+                .map(|o| o["amplitude"].as_f64().unwrap_or(DEFAULT_AMPLITUDE))
+                .map(f64::abs)
+                .sum()
+        })
+        .unwrap_or(0.0)
+}
+
+/// A command line that parses but does not describe a run, and the kind of
+/// error it is reported as.
+type Invalid = (ErrorKind, String);
+
+/// Turns what a `validate_*` helper found wrong into `cmd`'s usage error.
+fn checked<T>(cmd: &mut Command, result: Result<T, Invalid>) -> Result<T, clap::Error> {
+    result.map_err(|(kind, msg)| cmd.error(kind, msg))
+}
+
+/// Where a setting is taken from: the command line, else the checkpoint a
+/// restart continues, else the config file. Settings a restart does not
+/// keep skip the checkpoint.
+struct Sources<'a> {
+    matches: &'a ArgMatches,
+    saved: Option<&'a ControlBlock>,
+    file: &'a Value,
+}
+
+impl Sources<'_> {
+    fn arg<T: Clone + Send + Sync + 'static>(&self, id: &str) -> Option<T> {
+        self.matches.get_one::<T>(id).cloned()
+    }
+
+    fn flag(&self, id: &str) -> Option<bool> {
+        self.matches.get_flag(id).then_some(true)
+    }
+
+    fn list<T: Clone + Send + Sync + 'static>(&self, id: &str) -> Option<Vec<T>> {
+        self.matches.get_many::<T>(id).map(|v| v.cloned().collect())
+    }
+
+    /// `given` on the command line, else the setting `restore` reads from
+    /// the checkpoint, else `file`, what the config file says.
+    fn kept<T>(
+        &self,
+        given: Option<T>,
+        restore: impl FnOnce(&ControlBlock) -> Option<T>,
+        file: Option<T>,
+    ) -> Option<T> {
+        given.or_else(|| match self.saved {
+            Some(saved) => restore(saved),
+            None => file,
+        })
+    }
+
+    fn file_usize(&self, key: &str) -> Option<usize> {
+        self.file
+            .get(key)
+            .and_then(Value::as_u64)
+            .map(|v| v as usize)
+    }
+
+    fn file_f64(&self, key: &str) -> Option<f64> {
+        self.file.get(key).and_then(Value::as_f64)
+    }
+
+    fn file_bool(&self, key: &str) -> Option<bool> {
+        self.file.get(key).and_then(Value::as_bool)
+    }
+
+    fn file_str(&self, key: &str) -> Option<String> {
+        self.file.get(key).and_then(Value::as_str).map(String::from)
+    }
+
+    fn file_path(&self, key: &str) -> Option<PathBuf> {
+        self.file
+            .get(key)
+            .and_then(Value::as_str)
+            .map(PathBuf::from)
+    }
+
+    /// A config file value given as a string, or as an array read as its
+    /// values joined by commas.
+    fn file_list(&self, key: &str) -> Option<String> {
+        match self.file.get(key)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                Some(values.join(","))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Grid size, iterations and the units of the output coordinates.
+fn run_args() -> [Arg; 7] {
+    [
+        Arg::new("config").short('c').help("config file name"),
+        Arg::new("n").short('n').value_parser(value_parser!(usize)),
+        Arg::new("niters")
+            .short('i')
+            .value_parser(value_parser!(usize)),
+        Arg::new("stats-freq")
+            .short('s')
+            .value_parser(value_parser!(usize)),
+        Arg::new("plot")
+            .short('p')
+            .value_parser(value_parser!(usize)),
+        Arg::new("dx")
+            .long("dx")
+            .value_parser(value_parser!(f64))
+            .help("grid spacing in metres for the output coordinates (default: 1)"),
+        Arg::new("dt")
+            .long("dt")
+            .value_parser(value_parser!(f64))
+            .help("time step in seconds for the output time axis (default: 1)"),
+    ]
+}
+
+/// Which iterations are written out as frames.
+fn window_args() -> [Arg; 4] {
+    [
+        Arg::new("output-freq")
+            .short('f')
+            .long("output-freq")
+            .value_parser(value_parser!(usize))
+            .help("write a frame every N iterations (0: none besides --snapshots)"),
+        Arg::new("output-start")
+            .long("output-start")
+            .value_parser(value_parser!(usize))
+            .help("first iteration -f writes out"),
+        Arg::new("output-stop")
+            .long("output-stop")
+            .value_parser(value_parser!(usize))
+            .help("iteration from which -f writes no more frames"),
+        Arg::new("snapshots")
+            .long("snapshots")
+            .value_parser(value_parser!(usize))
+            .value_delimiter(',')
+            .num_args(1..)
+            .help("also write these iterations, e.g. 0,500,1999; without -f, only these"),
+    ]
+}
+
+/// Output window as given.
+struct WindowOptions {
+    freq: Option<usize>,
+    start: Option<usize>,
+    stop: Option<usize>,
+    snapshots: Option<Vec<usize>>,
+}
+
+fn parse_window(s: &Sources) -> WindowOptions {
+    let file_snapshots = s
+        .file
+        .get("--snapshots")
+        .and_then(Value::as_array)
+        .map(|v| {
+            v.iter()
+                .filter_map(|i| i.as_u64())
+                .map(|i| i as usize)
+                .collect()
+        });
+    WindowOptions {
+        freq: s.kept(
+            s.arg("output-freq"),
+            |cb| Some(cb.output_freq),
+            s.file_usize("-f"),
+        ),
+        start: s.kept(
+            s.arg("output-start"),
+            |cb| Some(cb.output_start),
+            s.file_usize("--output-start"),
+        ),
+        stop: s.kept(
+            s.arg("output-stop"),
+            |cb| cb.output_stop,
+            s.file_usize("--output-stop"),
+        ),
+        snapshots: s.kept(
+            s.list("snapshots"),
+            |cb| Some(cb.snapshots.clone()),
+            file_snapshots,
+        ),
+    }
+}
+
+/// Output frequency, window and snapshots of a run.
+struct Window {
+    freq: usize,
+    start: usize,
+    stop: Option<usize>,
+    snapshots: Vec<usize>,
+}
+
+fn validate_window(o: WindowOptions) -> Window {
+    // a list of snapshots on its own asks for just those frames
+    let freq = o.freq.unwrap_or(if o.snapshots.is_some() { 0 } else { 1 });
+    let mut snapshots = o.snapshots.unwrap_or_default();
+    snapshots.sort_unstable();
+    snapshots.dedup();
+    Window {
+        freq,
+        start: o.start.unwrap_or(0),
+        stop: o.stop,
+        snapshots,
+    }
+}
+
+/// Where the frames and probe traces go, in which format, with which
+/// fields.
+fn output_args() -> [Arg; 5] {
+    [
+        Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_parser(value_parser!(PathBuf))
+            .help("file the frames are written to, `-` for a y4m stream on stdout (default: output.nc, .npy, .npz, .pvd or .y4m after --format; png frames go to <stem>_NNNNNN.png)"),
+        Arg::new("probe-output")
+            .long("probe-output")
+            .value_parser(value_parser!(PathBuf))
+            .help("CSV file the traces of the config's probes are written to (default: <output stem>_probes.csv next to the output)"),
+        Arg::new("format")
+            .long("format")
+            .value_parser(OUTPUT_FORMATS)
+            .help("output format, netcdf, npy, npz, vtk, png or y4m (default: after the --output extension, else netcdf)"),
+        Arg::new("fields")
+            .long("fields")
+            .value_parser(EXTRA_FIELDS)
+            .value_delimiter(',')
+            .num_args(1..)
+            .help("also write these fields, e.g. dudt,energy"),
+        Arg::new("tile-writes")
+            .long("tile-writes")
+            .action(clap::ArgAction::SetTrue)
+            .help("write each tile's block into the output as it arrives, without whole-frame buffers"),
+    ]
+}
+
+/// Output files, format and fields as given.
+struct OutputOptions {
+    output: Option<PathBuf>,
+    probe_output: Option<PathBuf>,
+    format: Option<String>,
+    fields: Option<Vec<String>>,
+}
+
+fn parse_output(s: &Sources) -> OutputOptions {
+    let file_fields = s.file.get("--fields").and_then(Value::as_array).map(|v| {
+        v.iter()
+            .filter_map(|f| f.as_str())
+            .map(String::from)
+            .collect()
+    });
+    OutputOptions {
+        output: s.kept(
+            s.arg("output"),
+            |cb| Some(cb.output.clone()),
+            s.file_path("-o"),
+        ),
+        probe_output: s.kept(
+            s.arg("probe-output"),
+            |cb| Some(cb.probe_output.clone()),
+            s.file_path("--probe-output"),
+        ),
+        format: s.kept(
+            s.arg("format"),
+            |cb| Some(cb.format.clone()),
+            s.file_str("--format"),
+        ),
+        fields: s.kept(s.list("fields"), |cb| Some(cb.fields.clone()), file_fields),
+    }
+}
+
+/// Output files, format and fields of a run.
+struct Output {
+    output: PathBuf,
+    probe_output: PathBuf,
+    format: String,
+    fields: Vec<String>,
+}
+
+fn validate_output(o: OutputOptions) -> Result<Output, Invalid> {
+    let mut fields = o.fields.unwrap_or_default();
+    if let Some(unknown) = fields.iter().find(|f| !EXTRA_FIELDS.contains(&f.as_str())) {
+        return Err((
+            ErrorKind::InvalidValue,
+            format!(
+                "unknown field `{}`; expected one of {}",
+                unknown,
+                EXTRA_FIELDS.join(", ")
+            ),
+        ));
+    }
+    fields.sort_by_key(|f| EXTRA_FIELDS.iter().position(|e| e == f));
+    fields.dedup();
+    let to_stdout = o
+        .output
+        .as_ref()
+        .is_some_and(|path| path.as_os_str() == "-");
+    let format = o.format.unwrap_or_else(|| {
+        let ext = o.output.as_ref().and_then(|path| path.extension());
+        match ext.and_then(|ext| ext.to_str()) {
+            _ if to_stdout => "y4m".to_string(),
+            Some("npy") => "npy".to_string(),
+            Some("npz") => "npz".to_string(),
+            Some("pvd") => "vtk".to_string(),
+            Some("png") => "png".to_string(),
+            Some("y4m") => "y4m".to_string(),
+            _ => "netcdf".to_string(),
+        }
+    });
+    if !OUTPUT_FORMATS.contains(&format.as_str()) {
+        return Err((
+            ErrorKind::InvalidValue,
+            format!(
+                "unknown format `{}`; expected netcdf, npy, npz, vtk, png or y4m",
+                format
+            ),
+        ));
+    }
+    let output = o.output.unwrap_or_else(|| match format.as_str() {
+        "netcdf" => PathBuf::from("output.nc"),
+        "vtk" => PathBuf::from("output.pvd"),
+        ext => PathBuf::from(format!("output.{}", ext)),
+    });
+    let probe_output = o.probe_output.unwrap_or_else(|| {
+        let stem = match output.file_stem() {
+            Some(stem) if !to_stdout => stem.to_string_lossy().into_owned(),
+            _ => "output".to_string(),
+        };
+        output.with_file_name(format!("{}_probes.csv", stem))
+    });
+    if to_stdout && format != "y4m" {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            format!("only y4m streams to stdout, not {}", format),
+        ));
+    }
+    if ["npy", "png", "y4m"].contains(&format.as_str()) && !fields.is_empty() {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            format!(
+                "{} frames hold `data` alone; write --fields with --format npz",
+                format
+            ),
+        ));
+    }
+    Ok(Output {
+        output,
+        probe_output,
+        format,
+        fields,
+    })
+}
+
+/// How png and y4m frames are drawn.
+fn image_args() -> [Arg; 5] {
+    [
+        Arg::new("fps")
+            .long("fps")
+            .value_parser(value_parser!(usize))
+            .help("frame rate of a y4m stream (default: 25)"),
+        Arg::new("colormap")
+            .long("colormap")
+            .value_parser(COLORMAPS)
+            .help("colormap of png and y4m frames (default: seismic)"),
+        Arg::new("color-limits").long("color-limits").help(
+            "values at the ends of the colormap, `symmetric` or LOW,HIGH (default: symmetric)",
+        ),
+        Arg::new("obstacle-color")
+            .long("obstacle-color")
+            .help("RRGGBB color of obstacle cells in png and y4m frames (default: 000000)"),
+        Arg::new("source-color")
+            .long("source-color")
+            .help("RRGGBB color of the source marks in png and y4m frames (default: 00ff00)"),
+    ]
+}
+
+/// Image and video settings as given.
+struct ImageOptions {
+    fps: Option<usize>,
+    colormap: Option<String>,
+    color_limits: Option<String>,
+    obstacle_color: Option<String>,
+    source_color: Option<String>,
+}
+
+fn parse_image(s: &Sources) -> ImageOptions {
+    let hex = |color: [u8; 3]| color.map(|c| format!("{:02x}", c)).concat();
+    ImageOptions {
+        fps: s.kept(s.arg("fps"), |cb| Some(cb.fps), s.file_usize("--fps")),
+        colormap: s.kept(
+            s.arg("colormap"),
+            |cb| Some(cb.colormap.clone()),
+            s.file_str("--colormap"),
+        ),
+        color_limits: s.kept(
+            s.arg("color-limits"),
+            |cb| {
+                cb.color_limits
+                    .map(|(low, high)| format!("{},{}", low, high))
+            },
+            s.file_list("--color-limits"),
+        ),
+        obstacle_color: s.kept(
+            s.arg("obstacle-color"),
+            |cb| Some(hex(cb.obstacle_color)),
+            s.file_str("--obstacle-color"),
+        ),
+        source_color: s.kept(
+            s.arg("source-color"),
+            |cb| Some(hex(cb.source_color)),
+            s.file_str("--source-color"),
+        ),
+    }
+}
+
+/// How a run draws png and y4m frames.
+struct Image {
+    fps: usize,
+    colormap: String,
+    color_limits: Option<(f64, f64)>,
+    obstacle_color: [u8; 3],
+    source_color: [u8; 3],
+}
+
+fn validate_image(o: ImageOptions) -> Result<Image, Invalid> {
+    let invalid = |e| (ErrorKind::InvalidValue, e);
+    let fps = o.fps.unwrap_or(25);
+    if fps == 0 {
+        return Err(invalid("--fps must be at least 1".to_string()));
+    }
+    let colormap = o.colormap.unwrap_or_else(|| "seismic".to_string());
+    if !COLORMAPS.contains(&colormap.as_str()) {
+        return Err(invalid(format!(
+            "unknown colormap `{}`; expected one of {}",
+            colormap,
+            COLORMAPS.join(", ")
+        )));
+    }
+    Ok(Image {
+        fps,
+        colormap,
+        color_limits: o
+            .color_limits
+            .map_or(Ok(None), |spec| parse_color_limits(&spec))
+            .map_err(invalid)?,
+        obstacle_color: parse_color(o.obstacle_color.as_deref().unwrap_or("000000"))
+            .map_err(invalid)?,
+        source_color: parse_color(o.source_color.as_deref().unwrap_or("00ff00"))
+            .map_err(invalid)?,
+    })
+}
+
+/// How netCDF frames are stored.
+fn storage_args() -> [Arg; 5] {
+    [
+        Arg::new("deflate")
+            .long("deflate")
+            .value_parser(value_parser!(i32).range(0..=9))
+            .help("deflate level of the frames, 0-9 (default: 0, uncompressed)"),
+        Arg::new("shuffle")
+            .long("shuffle")
+            .action(clap::ArgAction::SetTrue)
+            .help("byte-shuffle the frames before deflating them"),
+        Arg::new("chunk")
+            .long("chunk")
+            .help("chunk shape of the frames, `frame` or TIME,Y,X (default: the library's)"),
+        Arg::new("precision")
+            .long("precision")
+            .value_parser(["f64", "f32", "int16"])
+            .help("store frames as f64, f32, or int16 packed with scale_factor"),
+        Arg::new("pack-range")
+            .long("pack-range")
+            .value_parser(value_parser!(f64))
+            .help("largest |u| --precision int16 can store (default: twice the summed source amplitudes)"),
+    ]
+}
+
+/// netCDF storage as given. A restart keeps the precision and pack range,
+/// as the frames still go to the same variable.
+struct StorageOptions {
+    deflate: Option<i32>,
+    shuffle: Option<bool>,
+    chunk: Option<String>,
+    precision: Option<String>,
+    pack_range: Option<f64>,
+}
+
+fn parse_storage(s: &Sources) -> StorageOptions {
+    let file_deflate = s.file.get("--deflate").and_then(Value::as_i64);
+    StorageOptions {
+        deflate: s.arg("deflate").or(file_deflate.map(|v| v as i32)),
+        shuffle: s.flag("shuffle").or(s.file_bool("--shuffle")),
+        chunk: s.arg("chunk").or(s.file_list("--chunk")),
+        precision: s.kept(
+            s.arg("precision"),
+            |cb| Some(cb.precision.clone()),
+            s.file_str("--precision"),
+        ),
+        pack_range: s.kept(
+            s.arg("pack-range"),
+            |cb| Some(cb.pack_range),
+            s.file_f64("--pack-range"),
+        ),
+    }
+}
+
+/// How a run stores its netCDF frames.
+struct Storage {
+    deflate: i32,
+    shuffle: bool,
+    chunk: Option<Vec<usize>>,
+    precision: String,
+    pack_range: f64,
+}
+
+/// Checks the storage of frames of `format` showing `sampling`; the pack
+/// range defaults to twice what the sources of `config` emit.
+fn validate_storage(
+    o: StorageOptions,
+    format: &str,
+    sampling: &Sampling,
+    config: &Value,
+) -> Result<Storage, Invalid> {
+    let precision = o.precision.unwrap_or_else(|| "f64".to_string());
+    if !["f64", "f32", "int16"].contains(&precision.as_str()) {
+        return Err((
+            ErrorKind::InvalidValue,
+            format!(
+                "unknown precision `{}`; expected f64, f32 or int16",
+                precision
+            ),
+        ));
+    }
+    // images are drawn from the values, whatever they are stored as
+    if !["netcdf", "png", "y4m"].contains(&format) && precision == "int16" {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            format!(
+                "--precision int16 needs netCDF's scale_factor; write {} as f32 or f64",
+                format
+            ),
+        ));
+    }
+    let pack_range = o
+        .pack_range
+        .unwrap_or_else(|| 2.0 * source_amplitudes(config));
+    if precision == "int16" && !(pack_range > 0.0 && pack_range.is_finite()) {
+        return Err((
+            ErrorKind::InvalidValue,
+            format!("--pack-range must be positive, got {}", pack_range),
+        ));
+    }
+    let chunk = o
+        .chunk
+        .map(|spec| parse_chunk(&spec, sampling.rows(), sampling.cols()))
+        .transpose()
+        .map_err(|e| (ErrorKind::InvalidValue, e))?;
+    Ok(Storage {
+        deflate: o.deflate.unwrap_or(0),
+        shuffle: o.shuffle.unwrap_or(false),
+        chunk,
+        precision,
+        pack_range,
+    })
+}
+
+/// Which cells of the grid the frames show.
+fn sampling_args() -> [Arg; 3] {
+    [
+        Arg::new("crop")
+            .long("crop")
+            .help("write only the cells of ROW,COL,HEIGHT,WIDTH (default: the whole grid)"),
+        Arg::new("stride")
+            .long("stride")
+            .value_parser(value_parser!(usize))
+            .help("write every Nth row and column of the crop (default: 1)"),
+        Arg::new("average")
+            .long("average")
+            .action(clap::ArgAction::SetTrue)
+            .help("write the mean of each --stride x --stride block instead of its first cell"),
+    ]
+}
+
+/// Crop and reduction of the frames as given.
+struct SamplingOptions {
+    crop: Option<String>,
+    stride: Option<usize>,
+    average: Option<bool>,
+}
+
+fn parse_sampling(s: &Sources) -> SamplingOptions {
+    SamplingOptions {
+        crop: s.kept(
+            s.arg("crop"),
+            |cb| cb.crop.map(|c| c.map(|v| v.to_string()).join(",")),
+            s.file_list("--crop"),
+        ),
+        stride: s.kept(
+            s.arg("stride"),
+            |cb| Some(cb.stride),
+            s.file_usize("--stride"),
+        ),
+        average: s.kept(
+            s.flag("average"),
+            |cb| Some(cb.average),
+            s.file_bool("--average"),
+        ),
+    }
+}
+
+/// Checks the crop and reduction of the frames of an `m` x `n` grid.
+fn validate_sampling(
+    o: SamplingOptions,
+    m: usize,
+    n: usize,
+) -> Result<(Option<[usize; 4]>, Sampling), Invalid> {
+    let crop = o
+        .crop
+        .map(|spec| parse_crop(&spec, m, n))
+        .transpose()
+        .map_err(|e| (ErrorKind::InvalidValue, e))?;
+    let stride = o.stride.unwrap_or(1);
+    if stride == 0 {
+        return Err((
+            ErrorKind::InvalidValue,
+            "--stride must be at least 1".to_string(),
+        ));
+    }
+    let [row, col, height, width] = crop.unwrap_or([0, 0, m, n]);
+    let sampling = Sampling {
+        row,
+        col,
+        height,
+        width,
+        stride,
+        average: o.average.unwrap_or(false),
+    };
+    if sampling.rows() == 0 || sampling.cols() == 0 {
+        return Err((
+            ErrorKind::InvalidValue,
+            format!(
+                "a {}x{} crop holds no whole {}x{} block to --average",
+                height, width, stride, stride
+            ),
+        ));
+    }
+    Ok((crop, sampling))
+}
+
+/// Checks the config's probes against an `m` x `n` grid.
+fn validate_probes(m: usize, n: usize, config: &Value) -> Result<Vec<Probe>, Invalid> {
+    probes(m, n, config).map_err(|e| (ErrorKind::InvalidValue, e))
+}
+
+/// How the grid is cut into tiles and how the tiles talk.
+fn layout_args() -> [Arg; 11] {
+    [
+        Arg::new("px")
+            .short('x')
+            .value_parser(parse_tiles)
+            .help("tiles along x, or auto"),
+        Arg::new("py")
+            .short('y')
+            .value_parser(parse_tiles)
+            .help("tiles along y, or auto"),
+        Arg::new("cores")
+            .long("cores")
+            .value_parser(value_parser!(usize))
+            .help("cores shared out by -x/-y auto (default: all available)"),
+        Arg::new("transport")
+            .long("transport")
+            .value_parser(["channel", "tcp", "unix", "shm"])
+            .help("halo transport; tcp/unix/shm run one process per tile"),
+        Arg::new("port")
+            .long("port")
+            .value_parser(value_parser!(u16))
+            .help("first TCP port used by --transport tcp"),
+        Arg::new("socket-dir")
+            .long("socket-dir")
+            .value_parser(value_parser!(PathBuf))
+            .help("directory for the sockets and shared file of --transport unix/shm"),
+        Arg::new("tile")
+            .long("tile")
+            .value_parser(value_parser!(usize))
+            .hide(true),
+        Arg::new("balance")
+            .short('b')
+            .long("balance")
+            .action(clap::ArgAction::SetTrue)
+            .help("size tiles by active (non-obstacle) cells instead of evenly"),
+        Arg::new("rebalance")
+            .short('r')
+            .long("rebalance")
+            .value_parser(value_parser!(usize))
+            .help("move tile boundaries by measured load every N iterations (0: never)"),
+        Arg::new("rebalance-threshold")
+            .long("rebalance-threshold")
+            .value_parser(value_parser!(f64))
+            .help("rebalance when the busiest tile exceeds the mean by this factor"),
+        Arg::new("nocomm")
+            .short('k')
+            .action(clap::ArgAction::SetTrue),
+    ]
+}
+
+/// Tile layout as given: `None` tile counts are `auto`.
+struct LayoutOptions {
+    px: Option<Option<usize>>,
+    py: Option<Option<usize>>,
+    balance: Option<bool>,
+    cores: Option<usize>,
+}
+
+fn parse_layout(s: &Sources) -> LayoutOptions {
+    let file_tiles = |key: &str| {
+        let val = s.file.get(key)?;
+        match val.as_u64() {
+            Some(v) => Some(Some(v as usize)),
+            None => (val.as_str() == Some("auto")).then_some(None),
+        }
+    };
+    LayoutOptions {
+        px: s.kept(s.arg("px"), |cb| Some(Some(cb.px)), file_tiles("-x")),
+        py: s.kept(s.arg("py"), |cb| Some(Some(cb.py)), file_tiles("-y")),
+        balance: s.flag("balance").or(s.file_bool("-b")),
+        cores: s.arg("cores"),
+    }
+}
+
+/// Cuts an `m` x `n` grid into tiles whose cuts do not split the blocks of
+/// `sampling`. A restart keeps the `saved` cuts unless asked for a layout.
+fn validate_layout(
+    o: LayoutOptions,
+    relayout: bool,
+    saved: Option<&Decomposition>,
+    m: usize,
+    n: usize,
+    sampling: &Sampling,
+    config: &Value,
+) -> Result<Decomposition, Invalid> {
+    let cores = o.cores.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let (px, py) = (o.px.unwrap_or(Some(1)), o.py.unwrap_or(Some(1)));
+    let decomp = match saved {
+        Some(saved) if !relayout => Ok(saved.clone()),
+        _ => choose_layout(m, n, cores, px, py).and_then(|(px, py)| {
+            if o.balance.unwrap_or(false) {
+                Decomposition::weighted(m, n, px, py, &active_mask(m, n, config))
+            } else {
+                Decomposition::even(m, n, px, py)
+            }
+        }),
+    }
+    .map_err(|e| (ErrorKind::ValueValidation, e.to_string()))?;
+    sampling.align(&decomp, None).ok_or_else(|| {
+        (
+            ErrorKind::ValueValidation,
+            format!(
+                "{} cannot be cut along the {}x{} blocks of --average; use fewer tiles",
+                decomp, sampling.stride, sampling.stride
+            ),
+        )
+    })
+}
+
+/// Checkpoints, restarts and checks on the run.
+fn checkpoint_args() -> [Arg; 5] {
+    [
+        Arg::new("verify")
+            .long("verify")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("restart")
+            .help("run serially and decomposed side by side and compare every frame"),
+        Arg::new("checkpoint")
+            .long("checkpoint")
+            .value_parser(value_parser!(PathBuf))
+            .help("checkpoint file (default: checkpoint.bin)"),
+        Arg::new("checkpoint-freq")
+            .long("checkpoint-freq")
+            .value_parser(value_parser!(usize))
+            .help("write a checkpoint every N iterations (0: never)"),
+        Arg::new("restart")
+            .long("restart")
+            .value_parser(value_parser!(PathBuf))
+            .help("continue the run saved in this checkpoint file"),
+        Arg::new("halo-timeout")
+            .long("halo-timeout")
+            .value_parser(value_parser!(u64))
+            .help("seconds a tile waits on a neighbour before failing the run (0: forever)"),
+    ]
+}
+
+/// Checks that a restart from `path`, saved with the settings `saved`,
+/// writes the same frames of the same grid.
+fn validate_restart(
+    path: &Path,
+    saved: &ControlBlock,
+    (m, n): (usize, usize),
+    storage: &Storage,
+    output: &Output,
+    sampling: &Sampling,
+) -> Result<(), Invalid> {
+    let conflict = |msg| Err((ErrorKind::ArgumentConflict, msg));
+    if (m, n) != (saved.m, saved.n) {
+        return conflict(format!(
+            "{} holds a {}x{} grid; a restart cannot change its size",
+            path.display(),
+            saved.m,
+            saved.n
+        ));
+    }
+    if storage.precision != saved.precision {
+        return conflict(format!(
+            "{} was stored as {}; a restart cannot change the precision",
+            path.display(),
+            saved.precision
+        ));
+    }
+    if output.fields != saved.fields {
+        return conflict(format!(
+            "{} wrote the fields [{}]; a restart cannot change them",
+            path.display(),
+            saved.fields.join(", ")
+        ));
+    }
+    if *sampling != saved.sampling() {
+        return conflict(format!(
+            "{} wrote frames of {:?}; a restart cannot change their cells",
+            path.display(),
+            saved.sampling()
+        ));
+    }
+    Ok(())
 }
 
 impl ControlBlock {
+    /// Settings of the run `args` describe, the program name first. Each
+    /// comes from the command line, else the checkpoint restarted from,
+    /// else the `-c` config file, else its default. Exits with a usage
+    /// error when they do not make a run.
     pub fn new(args: Vec<String>) -> Self {
-        let matches = Command::new("controlblock")
-            .arg(Arg::new("config").short('c').help("config file name"))
-            .arg(Arg::new("n").short('n').value_parser(value_parser!(usize)))
-            .arg(
-                Arg::new("niters")
-                    .short('i')
-                    .value_parser(value_parser!(usize)),
-            )
-            .arg(
-                Arg::new("stats-freq")
-                    .short('s')
-                    .value_parser(value_parser!(usize)),
-            )
-            .arg(
-                Arg::new("plot")
-                    .short('p')
-                    .value_parser(value_parser!(usize)),
-            )
-            .arg(Arg::new("px").short('x').value_parser(value_parser!(usize)))
-            .arg(Arg::new("py").short('y').value_parser(value_parser!(usize)))
-            .arg(
-                Arg::new("nocomm")
-                    .short('k')
-                    .action(clap::ArgAction::SetTrue),
-            )
-            .get_matches_from(args);
+        Self::try_new(args).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Settings of the run `args` describe, or the usage error `new` would
+    /// exit with.
+    pub fn try_new(args: Vec<String>) -> Result<Self, clap::Error> {
+        let mut cmd = Command::new("controlblock")
+            .args(run_args())
+            .args(window_args())
+            .args(output_args())
+            .args(image_args())
+            .args(storage_args())
+            .args(sampling_args())
+            .args(layout_args())
+            .args(checkpoint_args());
+        let matches = cmd.clone().try_get_matches_from(args)?;
         let program_path = std::env::current_exe().unwrap();
-        let config_file_name = matches.get_one::<String>("config").unwrap().to_string();
+        let config_file_name = matches
+            .get_one::<String>("config")
+            .cloned()
+            .unwrap_or_default();
         let project_root = std::env::current_dir().unwrap();
-        let absolute_file_path = project_root.join(&config_file_name);        
-        let mut m = 100;
-        let mut n = 100;
-        let mut stats_freq = 0;
-        let mut plot_freq = 0;
-        let mut px = 1;
-        let mut py = 1;
-        let mut niters = 100;
-        let config: Value = match fs::read_to_string(&absolute_file_path) {
+        let absolute_file_path = project_root.join(&config_file_name);
+        let file: Value = match fs::read_to_string(&absolute_file_path) {
             Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|_| Value::Null),
             Err(_) => Value::Null,
         };
+        let restart = matches.get_one::<PathBuf>("restart").cloned();
+        let resumed = match &restart {
+            Some(path) => Some(read_header(path).map_err(|e| {
+                cmd.error(
+                    ErrorKind::Io,
+                    format!("cannot restart from {}: {}", path.display(), e),
+                )
+            })?),
+            None => None,
+        };
+        let saved = resumed.as_ref().map(|(header, _)| &header.config);
+        let s = Sources {
+            matches: &matches,
+            saved,
+            file: &file,
+        };
+        // a restart runs the objects of the checkpoint
+        let config = saved.map_or_else(|| file.clone(), |cb| cb.config.clone());
+        let (m, n) = s
+            .kept(
+                s.arg::<usize>("n").map(|n| (n, n)),
+                |cb| Some((cb.m, cb.n)),
+                s.file_usize("-n").map(|n| (n, n)),
+            )
+            .unwrap_or((100, 100));
+        let niters = s
+            .kept(s.arg("niters"), |cb| Some(cb.niters), s.file_usize("-i"))
+            .unwrap_or(100);
+        let dx = s
+            .kept(s.arg("dx"), |cb| Some(cb.dx), s.file_f64("--dx"))
+            .unwrap_or(1.0);
+        let dt = s
+            .kept(s.arg("dt"), |cb| Some(cb.dt), s.file_f64("--dt"))
+            .unwrap_or(1.0);
 
-        if let Some(config_obj) = config.as_object() {
-            if let Some(val) = config_obj.get("-n") {
-                if let Some(v) = val.as_u64() {
-                    n = v as usize;
-                    m = n;
-                }
-            }
-            if let Some(val) = config_obj.get("-i") {
-                if let Some(v) = val.as_u64() {
-                    niters = v as usize;
-                }
-            }
-            if let Some(val) = config_obj.get("-x") {
-                if let Some(v) = val.as_u64() {
-                    px = v as usize;
-                }
-            }
-            if let Some(val) = config_obj.get("-y") {
-                if let Some(v) = val.as_u64() {
-                    py = v as usize;
-                }
-            }
-        }
-        if matches.contains_id("n") {
-            n = *matches.get_one("n").unwrap();
-            m = n;
-        }
-        if matches.contains_id("niters") {
-            niters = *matches.get_one("niters").unwrap();
-        }
-        if matches.contains_id("stats-freq") {
-            stats_freq = *matches.get_one("stats-freq").unwrap();
-        }
-        if matches.contains_id("plot") {
-            plot_freq = *matches.get_one("plot").unwrap();
-        }
-
-        if matches.contains_id("px") {
-            px = *matches.get_one("px").unwrap();
-        }
-        if matches.contains_id("py") {
-            py = *matches.get_one("py").unwrap();
+        let window = validate_window(parse_window(&s));
+        let output = checked(&mut cmd, validate_output(parse_output(&s)))?;
+        let image = checked(&mut cmd, validate_image(parse_image(&s)))?;
+        let (crop, sampling) = checked(&mut cmd, validate_sampling(parse_sampling(&s), m, n))?;
+        let storage = validate_storage(parse_storage(&s), &output.format, &sampling, &config);
+        let storage = checked(&mut cmd, storage)?;
+        let probes = checked(&mut cmd, validate_probes(m, n, &config))?;
+        if let (Some(path), Some(saved)) = (&restart, saved) {
+            let restarted = validate_restart(path, saved, (m, n), &storage, &output, &sampling);
+            checked(&mut cmd, restarted)?;
         }
+        let relayout =
+            matches.contains_id("px") || matches.contains_id("py") || matches.get_flag("balance");
+        let decomp = validate_layout(
+            parse_layout(&s),
+            relayout,
+            resumed.as_ref().map(|(_, decomp)| decomp),
+            m,
+            n,
+            &sampling,
+            &config,
+        );
+        let decomp = checked(&mut cmd, decomp)?;
+        let (first_iter, first_frame) = resumed
+            .as_ref()
+            .map_or((0, 0), |(header, _)| (header.next_iter, header.next_frame));
 
-        ControlBlock {
+        Ok(ControlBlock {
             program_path,
             config_file_name,
             config,
             m,
             n,
-            stats_freq,
-            plot_freq,
-            px,
-            py,
-            niters
+            stats_freq: s.arg("stats-freq").unwrap_or(0),
+            plot_freq: s.arg("plot").unwrap_or(0),
+            output_freq: window.freq,
+            output: output.output,
+            probe_output: output.probe_output,
+            probes,
+            format: output.format,
+            fps: image.fps,
+            colormap: image.colormap,
+            color_limits: image.color_limits,
+            obstacle_color: image.obstacle_color,
+            source_color: image.source_color,
+            output_start: window.start,
+            output_stop: window.stop,
+            snapshots: window.snapshots,
+            dx,
+            dt,
+            deflate: storage.deflate,
+            shuffle: storage.shuffle,
+            chunk: storage.chunk,
+            precision: storage.precision,
+            pack_range: storage.pack_range,
+            crop,
+            stride: sampling.stride,
+            average: sampling.average,
+            fields: output.fields,
+            tile_writes: s
+                .flag("tile-writes")
+                .or(s.file_bool("--tile-writes"))
+                .unwrap_or(false),
+            px: decomp.px,
+            py: decomp.py,
+            niters,
+            transport: s.arg("transport").unwrap_or_else(|| "channel".to_string()),
+            port: s.arg("port").unwrap_or(47000),
+            socket_dir: s.arg("socket-dir"),
+            tile: s.arg("tile"),
+            decomp,
+            rebalance_freq: s.arg("rebalance").or(s.file_usize("-r")).unwrap_or(0),
+            rebalance_threshold: s
+                .arg("rebalance-threshold")
+                .or(s.file_f64("--rebalance-threshold"))
+                .unwrap_or(1.1),
+            verify: matches.get_flag("verify"),
+            checkpoint: s
+                .arg("checkpoint")
+                .or(s.file_path("--checkpoint"))
+                .unwrap_or_else(|| PathBuf::from("checkpoint.bin")),
+            checkpoint_freq: s
+                .arg("checkpoint-freq")
+                .or(s.file_usize("--checkpoint-freq"))
+                .unwrap_or(0),
+            restart,
+            halo_timeout: s
+                .arg("halo-timeout")
+                .or(s.file.get("--halo-timeout").and_then(Value::as_u64))
+                .unwrap_or(120),
+            first_iter,
+            first_frame,
+        })
+    }
+
+    /// Cells of the grid the output frames show.
+    pub fn sampling(&self) -> Sampling {
+        let [row, col, height, width] = self.crop.unwrap_or([0, 0, self.m, self.n]);
+        Sampling {
+            row,
+            col,
+            height,
+            width,
+            stride: self.stride,
+            average: self.average,
         }
     }
+
+    /// Whether the frames are streamed to stdout rather than to a file.
+    pub fn streams_to_stdout(&self) -> bool {
+        self.output.as_os_str() == "-"
+    }
+
+    /// Whether the state at iteration `iter` is written out as a frame:
+    /// it is a snapshot, or falls on the output frequency inside the output
+    /// window. An output frequency of 0 leaves only the snapshots.
+    pub fn is_output_iter(&self, iter: usize) -> bool {
+        let in_window =
+            iter >= self.output_start && self.output_stop.is_none_or(|stop| iter < stop);
+        self.snapshots.binary_search(&iter).is_ok()
+            || (self.output_freq != 0
+                && in_window
+                && (iter - self.output_start).is_multiple_of(self.output_freq))
+    }
+
+    /// Whether tiles report their load after iteration `iter` and possibly
+    /// move their boundaries before the next one.
+    pub fn is_rebalance_iter(&self, iter: usize) -> bool {
+        self.rebalance_freq != 0
+            && (iter + 1).is_multiple_of(self.rebalance_freq)
+            && iter + 1 < self.niters
+    }
+
+    /// Whether every tile saves its state after iteration `iter`.
+    pub fn is_checkpoint_iter(&self, iter: usize) -> bool {
+        self.checkpoint_freq != 0 && (iter + 1).is_multiple_of(self.checkpoint_freq)
+    }
+
+    /// Number of frames a full run writes.
+    pub fn num_frames(&self) -> usize {
+        (0..self.niters).filter(|&i| self.is_output_iter(i)).count()
+    }
+
+    /// Iterations this run writes as frames, from `first_iter` on.
+    pub fn output_iters(&self) -> Vec<usize> {
+        (self.first_iter..self.niters)
+            .filter(|&i| self.is_output_iter(i))
+            .collect()
+    }
 }
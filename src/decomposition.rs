@@ -0,0 +1,408 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Smallest number of interior rows or columns a tile may hold.
+pub const MIN_TILE: usize = 2;
+
+/// How the m x n domain is cut into `px` x `py` tiles.
+///
+/// `row_starts` has `py + 1` entries and `col_starts` has `px + 1`; tile row
+/// `j` covers global rows `row_starts[j]..row_starts[j + 1]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decomposition {
+    pub px: usize,
+    pub py: usize,
+    pub row_starts: Vec<usize>,
+    pub col_starts: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecompositionError {
+    /// px or py is zero.
+    Empty { px: usize, py: usize },
+    /// More tiles along an axis than the domain has room for.
+    TooManyTiles {
+        axis: &'static str,
+        tiles: usize,
+        cells: usize,
+    },
+    /// No factorisation of `cores` fits the domain.
+    NoLayout { cores: usize, m: usize, n: usize },
+    /// The tile count fixed along `axis` does not divide `cores`, so the
+    /// other axis cannot take the rest.
+    Indivisible {
+        axis: &'static str,
+        tiles: usize,
+        cores: usize,
+    },
+}
+
+impl fmt::Display for DecompositionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompositionError::Empty { px, py } => {
+                write!(f, "a {}x{} layout has no tiles", px, py)
+            }
+            DecompositionError::TooManyTiles { axis, tiles, cells } => write!(
+                f,
+                "{} tiles along {} leave fewer than {} of its {} cells per tile",
+                tiles, axis, MIN_TILE, cells
+            ),
+            DecompositionError::NoLayout { cores, m, n } => write!(
+                f,
+                "no px x py layout of {} cores fits a {}x{} grid with at least {} cells per tile side",
+                cores, m, n, MIN_TILE
+            ),
+            DecompositionError::Indivisible { axis, tiles, cores } => write!(
+                f,
+                "{} tiles along {} do not divide the {} cores to share out",
+                tiles, axis, cores
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecompositionError {}
+
+impl Decomposition {
+    /// Splits rows and columns as evenly as possible; the first tiles along
+    /// each axis take one extra cell when the split is not exact.
+    pub fn even(m: usize, n: usize, px: usize, py: usize) -> Result<Self, DecompositionError> {
+        check_layout(m, n, px, py)?;
+        Ok(Decomposition {
+            px,
+            py,
+            row_starts: even_starts(m, py),
+            col_starts: even_starts(n, px),
+        })
+    }
+
+    /// Splits rows and columns so that every tile holds about the same number
+    /// of `active` cells (row-major m x n). The cuts still form a grid, so
+    /// neighbouring tiles keep matching edges; they are refined by
+    /// alternately re-cutting rows for the current columns and vice versa.
+    pub fn weighted(
+        m: usize,
+        n: usize,
+        px: usize,
+        py: usize,
+        active: &[bool],
+    ) -> Result<Self, DecompositionError> {
+        let mut decomp = Self::even(m, n, px, py)?;
+        let mut best = decomp.max_load(active, n);
+        for _ in 0..4 {
+            let row_starts =
+                balance_axis(m, py, &band_prefix(active, m, n, &decomp.col_starts, false));
+            let candidate = Decomposition {
+                row_starts,
+                ..decomp.clone()
+            };
+            let col_starts = balance_axis(
+                n,
+                px,
+                &band_prefix(active, m, n, &candidate.row_starts, true),
+            );
+            let candidate = Decomposition {
+                col_starts,
+                ..candidate
+            };
+            let load = candidate.max_load(active, n);
+            if load >= best {
+                break;
+            }
+            best = load;
+            decomp = candidate;
+        }
+        Ok(decomp)
+    }
+
+    /// New cuts for measured per-tile compute times `busy`, or `None` while
+    /// the busiest tile stays within `threshold` times the mean. Each tile's
+    /// time is spread evenly over its cells to estimate the cost of every
+    /// row and column. Boundaries move half way to the balanced cut and by at
+    /// most half of the smaller tile next to them, so cells only ever pass
+    /// between direct neighbours.
+    pub fn rebalanced(&self, busy: &[f64], threshold: f64) -> Option<Self> {
+        let mean = busy.iter().sum::<f64>() / busy.len() as f64;
+        let max = busy.iter().cloned().fold(0.0, f64::max);
+        if mean <= 0.0 || max <= threshold * mean {
+            return None;
+        }
+        // cost of one row (or column) of tile t, in integer units for balance_axis
+        let line_cost = |t: usize, len: usize| (busy[t] / mean * 1e6 / len as f64).round() as u64;
+        let m = *self.row_starts.last().unwrap();
+        let n = *self.col_starts.last().unwrap();
+        let rows: Vec<Vec<u64>> = (0..self.px)
+            .map(|i| {
+                prefix_sums((0..self.py).flat_map(|j| {
+                    let t = j * self.px + i;
+                    let len = self.tile_rows(t).1;
+                    std::iter::repeat_n(line_cost(t, len), len)
+                }))
+            })
+            .collect();
+        let cols: Vec<Vec<u64>> = (0..self.py)
+            .map(|j| {
+                prefix_sums((0..self.px).flat_map(|i| {
+                    let t = j * self.px + i;
+                    let len = self.tile_cols(t).1;
+                    std::iter::repeat_n(line_cost(t, len), len)
+                }))
+            })
+            .collect();
+        let next = Decomposition {
+            px: self.px,
+            py: self.py,
+            row_starts: limit_moves(&self.row_starts, &balance_axis(m, self.py, &rows)),
+            col_starts: limit_moves(&self.col_starts, &balance_axis(n, self.px, &cols)),
+        };
+        (next != *self).then_some(next)
+    }
+
+    /// Active cells of the busiest tile.
+    pub fn max_load(&self, active: &[bool], n: usize) -> u64 {
+        (0..self.num_tiles())
+            .map(|t| {
+                let (r0, rows) = self.tile_rows(t);
+                let (c0, cols) = self.tile_cols(t);
+                (r0..r0 + rows)
+                    .map(|r| {
+                        active[r * n + c0..r * n + c0 + cols]
+                            .iter()
+                            .filter(|&&a| a)
+                            .count() as u64
+                    })
+                    .sum::<u64>()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.px * self.py
+    }
+
+    /// First global row and number of rows of tile `t_id`.
+    pub fn tile_rows(&self, t_id: usize) -> (usize, usize) {
+        let j = t_id / self.px;
+        (
+            self.row_starts[j],
+            self.row_starts[j + 1] - self.row_starts[j],
+        )
+    }
+
+    /// First global column and number of columns of tile `t_id`.
+    pub fn tile_cols(&self, t_id: usize) -> (usize, usize) {
+        let i = t_id % self.px;
+        (
+            self.col_starts[i],
+            self.col_starts[i + 1] - self.col_starts[i],
+        )
+    }
+}
+
+impl fmt::Display for Decomposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (rmin, rmax) = size_range(&self.row_starts);
+        let (cmin, cmax) = size_range(&self.col_starts);
+        write!(f, "{}x{} tiles of ", self.px, self.py)?;
+        if rmin == rmax {
+            write!(f, "{}", rmin)?;
+        } else {
+            write!(f, "{}-{}", rmin, rmax)?;
+        }
+        write!(f, " rows x ")?;
+        if cmin == cmax {
+            write!(f, "{}", cmin)?;
+        } else {
+            write!(f, "{}-{}", cmin, cmax)?;
+        }
+        write!(f, " cols")
+    }
+}
+
+fn size_range(starts: &[usize]) -> (usize, usize) {
+    let sizes = starts.windows(2).map(|w| w[1] - w[0]);
+    (sizes.clone().min().unwrap_or(0), sizes.max().unwrap_or(0))
+}
+
+fn even_starts(cells: usize, parts: usize) -> Vec<usize> {
+    let mut starts = Vec::with_capacity(parts + 1);
+    let mut start = 0;
+    for i in 0..parts {
+        starts.push(start);
+        start += cells / parts + if i < cells % parts { 1 } else { 0 };
+    }
+    starts.push(start);
+    starts
+}
+
+/// Rejects layouts that would leave a tile with fewer than `MIN_TILE` rows
+/// or columns.
+pub fn check_layout(m: usize, n: usize, px: usize, py: usize) -> Result<(), DecompositionError> {
+    if px == 0 || py == 0 {
+        return Err(DecompositionError::Empty { px, py });
+    }
+    if py > 1 && m / py < MIN_TILE {
+        return Err(DecompositionError::TooManyTiles {
+            axis: "y",
+            tiles: py,
+            cells: m,
+        });
+    }
+    if px > 1 && n / px < MIN_TILE {
+        return Err(DecompositionError::TooManyTiles {
+            axis: "x",
+            tiles: px,
+            cells: n,
+        });
+    }
+    Ok(())
+}
+
+/// Picks `px` x `py` with `px * py == cores` that minimises the total halo
+/// length `(px - 1) * m + (py - 1) * n`. A fixed `px` or `py` is kept and
+/// the other one takes the rest of the cores, which it must divide; ties go
+/// to the layout with more columns.
+pub fn choose_layout(
+    m: usize,
+    n: usize,
+    cores: usize,
+    px: Option<usize>,
+    py: Option<usize>,
+) -> Result<(usize, usize), DecompositionError> {
+    match (px, py) {
+        (Some(px), Some(py)) => {
+            check_layout(m, n, px, py)?;
+            Ok((px, py))
+        }
+        (Some(px), None) => {
+            let py = share(cores, "x", px)?;
+            check_layout(m, n, px, py)?;
+            Ok((px, py))
+        }
+        (None, Some(py)) => {
+            let px = share(cores, "y", py)?;
+            check_layout(m, n, px, py)?;
+            Ok((px, py))
+        }
+        (None, None) => (1..=cores)
+            .filter(|px| cores.is_multiple_of(*px))
+            .map(|px| (px, cores / px))
+            .filter(|&(px, py)| check_layout(m, n, px, py).is_ok())
+            .min_by_key(|&(px, py)| ((px - 1) * m + (py - 1) * n, usize::MAX - px))
+            .ok_or(DecompositionError::NoLayout { cores, m, n }),
+    }
+}
+
+/// Tiles left along the other axis once `tiles` are fixed along `axis`.
+/// Zero tiles are left to `check_layout` to reject.
+fn share(cores: usize, axis: &'static str, tiles: usize) -> Result<usize, DecompositionError> {
+    if tiles == 0 {
+        return Ok(cores);
+    }
+    if !cores.is_multiple_of(tiles) {
+        return Err(DecompositionError::Indivisible { axis, tiles, cores });
+    }
+    Ok(cores / tiles)
+}
+
+/// Prefix sums of active cells along one axis, one series per band of the
+/// other axis: `prefix[b][i]` counts the active cells of band `b` in the
+/// first `i` rows (or columns, when `by_col`).
+fn band_prefix(
+    active: &[bool],
+    m: usize,
+    n: usize,
+    bands: &[usize],
+    by_col: bool,
+) -> Vec<Vec<u64>> {
+    let len = if by_col { n } else { m };
+    bands
+        .windows(2)
+        .map(|w| {
+            let mut prefix = Vec::with_capacity(len + 1);
+            let mut sum = 0;
+            prefix.push(0);
+            for i in 0..len {
+                sum += (w[0]..w[1])
+                    .filter(|&k| {
+                        if by_col {
+                            active[k * n + i]
+                        } else {
+                            active[i * n + k]
+                        }
+                    })
+                    .count() as u64;
+                prefix.push(sum);
+            }
+            prefix
+        })
+        .collect()
+}
+
+fn prefix_sums(values: impl Iterator<Item = u64>) -> Vec<u64> {
+    let mut prefix = vec![0];
+    let mut sum = 0;
+    for v in values {
+        sum += v;
+        prefix.push(sum);
+    }
+    prefix
+}
+
+/// Moves each inner boundary of `old` half way towards `new`, since the
+/// timings behind `new` are noisy, and at most half of the smaller of its
+/// two pieces (less `MIN_TILE`), so no piece shrinks below `MIN_TILE` and
+/// every moved cell comes from the adjacent piece.
+fn limit_moves(old: &[usize], new: &[usize]) -> Vec<usize> {
+    let mut starts = old.to_vec();
+    for k in 1..old.len() - 1 {
+        let below = (old[k] - old[k - 1]).saturating_sub(MIN_TILE) / 2;
+        let above = (old[k + 1] - old[k]).saturating_sub(MIN_TILE) / 2;
+        let target = (old[k] + new[k]) / 2;
+        starts[k] = target.clamp(old[k] - below, old[k] + above);
+    }
+    starts
+}
+
+/// Cuts `len` cells into `parts` pieces of at least `MIN_TILE` cells that
+/// minimise the heaviest tile, where a piece `[a, b)` weighs
+/// `max_band prefix[band][b] - prefix[band][a]`.
+fn balance_axis(len: usize, parts: usize, prefix: &[Vec<u64>]) -> Vec<usize> {
+    if parts == 1 {
+        return vec![0, len];
+    }
+    let weight = |a: usize, b: usize| prefix.iter().map(|p| p[b] - p[a]).max().unwrap_or(0);
+    // greedy cut with each piece as long as `limit` allows
+    let cut = |limit: u64| -> Option<Vec<usize>> {
+        let mut starts = vec![0];
+        let mut a = 0;
+        for left in (1..parts).rev() {
+            let mut b = a + MIN_TILE;
+            if weight(a, b) > limit {
+                return None;
+            }
+            while b + 1 + MIN_TILE * left <= len && weight(a, b + 1) <= limit {
+                b += 1;
+            }
+            starts.push(b);
+            a = b;
+        }
+        if len - a < MIN_TILE || weight(a, len) > limit {
+            return None;
+        }
+        starts.push(len);
+        Some(starts)
+    };
+    let (mut lo, mut hi) = (0, weight(0, len));
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if cut(mid).is_some() {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    cut(lo).expect("the whole axis always fits")
+}
@@ -0,0 +1,104 @@
+use crate::buffer::ArrBuffer;
+
+/// A quantity written out with every frame, computed by each tile from its
+/// own planes. `U` is the frame itself and always comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    U,
+    /// (cur - prev) / dt
+    Dudt,
+    /// |grad u| from central differences
+    Gradient,
+    /// (du/dt)^2 / 2 + c^2 |grad u|^2 / 2, per unit density
+    Energy,
+    /// Alpha of every cell, 0 inside obstacles; it never changes, so only
+    /// the first frame's is stored.
+    Alpha,
+}
+
+/// Values `--fields` takes, in the order the fields are written.
+pub const EXTRA_FIELDS: [&str; 4] = ["dudt", "gradient", "energy", "alpha"];
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dudt" => Some(Field::Dudt),
+            "gradient" => Some(Field::Gradient),
+            "energy" => Some(Field::Energy),
+            "alpha" => Some(Field::Alpha),
+            _ => None,
+        }
+    }
+
+    /// `U` followed by the extra fields named in `names`, in a fixed order
+    /// whatever the order of `names`.
+    pub fn selected(names: &[String]) -> Vec<Field> {
+        let mut fields = vec![Field::U];
+        fields.extend(
+            EXTRA_FIELDS
+                .iter()
+                .filter(|&&name| names.iter().any(|n| n == name))
+                .filter_map(|&name| Field::from_name(name)),
+        );
+        fields
+    }
+
+    /// netCDF variable the field is stored in.
+    pub fn var_name(self) -> &'static str {
+        match self {
+            Field::U => "data",
+            Field::Dudt => "dudt",
+            Field::Gradient => "gradient",
+            Field::Energy => "energy",
+            Field::Alpha => "alpha",
+        }
+    }
+
+    pub fn long_name(self) -> &'static str {
+        match self {
+            Field::U => "wave displacement",
+            Field::Dudt => "time derivative of the displacement",
+            Field::Gradient => "magnitude of the displacement gradient",
+            Field::Energy => "energy density per unit density",
+            Field::Alpha => "squared Courant number (c dt / dx)^2, 0 in obstacles",
+        }
+    }
+
+    /// CF units, with the displacement itself dimensionless.
+    pub fn units(self) -> &'static str {
+        match self {
+            Field::U | Field::Alpha => "1",
+            Field::Dudt => "s-1",
+            Field::Gradient => "m-1",
+            Field::Energy => "s-2",
+        }
+    }
+
+    /// Whether the field is the same in every frame.
+    pub fn is_static(self) -> bool {
+        self == Field::Alpha
+    }
+
+    /// Value of the field at cell `(r, c)` of `u`, ghost ring included, so
+    /// interior cells start at `(1, 1)`. Ghost cells must hold the
+    /// neighbours' current values, as they do right after the exchange.
+    pub fn value(self, u: &ArrBuffer<'_>, r: usize, c: usize, dx: f64, dt: f64) -> f64 {
+        // differences across the cell, in grid units
+        let across = || {
+            let gx = (u.cur_v(r, c + 1) - u.cur_v(r, c - 1)) / 2.0;
+            let gy = (u.cur_v(r + 1, c) - u.cur_v(r - 1, c)) / 2.0;
+            gx * gx + gy * gy
+        };
+        match self {
+            Field::U => u.cur_v(r, c),
+            Field::Dudt => (u.cur_v(r, c) - u.prev_v(r, c)) / dt,
+            Field::Gradient => across().sqrt() / dx,
+            Field::Energy => {
+                let dudt = (u.cur_v(r, c) - u.prev_v(r, c)) / dt;
+                // c^2 |grad u|^2 = alpha dx^2 / dt^2 * across / dx^2
+                0.5 * dudt * dudt + 0.5 * u.alp_v(r, c) * across() / (dt * dt)
+            }
+            Field::Alpha => u.alp_v(r, c),
+        }
+    }
+}
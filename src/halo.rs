@@ -0,0 +1,470 @@
+use crate::buffer::{ArrBuffer, Axis};
+use crate::transport::HaloTransport;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Direction a halo message travels in, seen from the sending tile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+    ];
+
+    pub fn index(self) -> usize {
+        match self {
+            Direction::Up => 0,
+            Direction::Down => 1,
+            Direction::Left => 2,
+            Direction::Right => 3,
+        }
+    }
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+/// One ghost row or column, tagged with the exchange round it belongs to.
+/// Rounds follow the time steps, plus one per migration chunk when tiles are
+/// rebalanced.
+#[derive(Debug)]
+pub struct HaloMessage {
+    pub dir: Direction,
+    pub step: u64,
+    /// The sender's `HaloState::stop_at`.
+    pub stop_at: Option<u64>,
+    pub data: Vec<f64>,
+}
+
+#[derive(Debug)]
+pub enum HaloError {
+    /// A message arrived for a step other than the current or the next one.
+    WrongStep {
+        dir: Direction,
+        expected: u64,
+        got: u64,
+    },
+    /// Two messages travelling in the same direction for the same step.
+    Duplicate { dir: Direction, step: u64 },
+    /// A message arrived from a side that has no neighbour.
+    NoNeighbour { dir: Direction, step: u64 },
+    /// The ghost data does not match the length of the receiving edge.
+    Length {
+        dir: Direction,
+        expected: usize,
+        got: usize,
+    },
+    /// The channel to a neighbour has been closed.
+    Disconnected { dir: Direction },
+    /// Our own channel closed while ghost cells were still missing.
+    Closed,
+    /// Reading from the link to the neighbour on side `dir` failed.
+    Io { dir: Direction, error: io::Error },
+    /// The watchdog gave up on `tile`, which neither sent (or, when
+    /// `sending`, took) the `dir` message of `step` within `waited`.
+    Stalled {
+        dir: Direction,
+        step: u64,
+        tile: usize,
+        waited: Duration,
+        sending: bool,
+    },
+}
+
+impl fmt::Display for HaloError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaloError::WrongStep { dir, expected, got } => write!(
+                f,
+                "{:?} halo message for step {} arrived during step {}",
+                dir, got, expected
+            ),
+            HaloError::Duplicate { dir, step } => {
+                write!(f, "duplicate {:?} halo message for step {}", dir, step)
+            }
+            HaloError::NoNeighbour { dir, step } => write!(
+                f,
+                "{:?} halo message for step {} from a side without a neighbour",
+                dir, step
+            ),
+            HaloError::Length { dir, expected, got } => write!(
+                f,
+                "{:?} halo message holds {} values, edge has {}",
+                dir, got, expected
+            ),
+            HaloError::Disconnected { dir } => {
+                write!(f, "halo channel closed while sending {:?}", dir)
+            }
+            HaloError::Closed => write!(f, "halo channel closed while waiting for ghost cells"),
+            HaloError::Io { dir, error } => {
+                write!(f, "halo link on the {:?} side failed: {}", dir, error)
+            }
+            HaloError::Stalled {
+                dir,
+                step,
+                tile,
+                waited,
+                sending: false,
+            } => write!(
+                f,
+                "tile {} stalled: no {:?} halo message for step {} within {:?}",
+                tile, dir, step, waited
+            ),
+            HaloError::Stalled {
+                dir,
+                step,
+                tile,
+                waited,
+                sending: true,
+            } => write!(
+                f,
+                "tile {} stalled: it did not take the {:?} halo message for step {} within {:?}",
+                tile, dir, step, waited
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HaloError {}
+
+/// Bounds how long a tile waits for any one halo message to arrive or be
+/// taken before reporting the neighbour as stalled.
+#[derive(Debug, Clone, Copy)]
+struct Watchdog {
+    timeout: Duration,
+    /// neighbour tile on each side
+    sides: [Option<usize>; 4],
+}
+
+impl Watchdog {
+    fn stalled(&self, dir: Direction, side: Direction, step: u64, sending: bool) -> HaloError {
+        HaloError::Stalled {
+            dir,
+            step,
+            tile: self.sides[side.index()].unwrap_or_default(),
+            waited: self.timeout,
+            sending,
+        }
+    }
+}
+
+/// Per-tile exchange state that survives between steps: messages that came
+/// in one step early, spare buffers for the next outgoing messages, and the
+/// iteration the tiles have agreed to stop before, if any.
+#[derive(Debug, Default)]
+pub struct HaloState {
+    early: Vec<HaloMessage>,
+    spare: [Option<Vec<f64>>; 4],
+    watchdog: Option<Watchdog>,
+    /// Earliest stop iteration proposed by this tile or heard from any
+    /// neighbour. Every message carries it on, so within one round per hop
+    /// all tiles hold the smallest proposal.
+    pub stop_at: Option<u64>,
+}
+
+impl HaloState {
+    pub fn new() -> Self {
+        HaloState {
+            early: Vec::with_capacity(4),
+            spare: Default::default(),
+            watchdog: None,
+            stop_at: None,
+        }
+    }
+
+    /// Fails exchanges of tile `tid` of a `px` x `py` layout that wait on a
+    /// neighbour for longer than `timeout`.
+    pub fn watched(mut self, timeout: Duration, tid: usize, px: usize, py: usize) -> Self {
+        let (top, bottom, left, right) = compute_neighbors(tid as i32, px as i32, py as i32);
+        let sides = [top, bottom, left, right].map(|t| usize::try_from(t).ok());
+        self.watchdog = Some(Watchdog { timeout, sides });
+        self
+    }
+
+    /// Proposes to stop before iteration `iter`, unless an earlier stop is
+    /// already known.
+    pub fn propose_stop(&mut self, iter: u64) {
+        self.stop_at = Some(self.stop_at.map_or(iter, |s| s.min(iter)));
+    }
+
+    fn buffer_for(&mut self, dir: Direction, len: usize) -> Vec<f64> {
+        self.spare[dir.index()]
+            .take()
+            .unwrap_or_else(|| Vec::with_capacity(len))
+    }
+}
+
+pub fn compute_neighbors(t_id: i32, px: i32, py: i32) -> (i32, i32, i32, i32) {
+    let x = t_id % px;
+    let y = t_id / px;
+
+    let top = if y > 0 { t_id - px } else { -1 };
+    let bottom = if y < py - 1 { t_id + px } else { -1 };
+    let left = if x > 0 { t_id - 1 } else { -1 };
+    let right = if x < px - 1 { t_id + 1 } else { -1 };
+
+    (top, bottom, left, right)
+}
+
+/// Sends this tile's edge rows/columns in exchange round `step` to its
+/// neighbours and fills the ghost cells from theirs.
+///
+/// A neighbour may already be one round ahead; its next message is kept in
+/// `state` for the following call. Anything else that does not fit the
+/// current step is reported as an error. Outgoing messages reuse the buffers
+/// of the messages received from the opposite side, so in steady state the
+/// exchange does not allocate.
+pub async fn exchange_ghost_cells<'a, T: HaloTransport>(
+    buffers: Arc<Mutex<ArrBuffer<'a>>>,
+    state: &mut HaloState,
+    transport: &mut T,
+    step: u64,
+) -> Result<(), HaloError> {
+    let mut outgoing: [Option<HaloMessage>; 4] = Default::default();
+    let (grid_m, grid_n) = {
+        let u = buffers.lock().unwrap();
+        for dir in Direction::ALL {
+            if !transport.has_neighbour(dir) {
+                continue;
+            }
+            let len = match dir {
+                Direction::Up | Direction::Down => u.grid_n,
+                Direction::Left | Direction::Right => u.grid_m,
+            };
+            let mut data = state.buffer_for(dir, len);
+            match dir {
+                Direction::Up => u.copy_row_into(1, &mut data),
+                Direction::Down => u.copy_row_into(u.grid_m - 2, &mut data),
+                Direction::Left => u.copy_col_into(1, &mut data),
+                Direction::Right => u.copy_col_into(u.grid_n - 2, &mut data),
+            }
+            outgoing[dir.index()] = Some(HaloMessage {
+                dir,
+                step,
+                stop_at: state.stop_at,
+                data,
+            });
+        }
+        (u.grid_m, u.grid_n)
+    };
+    for msg in outgoing.iter_mut().filter_map(Option::take) {
+        send_watched(state, transport, msg).await?;
+    }
+
+    let received = receive_round(state, transport, step).await?;
+    for msg in received.into_iter().flatten() {
+        let dir = msg.dir;
+        let expected = match dir {
+            Direction::Up | Direction::Down => grid_n,
+            Direction::Left | Direction::Right => grid_m,
+        };
+        if msg.data.len() != expected {
+            return Err(HaloError::Length {
+                dir,
+                expected,
+                got: msg.data.len(),
+            });
+        }
+        {
+            let mut u = buffers.lock().unwrap();
+            match dir {
+                Direction::Up => u.update_row(grid_m - 1, &msg.data),
+                Direction::Down => u.update_row(0, &msg.data),
+                Direction::Left => u.update_col(grid_n - 1, &msg.data),
+                Direction::Right => u.update_col(0, &msg.data),
+            }
+        }
+        state.spare[dir.opposite().index()] = Some(msg.data);
+    }
+    Ok(())
+}
+
+/// Moves this tile's boundaries along `axis` so that it covers the global
+/// rows (or columns) in `new`, handing the cells it gives up
+/// to the neighbours taking them over and receiving the ones it gains.
+///
+/// Every neighbour pair moves the same boundary, so what one side sends is
+/// exactly what the other expects. The strips travel in `chunks` rounds
+/// starting at `step`, each message at most `transport.max_message_len()`
+/// long; all tiles must agree on `chunks` so that their rounds stay aligned.
+pub async fn migrate<'a, T: HaloTransport>(
+    buffers: Arc<Mutex<ArrBuffer<'a>>>,
+    state: &mut HaloState,
+    transport: &mut T,
+    step: u64,
+    axis: Axis,
+    new: Range<usize>,
+    chunks: usize,
+) -> Result<(), HaloError> {
+    // directions towards the lower and the higher neighbour along `axis`
+    let (down_dir, up_dir) = match axis {
+        Axis::Rows => (Direction::Up, Direction::Down),
+        Axis::Cols => (Direction::Left, Direction::Right),
+    };
+    let (new_start, new_end) = (new.start, new.end);
+    let (outgoing, expected) = {
+        let u = buffers.lock().unwrap();
+        let (old_start, old_len, line) = match axis {
+            Axis::Rows => (u.start_row, u.m, u.grid_n),
+            Axis::Cols => (u.start_col, u.n, u.grid_m),
+        };
+        let old_end = old_start + old_len;
+        let mut outgoing: [Vec<f64>; 4] = Default::default();
+        if new_start > old_start {
+            outgoing[down_dir.index()] = u.strip(axis, old_start, new_start);
+        }
+        if new_end < old_end {
+            outgoing[up_dir.index()] = u.strip(axis, new_end, old_end);
+        }
+        // gained cells arrive travelling away from the neighbour that held them
+        let mut expected = [0; 4];
+        expected[up_dir.index()] = 3 * line * old_start.saturating_sub(new_start);
+        expected[down_dir.index()] = 3 * line * new_end.saturating_sub(old_end);
+        (outgoing, expected)
+    };
+
+    let chunk_len = transport.max_message_len();
+    let mut incoming: [Vec<f64>; 4] = Default::default();
+    for chunk in 0..chunks {
+        let round = step + chunk as u64;
+        for dir in Direction::ALL {
+            if !transport.has_neighbour(dir) {
+                continue;
+            }
+            let data = &outgoing[dir.index()];
+            let from = (chunk * chunk_len).min(data.len());
+            let to = (from + chunk_len).min(data.len());
+            let msg = HaloMessage {
+                dir,
+                step: round,
+                stop_at: state.stop_at,
+                data: data[from..to].to_vec(),
+            };
+            send_watched(state, transport, msg).await?;
+        }
+        for msg in receive_round(state, transport, round)
+            .await?
+            .into_iter()
+            .flatten()
+        {
+            incoming[msg.dir.index()].extend_from_slice(&msg.data);
+        }
+    }
+    for dir in Direction::ALL {
+        let got = incoming[dir.index()].len();
+        if got != expected[dir.index()] {
+            return Err(HaloError::Length {
+                dir,
+                expected: expected[dir.index()],
+                got,
+            });
+        }
+    }
+
+    buffers.lock().unwrap().reslice(
+        axis,
+        new_start,
+        new_end - new_start,
+        &incoming[up_dir.index()],
+        &incoming[down_dir.index()],
+    );
+    Ok(())
+}
+
+/// Collects one message of round `step` from every neighbour, indexed by the
+/// direction it travels in. A neighbour may already be one round ahead; its
+/// next message is kept in `state` for the following call.
+async fn receive_round<T: HaloTransport>(
+    state: &mut HaloState,
+    transport: &mut T,
+    step: u64,
+) -> Result<[Option<HaloMessage>; 4], HaloError> {
+    // a message travelling `dir` comes from the neighbour on the opposite side
+    let from_side = Direction::ALL.map(|d| transport.has_neighbour(d.opposite()));
+    let expects = |dir: Direction| from_side[dir.index()];
+    let num_ghosts = from_side.iter().filter(|&&b| b).count();
+    let mut received: [Option<HaloMessage>; 4] = Default::default();
+    let mut count = 0;
+    let mut deferred = 0;
+    while count < num_ghosts {
+        let msg = if deferred < state.early.len() {
+            state.early.remove(deferred)
+        } else if let Some(watchdog) = state.watchdog {
+            match timeout(watchdog.timeout, transport.recv()).await {
+                Ok(msg) => msg?,
+                Err(_) => {
+                    let dir = Direction::ALL
+                        .into_iter()
+                        .find(|&d| expects(d) && received[d.index()].is_none())
+                        .unwrap();
+                    return Err(watchdog.stalled(dir, dir.opposite(), step, false));
+                }
+            }
+        } else {
+            transport.recv().await?
+        };
+        let dir = msg.dir;
+        if !expects(dir) {
+            return Err(HaloError::NoNeighbour {
+                dir,
+                step: msg.step,
+            });
+        }
+        let seen = received[dir.index()].is_some();
+        if msg.step == step + 1 && seen {
+            state.early.insert(deferred, msg);
+            deferred += 1;
+            continue;
+        }
+        if msg.step != step {
+            return Err(HaloError::WrongStep {
+                dir,
+                expected: step,
+                got: msg.step,
+            });
+        }
+        if seen {
+            return Err(HaloError::Duplicate { dir, step });
+        }
+        if let Some(stop_at) = msg.stop_at {
+            state.propose_stop(stop_at);
+        }
+        received[dir.index()] = Some(msg);
+        count += 1;
+    }
+    Ok(received)
+}
+
+/// Sends `msg`, giving up once the watchdog's timeout has passed.
+async fn send_watched<T: HaloTransport>(
+    state: &HaloState,
+    transport: &mut T,
+    msg: HaloMessage,
+) -> Result<(), HaloError> {
+    let Some(watchdog) = state.watchdog else {
+        return transport.send(msg).await;
+    };
+    let (dir, step) = (msg.dir, msg.step);
+    timeout(watchdog.timeout, transport.send(msg))
+        .await
+        .unwrap_or_else(|_| Err(watchdog.stalled(dir, dir, step, true)))
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// First SIGINT or SIGTERM received, 0 while none has arrived.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Tile processes that get every signal this process receives.
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// The signal that asked the run to stop, if any.
+pub fn requested() -> Option<i32> {
+    match SIGNAL.load(Ordering::Acquire) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Exit status of a process stopped by `sig`, as a shell reports it.
+pub fn exit_code(sig: i32) -> i32 {
+    128 + sig
+}
+
+/// Forwards later signals to these tile processes, replacing earlier ones.
+pub fn set_children(pids: &[u32]) {
+    *CHILDREN.lock().unwrap() = pids.to_vec();
+}
+
+/// Catches SIGINT and SIGTERM from now on. The first one only records the
+/// stop request, which the tiles pick up between iterations; with
+/// `abort_on_repeat`, a second one ends the process at once.
+pub fn listen(abort_on_repeat: bool) -> io::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            let sig = tokio::select! {
+                _ = interrupt.recv() => libc::SIGINT,
+                _ = terminate.recv() => libc::SIGTERM,
+            };
+            for &pid in CHILDREN.lock().unwrap().iter() {
+                unsafe {
+                    libc::kill(pid as libc::pid_t, sig);
+                }
+            }
+            if SIGNAL
+                .compare_exchange(0, sig, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                if abort_on_repeat {
+                    eprintln!("Stopping all tiles at a common iteration; interrupt again to abort");
+                }
+            } else if abort_on_repeat {
+                process::exit(exit_code(sig));
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use crate::buffer::{ArrBuffer, MASK_BLOCK};
+use std::sync::{Arc, Mutex};
+
+/// Advances the interior of the tile by one step. Only cells within one
+/// stencil radius of the active box are visited, and blocks masked out by
+/// `ArrBuffer::build_block_mask` are skipped; every skipped cell would have
+/// been computed as exactly `0.0`, so the result matches a full sweep.
+pub fn compute_u(buffers: Arc<Mutex<ArrBuffer>>) {
+    let mut u = buffers.lock().unwrap();
+    let grid_m = u.grid_m;
+    let grid_n = u.grid_n;
+    let Some(b) = u.active else {
+        return;
+    };
+    let r_lo = b.r0.saturating_sub(1).max(2);
+    let r_hi = (b.r1 + 1).min(grid_m - 2);
+    let c_lo = b.c0.saturating_sub(1).max(2);
+    let c_hi = (b.c1 + 1).min(grid_n - 2);
+    if r_lo < r_hi && c_lo < c_hi {
+        for br in r_lo / MASK_BLOCK..=(r_hi - 1) / MASK_BLOCK {
+            for bc in c_lo / MASK_BLOCK..=(c_hi - 1) / MASK_BLOCK {
+                if u.is_masked_block(br, bc) {
+                    continue;
+                }
+                for r in (br * MASK_BLOCK).max(r_lo)..((br + 1) * MASK_BLOCK).min(r_hi) {
+                    for c in (bc * MASK_BLOCK).max(c_lo)..((bc + 1) * MASK_BLOCK).min(c_hi) {
+                        let nv = u.alp_v(r, c)
+                            * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1)
+                                + u.cur_v(r, c + 1)
+                                - 4.0 * u.cur_v(r, c))
+                            + 2.0 * u.cur_v(r, c)
+                            - u.prev_v(r, c);
+                        if let Some(v) = u.nxt(r, c) {
+                            *v = nv;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    u.grow_active(1);
+}
+
+pub fn compute_edge_u(
+    buffers: Arc<Mutex<ArrBuffer>>,
+    top_global_edge: bool,
+    bot_global_edge: bool,
+    left_global_edge: bool,
+    right_global_edge: bool,
+) {
+    let kappa = 0.2899999999999999;
+    let mut u = buffers.lock().unwrap();
+    let grid_m = u.grid_m;
+    let grid_n = u.grid_n;
+    for c in 1..grid_n - 1 {
+        for &r in &[1, grid_m - 2] {
+            let nv = u.alp_v(r, c)
+                * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1) + u.cur_v(r, c + 1)
+                    - 4.0 * u.cur_v(r, c))
+                + 2.0 * u.cur_v(r, c)
+                - u.prev_v(r, c);
+            if let Some(v) = u.nxt(r, c) {
+                *v = nv;
+            }
+        }
+    }
+    for r in 1..grid_m - 1 {
+        for &c in &[1, grid_n - 2] {
+            let nv = u.alp_v(r, c)
+                * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1) + u.cur_v(r, c + 1)
+                    - 4.0 * u.cur_v(r, c))
+                + 2.0 * u.cur_v(r, c)
+                - u.prev_v(r, c);
+            if let Some(v) = u.nxt(r, c) {
+                *v = nv;
+            }
+        }
+    }
+    if top_global_edge {
+        let r = 0;
+        for c in 1..grid_n - 1 {
+            let nv = u.cur_v(r + 1, c)
+                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r + 1, c) - u.cur_v(r, c));
+            if let Some(v) = u.nxt(r, c) {
+                *v = nv;
+            }
+        }
+    }
+    if bot_global_edge {
+        let r = grid_m - 1;
+        for c in 1..grid_n - 1 {
+            let nv = u.cur_v(r - 1, c)
+                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r - 1, c) - u.cur_v(r, c));
+            if let Some(v) = u.nxt(r, c) {
+                *v = nv;
+            }
+        }
+    }
+    if left_global_edge {
+        let c = 0;
+        for r in 1..grid_m - 1 {
+            let nv = u.cur_v(r, c + 1)
+                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r, c + 1) - u.cur_v(r, c));
+            if let Some(v) = u.nxt(r, c) {
+                *v = nv;
+            }
+        }
+    }
+    if right_global_edge {
+        let c = grid_n - 1;
+        for r in 1..grid_m - 1 {
+            let nv = u.cur_v(r, c - 1)
+                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r, c - 1) - u.cur_v(r, c));
+            if let Some(v) = u.nxt(r, c) {
+                *v = nv;
+            }
+        }
+    }
+}
@@ -2,4 +2,20 @@ pub mod controlblock;
 pub mod buffer;
 pub mod stimulus;
 pub mod plotter;
-pub mod obstacle;
\ No newline at end of file
+pub mod obstacle;
+pub mod kernel;
+pub mod output;
+pub mod halo;
+pub mod transport;
+pub mod simulation;
+pub mod decomposition;
+pub mod balance;
+pub mod checkpoint;
+pub mod interrupt;
+pub mod ncfile;
+pub mod fields;
+pub mod npy;
+pub mod vtk;
+pub mod render;
+pub mod sampling;
+pub mod probe;
\ No newline at end of file
@@ -1,423 +1,466 @@
 use futures::future::join_all;
-use netcdf::{create, Extent, Extents};
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::Instant;
 use std::vec;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, Barrier, RwLock};
-use tokio::task;
-use wave_2d::buffer::ArrBuffer;
+use tokio::{process, task};
+use wave_2d::balance::LoadBalancer;
+use wave_2d::checkpoint::CheckpointWriter;
 use wave_2d::controlblock::ControlBlock;
-use wave_2d::obstacle::clear_alpha_region;
-use wave_2d::stimulus::Stimulus;
+use wave_2d::decomposition::Decomposition;
+use wave_2d::fields::Field;
+use wave_2d::interrupt;
+use wave_2d::ncfile::{write_probes, NcWriter};
+use wave_2d::npy::NpyWriter;
+use wave_2d::output::{BlockQueue, FramePipeline, FrameSink, FrameWriter, Ready, WriteError};
+use wave_2d::probe::ProbeRecorder;
+use wave_2d::render::{take_stdout, PngWriter, Y4mWriter};
+use wave_2d::simulation::{guard, run_tile, serve_gather, Coordinator, Failure};
+use wave_2d::transport::{ChannelTransport, Endpoint, ShmTransport, SocketTransport};
+use wave_2d::vtk::VtkWriter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let mut tasks = vec![];
-    let args = vec![
-        "wave_2d",
-        "-c",
-        "tests/t500.config",
-        "-i",
-        "200",
-        "-x",
-        "2",
-        "-y",
-        "2",
-    ];
-    let args_string: Vec<String> = args.iter().map(|&s| String::from(s)).collect();
-    let task_config: ControlBlock = ControlBlock::new(args_string);
-    let grid_size: usize = task_config.m;
-    let num_threads = task_config.px * task_config.py;
-    let global_grid: Arc<RwLock<Vec<f64>>> =
-        Arc::new(RwLock::new(vec![0.0; grid_size * grid_size]));
-    let barrier = Arc::new(Barrier::new(num_threads + 1)); // +1 for the main thread
-    let mut file = create("output.nc")?;
-    file.add_dimension("y", grid_size)?;
-    file.add_dimension("x", grid_size)?;
-    file.add_unlimited_dimension("frame")?;
-    let mut data_var = file.add_variable::<f64>("data", &["frame", "y", "x"])?; 
-    let mut senders: Vec<Sender<Vec<f64>>> = vec![];
-    let mut receivers: Vec<Receiver<Vec<f64>>> = vec![];
-
-    for _ in 0..num_threads {
-        let (tx, rx) = mpsc::channel(4); 
-        senders.push(tx);
-        receivers.push(rx);
+    let args_string: Vec<String> = std::env::args().collect();
+    let task_config: ControlBlock = ControlBlock::new(args_string.clone());
+    if let Some(tid) = task_config.tile {
+        interrupt::listen(false)?;
+        return run_tile_process(task_config, tid).await;
     }
-    for tid in 0..num_threads {
-        let shared_grid: Arc<RwLock<Vec<f64>>> = Arc::clone(&global_grid);
-        let barrier = Arc::clone(&barrier);
-        let mut my_receiver: Receiver<Vec<f64>> = receivers.remove(0); 
-        let my_senders = senders.clone(); 
-        let cb = task_config.clone();
-        let (top_t_id, bot_t_id, left_t_id, right_t_id) =
-            compute_neighbors(tid as i32, cb.px as i32, cb.py as i32);
-        let top_global_edge = tid < cb.px;
-        let bot_global_edge = tid >= cb.px * (cb.py - 1);
-        let left_global_edge = tid % cb.px == 0;
-        let right_global_edge = (tid + 1) % cb.px == 0;
-        let task = task::spawn(async move {
-            let arr_buffers: Arc<Mutex<ArrBuffer<'_>>> =
-                Arc::new(Mutex::new(ArrBuffer::new(&cb, tid as i32)));
-
-            let mut s_list: Vec<Stimulus> = Vec::new();
-            if cb.config.get("objects").is_some() {
-                let objects = cb.config.get("objects").unwrap();
-                for object in objects.as_array().unwrap() {
-                    let obj_type = object.get("type").and_then(|v| v.as_str()).unwrap_or("");
-                    match obj_type {
-                        "sine" => {
-                            let start_time =
-                                object.get("start").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
-                            let duration =
-                                object.get("duration").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
-                            let row =
-                                object.get("row").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
-                            let col =
-                                object.get("col").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
-                            let period =
-                                object.get("period").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
-
-                            let buffers = Arc::clone(&arr_buffers);
-                            let s = Stimulus::new(buffers, start_time, duration, row, col, period);
-                            s_list.push(s);
-                        }
-
-                        "rectobstacle" => {
-                            let row =
-                                object.get("row").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
-                            let col =
-                                object.get("col").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
-                            let width =
-                                object.get("width").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
-                            let height =
-                                object.get("height").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
-                            clear_alpha_region(Arc::clone(&arr_buffers), row, col, width, height);
-                        }
-
-                        _ => {
-                            eprintln!("Unknown object type: {:?}", obj_type);
-                        }
-                    }
-                }
-            }
-            let start_row;
-            let start_col;
-            let grid_m;
-            let grid_n;
-            {
-                let u_val: std::sync::MutexGuard<'_, ArrBuffer<'_>> = arr_buffers.lock().unwrap();
-                start_row = u_val.start_row;
-                start_col = u_val.start_col;
-                grid_m = u_val.grid_m;
-                grid_n = u_val.grid_n;
-            }
-        
-            let mut iter = 0;
-            while !s_list.is_empty() && iter < cb.niters {
-                s_list.retain_mut(|it: &mut Stimulus<'_>| it.trigger_if_available(iter as i32));
-                if cb.px * cb.py != 1 {
-                    exchange_ghost_cells(
-                        Arc::clone(&arr_buffers),
-                        &mut my_receiver,
-                        &my_senders,
-                        top_t_id,
-                        bot_t_id,
-                        left_t_id,
-                        right_t_id,
-                    )
-                    .await;
-                }
-
-                compute_u(Arc::clone(&arr_buffers));
-                compute_edge_u(
-                    Arc::clone(&arr_buffers),
-                    top_global_edge,
-                    bot_global_edge,
-                    left_global_edge,
-                    right_global_edge,
-                );
-
-                {
-                    let mut grid = shared_grid.write().await;
-
-                    let u_val: std::sync::MutexGuard<'_, ArrBuffer<'_>> =
-                        arr_buffers.lock().unwrap();
-
-                    for i in 0..grid_m - 2 {
-                        for j in 0..grid_n - 2 {
-                            grid[grid_size * (start_row + i) + start_col + j] =
-                                u_val.cur_v(i + 1, j + 1);
-                        }
-                    }
-                }
-                barrier.wait().await;
-                barrier.wait().await;
-                {
-                    let mut buffers = arr_buffers.lock().unwrap();
-                    buffers.adv_buffers();
-                }
-                iter += 1;
-            }
-
-            while iter < cb.niters {
-                {
-                    if cb.px * cb.py != 1 {
-                        exchange_ghost_cells(
-                            Arc::clone(&arr_buffers),
-                            &mut my_receiver,
-                            &my_senders,
-                            top_t_id,
-                            bot_t_id,
-                            left_t_id,
-                            right_t_id,
-                        )
-                        .await;
-                    }
-                    compute_u(Arc::clone(&arr_buffers));
-                    compute_edge_u(
-                        Arc::clone(&arr_buffers),
-                        top_global_edge,
-                        bot_global_edge,
-                        left_global_edge,
-                        right_global_edge,
-                    );
-                    let mut grid = shared_grid.write().await;
-                    let u_val: std::sync::MutexGuard<'_, ArrBuffer<'_>> =
-                        arr_buffers.lock().unwrap();
-                    for i in 0..grid_m - 2 {
-                        for j in 0..grid_n - 2 {
-                            grid[grid_size * (start_row + i) + start_col + j] =
-                                u_val.cur_v(i + 1, j + 1);
-                        }
-                    }
-                }
-                barrier.wait().await;
-                barrier.wait().await;
-                {
-                    let mut buffers = arr_buffers.lock().unwrap();
-                    buffers.adv_buffers();
-                }
-                iter += 1;
-            }
-        });
-
-        tasks.push(task);
+    interrupt::listen(true)?;
+    // a stream on stdout keeps it to itself; messages go to stderr instead
+    let stdout = if task_config.streams_to_stdout() && !task_config.verify {
+        Some(take_stdout()?)
+    } else {
+        None
+    };
+    println!("Decomposition: {}", task_config.decomp);
+    if task_config.verify {
+        return verify(task_config, args_string).await;
     }
+    let num_threads = task_config.px * task_config.py;
+    let fields = Field::selected(&task_config.fields).len();
+    let (sink, ready) = if task_config.tile_writes {
+        // two frames in flight, as with the gather buffers
+        let (queue, ready) = BlockQueue::new(fields, num_threads, 2);
+        (FrameSink::Blocks(queue), ready)
+    } else {
+        let (pipeline, ready) = FramePipeline::starting_at(
+            task_config.sampling().rows(),
+            task_config.sampling().cols(),
+            fields,
+            num_threads,
+            task_config.first_frame,
+        );
+        (FrameSink::Gather(pipeline), Ready::Frames(ready))
+    };
+    let sink = Arc::new(sink);
+    // a restarted run adds its frames to the output of the run it continues
+    let resume_output = task_config.restart.is_some() && task_config.output.exists();
+    let writer = {
+        let sink = Arc::clone(&sink);
+        let cb = task_config.clone();
+        let args = args_string.clone();
+        thread::spawn(move || -> Result<(), WriteError> {
+            let mut file = open_output(&cb, &args, resume_output, stdout)?;
+            sink.drain(ready, |region, values| file.write_block(region, values))?;
+            file.close()
+        })
+    };
+    let balancer = Arc::new(LoadBalancer::new(
+        task_config.decomp.clone(),
+        task_config.rebalance_threshold,
+        task_config.sampling(),
+    ));
+    let probes = Arc::new(open_probes(&task_config)?);
     let start_time = Instant::now();
-
-    for frame_id in 0..task_config.niters {
-        barrier.wait().await;
-        let grid = global_grid.read().await;
-        let extents: Extents = [
-            Extent::SliceCount {
-                start: frame_id,
-                count: 1,
-                stride: 1,
-            },
-            Extent::SliceCount {
-                start: 0,
-                count: grid_size,
-                stride: 1,
-            },
-            Extent::SliceCount {
-                start: 0,
-                count: grid_size,
-                stride: 1,
-            },
-        ]
-        .into();
-        data_var.put_values(&grid, extents)?;
-        barrier.wait().await;
+    let result = run_simulation(&task_config, &args_string, &sink, &balancer, &probes).await;
+    // a failed writer is the cause of the tiles' failure, not the other way round
+    sink.close();
+    writer
+        .join()
+        .expect("frame writer panicked")
+        .map_err(|e| e as Box<dyn Error>)?;
+    result?;
+    let traces = probes.finish()?;
+    if task_config.format == "netcdf" && !task_config.probes.is_empty() {
+        write_probes(
+            &task_config.output,
+            &task_config.probes,
+            &traces,
+            task_config.dt,
+        )?;
     }
-
-    join_all(tasks).await;
-    println!("Simulation finished! {:?}", start_time.elapsed());
+    let elapsed = start_time.elapsed();
+    if let Some(sig) = interrupt::requested() {
+        println!(
+            "Simulation interrupted after {:?}; continue it with --restart {}",
+            elapsed,
+            task_config.checkpoint.display()
+        );
+        std::process::exit(interrupt::exit_code(sig));
+    }
+    println!(
+        "Simulation finished! {:?} ({:?} per step)",
+        elapsed,
+        elapsed / task_config.niters.max(1) as u32
+    );
     Ok(())
 }
 
-fn compute_u(buffers: Arc<Mutex<ArrBuffer>>) {
-    let mut u = buffers.lock().unwrap();
-    let grid_m = u.grid_m;
-    let grid_n = u.grid_n;
-    for r in 2..grid_m - 2 {
-        for c in 2..grid_n - 2 {
-            let nv = u.alp_v(r, c)
-                * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1) + u.cur_v(r, c + 1)
-                    - 4.0 * u.cur_v(r, c))
-                + 2.0 * u.cur_v(r, c)
-                - u.prev_v(r, c);
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
-            }
-        }
+/// Opens the writer of `cb.output` in its format, appending when `resume`.
+fn open_output(
+    cb: &ControlBlock,
+    args: &[String],
+    resume: bool,
+    stdout: Option<File>,
+) -> Result<Box<dyn FrameWriter>, WriteError> {
+    Ok(match (cb.format.as_str(), resume) {
+        ("y4m", true) => Box::new(Y4mWriter::append(cb)?),
+        ("y4m", false) => Box::new(Y4mWriter::create(cb, stdout)?),
+        ("npy" | "npz", true) => Box::new(NpyWriter::append(cb)?),
+        ("npy" | "npz", false) => Box::new(NpyWriter::create(cb)?),
+        // every png frame stands on its own
+        ("png", _) => Box::new(PngWriter::create(cb)?),
+        ("vtk", true) => Box::new(VtkWriter::append(cb)?),
+        ("vtk", false) => Box::new(VtkWriter::create(cb)?),
+        (_, true) => Box::new(NcWriter::append(cb)?),
+        (_, false) => Box::new(NcWriter::create(cb, args)?),
+    })
+}
+
+/// Opens the recorder of `cb`'s probes, appending to a restarted run's CSV.
+fn open_probes(cb: &ControlBlock) -> std::io::Result<ProbeRecorder> {
+    if cb.probes.is_empty() {
+        Ok(ProbeRecorder::in_memory(cb))
+    } else if cb.restart.is_some() && cb.probe_output.exists() {
+        ProbeRecorder::append(cb)
+    } else {
+        ProbeRecorder::create(cb)
     }
 }
 
-fn compute_edge_u(
-    buffers: Arc<Mutex<ArrBuffer>>,
-    top_global_edge: bool,
-    bot_global_edge: bool,
-    left_global_edge: bool,
-    right_global_edge: bool,
-) {
-    let kappa = 0.2899999999999999;
-    let mut u = buffers.lock().unwrap();
-    let grid_m = u.grid_m;
-    let grid_n = u.grid_n;
-    for c in 1..grid_n - 1 {
-        for &r in &[1, grid_m - 2] {
-            let nv = u.alp_v(r, c)
-                * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1) + u.cur_v(r, c + 1)
-                    - 4.0 * u.cur_v(r, c))
-                + 2.0 * u.cur_v(r, c)
-                - u.prev_v(r, c);
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
-            }
+/// Runs every tile of `cb` to completion, as tasks of this process or as
+/// child processes, delivering frames to `sink`.
+async fn run_simulation(
+    cb: &ControlBlock,
+    args: &[String],
+    sink: &Arc<FrameSink>,
+    balancer: &Arc<LoadBalancer>,
+    probes: &Arc<ProbeRecorder>,
+) -> Result<(), Box<dyn Error>> {
+    let checkpoints = Arc::new(CheckpointWriter::new(cb));
+    if cb.transport == "channel" {
+        let failure = Arc::new(Failure::new());
+        let mut tasks = vec![];
+        let transports = ChannelTransport::mesh(cb.px, cb.py);
+        for (tid, transport) in transports.into_iter().enumerate() {
+            let coordinator = Coordinator::Local {
+                sink: Arc::clone(sink),
+                balancer: Arc::clone(balancer),
+                checkpoints: Arc::clone(&checkpoints),
+                probes: Arc::clone(probes),
+            };
+            tasks.push(task::spawn(guard(
+                tid,
+                Arc::clone(&failure),
+                run_tile(cb.clone(), tid, transport, coordinator),
+            )));
         }
-    }
-    for r in 1..grid_m - 1 {
-        for &c in &[1, grid_n - 2] {
-            let nv = u.alp_v(r, c)
-                * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1) + u.cur_v(r, c + 1)
-                    - 4.0 * u.cur_v(r, c))
-                + 2.0 * u.cur_v(r, c)
-                - u.prev_v(r, c);
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
-            }
+        for result in join_all(tasks).await {
+            result?;
         }
-    }
-    if top_global_edge {
-        let r = 0;
-        for c in 1..grid_n - 1 {
-            let nv = u.cur_v(r + 1, c)
-                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r + 1, c) - u.cur_v(r, c));
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
-            }
+        match failure.reason() {
+            Some(reason) => Err(reason.into()),
+            None => Ok(()),
         }
+    } else {
+        launch_tile_processes(
+            cb,
+            args,
+            Arc::clone(sink),
+            Arc::clone(balancer),
+            checkpoints,
+            Arc::clone(probes),
+        )
+        .await
     }
-    if bot_global_edge {
-        let r = grid_m - 1;
-        for c in 1..grid_n - 1 {
-            let nv = u.cur_v(r - 1, c)
-                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r - 1, c) - u.cur_v(r, c));
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
-            }
-        }
+}
+
+/// `--verify`: runs the configuration once on a single tile and once with
+/// the requested decomposition, side by side, and compares their frames and
+/// probe traces. Fails unless all of them are bit-for-bit identical.
+async fn verify(cb: ControlBlock, args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut serial = cb.clone();
+    serial.decomp = Decomposition::even(cb.m, cb.n, 1, 1)?;
+    serial.px = 1;
+    serial.py = 1;
+    serial.transport = "channel".to_string();
+    serial.rebalance_freq = 0;
+    serial.checkpoint_freq = 0;
+
+    // each side's writer thread hands copies of its frames to the comparison
+    let mut writers = vec![];
+    let mut frames = vec![];
+    let mut runs = vec![];
+    for run_cb in [&serial, &cb] {
+        let (pipeline, ready) = FramePipeline::new(
+            cb.sampling().rows(),
+            cb.sampling().cols(),
+            Field::selected(&cb.fields).len(),
+            run_cb.px * run_cb.py,
+        );
+        // whole frames, whatever --tile-writes asks for
+        let sink = Arc::new(FrameSink::Gather(pipeline));
+        let (tx, rx) = mpsc::sync_channel::<Vec<f64>>(1);
+        let drained = Arc::clone(&sink);
+        writers.push(thread::spawn(move || {
+            drained.drain(Ready::Frames(ready), |_, grid| tx.send(grid.to_vec()))
+        }));
+        frames.push(rx);
+        let balancer = Arc::new(LoadBalancer::new(
+            run_cb.decomp.clone(),
+            run_cb.rebalance_threshold,
+            run_cb.sampling(),
+        ));
+        let probes = Arc::new(ProbeRecorder::in_memory(run_cb));
+        runs.push((run_cb, sink, balancer, probes));
     }
-    if left_global_edge {
-        let c = 0;
-        for r in 1..grid_m - 1 {
-            let nv = u.cur_v(r, c + 1)
-                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r, c + 1) - u.cur_v(r, c));
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
+    let compare = {
+        let decomposed = frames.pop().unwrap();
+        let serial = frames.pop().unwrap();
+        thread::spawn(move || {
+            let mut mismatched = 0;
+            let mut count = 0;
+            for (frame_id, (a, b)) in serial.iter().zip(decomposed.iter()).enumerate() {
+                let max_diff = a
+                    .iter()
+                    .zip(&b)
+                    .map(|(x, y)| (x - y).abs())
+                    .fold(0.0, f64::max);
+                let identical = a.iter().zip(&b).all(|(x, y)| x.to_bits() == y.to_bits());
+                println!(
+                    "frame {}: max |serial - decomposed| = {:e}{}",
+                    frame_id,
+                    max_diff,
+                    if identical { "" } else { " (not bit-for-bit)" }
+                );
+                count += 1;
+                if !identical {
+                    mismatched += 1;
+                }
             }
-        }
+            (count, mismatched)
+        })
+    };
+
+    let start_time = Instant::now();
+    let (serial_run, decomposed_run) = (&runs[0], &runs[1]);
+    let serial_args = vec![args[0].clone()];
+    let (a, b) = tokio::join!(
+        run_simulation(
+            serial_run.0,
+            &serial_args,
+            &serial_run.1,
+            &serial_run.2,
+            &serial_run.3
+        ),
+        run_simulation(
+            decomposed_run.0,
+            &args,
+            &decomposed_run.1,
+            &decomposed_run.2,
+            &decomposed_run.3
+        ),
+    );
+    for (_, sink, _, _) in &runs {
+        sink.close();
     }
-    if right_global_edge {
-        let c = grid_n - 1;
-        for r in 1..grid_m - 1 {
-            let nv = u.cur_v(r, c - 1)
-                + ((kappa - 1.0) / (kappa + 1.0)) * (u.nxt_v(r, c - 1) - u.cur_v(r, c));
-            if let Some(v) = u.nxt(r, c) {
-                *v = nv;
-            }
+    a?;
+    b?;
+    for w in writers {
+        w.join().expect("frame writer panicked")?;
+    }
+    let (count, mismatched) = compare.join().expect("frame comparison panicked");
+    println!(
+        "Verified {} frames of {} against a single tile in {:?}",
+        count,
+        cb.decomp,
+        start_time.elapsed()
+    );
+    if mismatched > 0 {
+        return Err(format!(
+            "{} of {} frames differ from the serial run",
+            mismatched, count
+        )
+        .into());
+    }
+    if !cb.probes.is_empty() {
+        let (serial, decomposed) = (runs[0].3.finish()?, runs[1].3.finish()?);
+        let differing = serial
+            .iter()
+            .zip(&decomposed)
+            .filter(|((_, a), (_, b))| a.iter().zip(b).any(|(x, y)| x.to_bits() != y.to_bits()))
+            .count();
+        println!(
+            "Verified {} iterations of {} probes",
+            serial.len(),
+            cb.probes.len()
+        );
+        if differing > 0 || serial.len() != decomposed.len() {
+            return Err(format!(
+                "probe traces differ from the serial run at {} iterations",
+                differing
+            )
+            .into());
         }
     }
+    Ok(())
 }
 
-fn compute_neighbors(t_id: i32, px: i32, py: i32) -> (i32, i32, i32, i32) {
-    let x = t_id % px;
-    let y = t_id / px;
-
-    let top = if y > 0 { t_id - px } else { -1 };
-    let bottom = if y < py - 1 { t_id + px } else { -1 };
-    let left = if x > 0 { t_id - 1 } else { -1 };
-    let right = if x < px as i32 - 1 { t_id + 1 } else { -1 };
-
-    (top, bottom, left, right)
+fn endpoint(cb: &ControlBlock) -> Endpoint {
+    match cb.transport.as_str() {
+        "unix" | "shm" => Endpoint::Unix {
+            dir: cb
+                .socket_dir
+                .clone()
+                .unwrap_or_else(|| std::env::temp_dir().join("wave_2d")),
+        },
+        _ => Endpoint::Tcp {
+            host: "127.0.0.1".to_string(),
+            base_port: cb.port,
+        },
+    }
 }
 
-pub async fn exchange_ghost_cells<'a>(
-    buffers: Arc<Mutex<ArrBuffer<'a>>>,
-    my_receiver: &mut Receiver<Vec<f64>>,
-    my_senders: &[Sender<Vec<f64>>],
-    top_t_id: i32,
-    bot_t_id: i32,
-    left_t_id: i32,
-    right_t_id: i32,
-) {
-    let (top_row, bot_row, left_col, right_col, grid_m, grid_n) = {
-        let u = buffers.lock().unwrap();
-
-        let top = if top_t_id >= 0 {
-            Some(u.extract_row(1))
-        } else {
-            None
-        };
-
-        let bot = if bot_t_id >= 0 {
-            Some(u.extract_row(u.grid_m - 2))
-        } else {
-            None
-        };
+/// Entry point of a child started by `launch_tile_processes`: runs a single
+/// tile and streams its frames back to the launcher.
+async fn run_tile_process(cb: ControlBlock, tid: usize) -> Result<(), Box<dyn Error>> {
+    let endpoint = endpoint(&cb);
+    let failure = Arc::new(Failure::new());
+    if let (Endpoint::Unix { dir }, "shm") = (&endpoint, cb.transport.as_str()) {
+        let transport = ShmTransport::open(&dir.join(SHM_FILE), tid, cb.px, cb.py, max_edge(&cb))?;
+        let coordinator = Coordinator::remote(endpoint.connect_gather().await?);
+        // the shared memory exchange waits by sleeping its thread, which
+        // must not be one of the runtime's workers; the tile still uses the
+        // runtime for its link to the launcher and its timers
+        let runtime = tokio::runtime::Handle::current();
+        let tile = guard(
+            tid,
+            Arc::clone(&failure),
+            run_tile(cb, tid, transport, coordinator),
+        );
+        task::spawn_blocking(move || runtime.block_on(tile)).await?;
+    } else {
+        let transport = SocketTransport::connect(&endpoint, tid, cb.px, cb.py).await?;
+        let coordinator = Coordinator::remote(endpoint.connect_gather().await?);
+        guard(
+            tid,
+            Arc::clone(&failure),
+            run_tile(cb, tid, transport, coordinator),
+        )
+        .await;
+    }
+    match failure.reason() {
+        Some(reason) => Err(reason.into()),
+        None => Ok(()),
+    }
+}
 
-        let left = if left_t_id >= 0 {
-            Some(u.extract_col(1))
-        } else {
-            None
-        };
+const SHM_FILE: &str = "halo.shm";
 
-        let right = if right_t_id >= 0 {
-            Some(u.extract_col(u.grid_n - 2))
-        } else {
-            None
-        };
+/// Longest edge, ghost cells included, that any tile can send.
+fn max_edge(cb: &ControlBlock) -> usize {
+    cb.m.max(cb.n) + 2
+}
 
-        (top, bot, left, right, u.grid_m, u.grid_n)
+/// Per-run directory for sockets and the shared halo file; shared memory
+/// lives in /dev/shm where the host has it.
+fn run_dir(transport: &str) -> PathBuf {
+    let shm = PathBuf::from("/dev/shm");
+    let root = if transport == "shm" && shm.is_dir() {
+        shm
+    } else {
+        std::env::temp_dir()
     };
-    if let Some(mut data) = top_row {
-        data.push(1.0);
-        let _ = my_senders[top_t_id as usize].send(data).await;
-    }
-    if let Some(mut data) = bot_row {
-        data.push(2.0);
-        let _ = my_senders[bot_t_id as usize].send(data).await;
+    root.join(format!("wave_2d-{}", std::process::id()))
+}
+
+/// Starts one OS process per tile with the same arguments plus `--tile`, and
+/// gathers their frames into `sink`, their load reports into `balancer`,
+/// their checkpoints into `checkpoints` and their probe samples into
+/// `probes` until all of them have exited.
+async fn launch_tile_processes(
+    cb: &ControlBlock,
+    args: &[String],
+    sink: Arc<FrameSink>,
+    balancer: Arc<LoadBalancer>,
+    checkpoints: Arc<CheckpointWriter>,
+    probes: Arc<ProbeRecorder>,
+) -> Result<(), Box<dyn Error>> {
+    let num_tiles = cb.px * cb.py;
+    let mut cb = cb.clone();
+    let mut extra_args = vec![];
+    if cb.transport != "tcp" && cb.socket_dir.is_none() {
+        let dir = run_dir(&cb.transport);
+        extra_args.push("--socket-dir".to_string());
+        extra_args.push(dir.display().to_string());
+        cb.socket_dir = Some(dir);
     }
-    if let Some(mut data) = left_col {
-        data.push(3.0);
-        let _ = my_senders[left_t_id as usize].send(data).await;
+    let endpoint = endpoint(&cb);
+    let listener = endpoint.listen_gather().await?;
+    if let (Endpoint::Unix { dir }, "shm") = (&endpoint, cb.transport.as_str()) {
+        ShmTransport::create_file(&dir.join(SHM_FILE), num_tiles, max_edge(&cb))?;
     }
-    if let Some(mut data) = right_col {
-        data.push(4.0);
-        let _ = my_senders[right_t_id as usize].send(data).await;
+
+    let exe = std::env::current_exe()?;
+    let mut children = vec![];
+    for tid in 0..num_tiles {
+        let child = process::Command::new(&exe)
+            .args(&args[1..])
+            .args(&extra_args)
+            .arg("--tile")
+            .arg(tid.to_string())
+            // signals reach the tiles only through `interrupt`, exactly once
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()?;
+        children.push(child);
     }
+    let pids: Vec<u32> = children.iter().filter_map(|c| c.id()).collect();
+    interrupt::set_children(&pids);
 
-    let num_ghosts = [top_t_id, bot_t_id, left_t_id, right_t_id]
-        .iter()
-        .filter(|&&r| r >= 0)
-        .count();
-    let mut received = 0;
-    while received < num_ghosts {
-        if let Some(mut data) = my_receiver.recv().await {
-            if let Some(dir_code) = data.pop() {
-                let mut u = buffers.lock().unwrap();
-                match dir_code as u8 {
-                    1 => u.update_row(grid_m - 1, &data),
-                    2 => u.update_row(0, &data),
-                    3 => u.update_col(grid_n - 1, &data),
-                    4 => u.update_col(0, &data),
-                    _ => eprintln!("Unknown direction code: {}", dir_code),
-                }
-                received += 1;
+    let gather = async {
+        serve_gather(listener, sink, balancer, checkpoints, probes, num_tiles)
+            .await
+            .map_err(|e| format!("gathering frames failed: {}", e))
+    };
+    // the first process to fail is the one to blame; the others are
+    // killed when `children` is dropped
+    let wait = async {
+        let mut exits: FuturesUnordered<_> = children
+            .iter_mut()
+            .enumerate()
+            .map(|(tid, child)| async move { (tid, child.wait().await) })
+            .collect();
+        while let Some((tid, status)) = exits.next().await {
+            let status = status.map_err(|e| e.to_string())?;
+            if !status.success() {
+                return Err(format!("tile process {} exited with {}", tid, status));
             }
         }
+        Ok(())
+    };
+    let result = tokio::try_join!(gather, wait);
+    interrupt::set_children(&[]);
+    if let Endpoint::Unix { dir } = &endpoint {
+        let _ = std::fs::remove_dir_all(dir);
     }
+    result?;
+    Ok(())
 }
@@ -0,0 +1,357 @@
+use crate::controlblock::ControlBlock;
+use crate::fields::Field;
+use crate::output::{FrameWriter, Region, WriteError};
+use crate::probe::{Probe, TraceRow};
+use netcdf::{append, create, Extent, Extents, FileMut};
+use std::path::{Path, PathBuf};
+
+/// netCDF's default fill values, declared as `data`'s `_FillValue`.
+const NC_FILL_DOUBLE: f64 = 9.969_209_968_386_869e36;
+const NC_FILL_FLOAT: f32 = 9.969_21e36;
+/// Packed `int16` values run over `-PACKED_MAX..=PACKED_MAX`, which leaves
+/// `i16::MIN` free as the fill value.
+const PACKED_MAX: f64 = i16::MAX as f64;
+
+/// Type the frames are stored as in `data`. Other fields use `F32` unless
+/// `data` is `F64`, as packing to `int16` needs a known range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Storage {
+    F64,
+    F32,
+    /// u = scale * packed value
+    Int16 {
+        scale: f64,
+    },
+}
+
+impl Storage {
+    fn of(cb: &ControlBlock) -> Self {
+        match cb.precision.as_str() {
+            "f32" => Storage::F32,
+            "int16" => Storage::Int16 {
+                scale: cb.pack_range / PACKED_MAX,
+            },
+            _ => Storage::F64,
+        }
+    }
+
+    fn of_field(cb: &ControlBlock, field: Field) -> Self {
+        match (field, Self::of(cb)) {
+            (Field::U, storage) | (_, storage @ Storage::F64) => storage,
+            _ => Storage::F32,
+        }
+    }
+}
+
+/// The netCDF file a run's frames go to.
+pub struct NcWriter {
+    file: FileMut,
+    path: PathBuf,
+    /// fields of each frame, in the order of its planes, and how each is
+    /// stored
+    fields: Vec<(Field, Storage)>,
+    /// frame whose blocks also carry the static fields into the file, if it
+    /// is a new one
+    static_frame: Option<usize>,
+    /// frames before this one have their time and iteration written
+    next_stamp: usize,
+    dt: f64,
+    /// iteration each frame of this run shows, from `first_frame` on
+    frame_iters: Vec<usize>,
+    first_frame: usize,
+    /// values out of the `int16` range, clipped to its ends
+    clipped: usize,
+}
+
+impl NcWriter {
+    /// Creates the file frames of `cb` go to, following the CF conventions:
+    /// `data` over `time, y, x` with a coordinate variable for each, and the
+    /// command line, resolved settings and decomposition of the run as
+    /// global attributes.
+    pub fn create(cb: &ControlBlock, args: &[String]) -> netcdf::Result<Self> {
+        let mut file = create(&cb.output)?;
+        file.add_attribute("Conventions", "CF-1.8")?;
+        file.add_attribute("title", "2D wave equation simulation")?;
+        file.add_attribute("source", format!("wave_2d {}", env!("CARGO_PKG_VERSION")))?;
+        file.add_attribute("history", args.join(" "))?;
+        let settings = serde_json::to_string(cb).map_err(|e| e.to_string())?;
+        file.add_attribute("wave_2d_settings", settings)?;
+        let scenario = serde_json::to_string(&cb.config).map_err(|e| e.to_string())?;
+        file.add_attribute("wave_2d_config", scenario)?;
+        file.add_attribute("decomposition", cb.decomp.to_string())?;
+        let starts = |s: &[usize]| s.iter().map(|&v| v as u64).collect::<Vec<_>>();
+        file.add_attribute("tile_row_starts", starts(&cb.decomp.row_starts))?;
+        file.add_attribute("tile_col_starts", starts(&cb.decomp.col_starts))?;
+
+        let sampling = cb.sampling();
+        file.add_dimension("y", sampling.rows())?;
+        file.add_dimension("x", sampling.cols())?;
+        file.add_unlimited_dimension("time")?;
+        let ys: Vec<f64> = (0..sampling.rows())
+            .map(|i| sampling.row_position(i))
+            .collect();
+        let xs: Vec<f64> = (0..sampling.cols())
+            .map(|j| sampling.col_position(j))
+            .collect();
+        for (name, positions, axis) in [("y", ys, "Y"), ("x", xs, "X")] {
+            let mut var = file.add_variable::<f64>(name, &[name])?;
+            var.put_attribute(
+                "long_name",
+                format!("{} distance from the grid origin", name),
+            )?;
+            var.put_attribute("units", "m")?;
+            var.put_attribute("axis", axis)?;
+            let coords: Vec<f64> = positions.iter().map(|p| p * cb.dx).collect();
+            var.put_values(&coords, ..)?;
+        }
+        let mut time = file.add_variable::<f64>("time", &["time"])?;
+        time.put_attribute("long_name", "simulated time")?;
+        time.put_attribute("units", "s")?;
+        time.put_attribute("axis", "T")?;
+        let mut iteration = file.add_variable::<u64>("iteration", &["time"])?;
+        iteration.put_attribute("long_name", "iteration shown by the frame")?;
+
+        for field in Field::selected(&cb.fields) {
+            let dims: &[&str] = if field.is_static() {
+                &["y", "x"]
+            } else {
+                &["time", "y", "x"]
+            };
+            let name = field.var_name();
+            let mut var = match Storage::of_field(cb, field) {
+                Storage::F64 => {
+                    let mut var = file.add_variable::<f64>(name, dims)?;
+                    var.set_fill_value(NC_FILL_DOUBLE)?;
+                    var
+                }
+                Storage::F32 => {
+                    let mut var = file.add_variable::<f32>(name, dims)?;
+                    var.set_fill_value(NC_FILL_FLOAT)?;
+                    var
+                }
+                Storage::Int16 { scale } => {
+                    let mut var = file.add_variable::<i16>(name, dims)?;
+                    var.set_fill_value(i16::MIN)?;
+                    var.put_attribute("scale_factor", scale)?;
+                    var.put_attribute("add_offset", 0.0)?;
+                    var.put_attribute("valid_range", vec![-i16::MAX, i16::MAX])?;
+                    var
+                }
+            };
+            if !field.is_static() {
+                if let Some(chunk) = &cb.chunk {
+                    var.set_chunking(chunk)?;
+                }
+                var.put_attribute("coordinates", "iteration")?;
+            }
+            if cb.deflate > 0 {
+                var.set_compression(cb.deflate, cb.shuffle)?;
+            }
+            var.put_attribute("long_name", field.long_name())?;
+            var.put_attribute("units", field.units())?;
+        }
+        Ok(Self::with_file(cb, file, true))
+    }
+
+    /// Opens the output of the run `cb` continues, to add its frames.
+    pub fn append(cb: &ControlBlock) -> netcdf::Result<Self> {
+        Ok(Self::with_file(cb, append(&cb.output)?, false))
+    }
+
+    fn with_file(cb: &ControlBlock, file: FileMut, created: bool) -> Self {
+        let fields: Vec<_> = Field::selected(&cb.fields)
+            .into_iter()
+            .map(|field| (field, Storage::of_field(cb, field)))
+            .collect();
+        NcWriter {
+            file,
+            path: cb.output.clone(),
+            static_frame: created.then_some(cb.first_frame),
+            next_stamp: cb.first_frame,
+            fields,
+            dt: cb.dt,
+            frame_iters: cb.output_iters(),
+            first_frame: cb.first_frame,
+            clipped: 0,
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> netcdf::Result<netcdf::VariableMut<'_>> {
+        let path = self.path.display().to_string();
+        self.file
+            .variable_mut(name)
+            .ok_or_else(|| format!("{} has no {} variable", path, name).into())
+    }
+
+    fn put_block(&mut self, region: &Region, values: &[f64]) -> netcdf::Result<()> {
+        let size = region.m * region.n;
+        for k in 0..self.fields.len() {
+            let (field, storage) = self.fields[k];
+            let plane = &values[k * size..(k + 1) * size];
+            let mut extents = vec![
+                Extent::SliceCount {
+                    start: region.start_row,
+                    count: region.m,
+                    stride: 1,
+                },
+                Extent::SliceCount {
+                    start: region.start_col,
+                    count: region.n,
+                    stride: 1,
+                },
+            ];
+            if field.is_static() {
+                if self.static_frame != Some(region.frame_id) {
+                    continue;
+                }
+            } else {
+                extents.insert(
+                    0,
+                    Extent::SliceCount {
+                        start: region.frame_id,
+                        count: 1,
+                        stride: 1,
+                    },
+                );
+            }
+            self.put_plane(field.var_name(), storage, plane, extents.into())?;
+        }
+        while self.next_stamp <= region.frame_id {
+            let frame_id = self.next_stamp;
+            let iter = self.frame_iters[frame_id - self.first_frame];
+            self.variable("iteration")?
+                .put_value(iter as u64, [frame_id])?;
+            let time = iter as f64 * self.dt;
+            self.variable("time")?.put_value(time, [frame_id])?;
+            self.next_stamp += 1;
+        }
+        Ok(())
+    }
+
+    fn put_plane(
+        &mut self,
+        name: &str,
+        storage: Storage,
+        plane: &[f64],
+        extents: Extents,
+    ) -> netcdf::Result<()> {
+        match storage {
+            Storage::F64 => self.variable(name)?.put_values(plane, extents),
+            Storage::F32 => {
+                let values: Vec<f32> = plane.iter().map(|&v| v as f32).collect();
+                self.variable(name)?.put_values(&values, extents)
+            }
+            Storage::Int16 { scale } => {
+                let mut clipped = 0;
+                let values: Vec<i16> = plane
+                    .iter()
+                    .map(|&v| {
+                        let packed = (v / scale).round();
+                        if packed.abs() > PACKED_MAX {
+                            clipped += 1;
+                        }
+                        packed.clamp(-PACKED_MAX, PACKED_MAX) as i16
+                    })
+                    .collect();
+                self.clipped += clipped;
+                self.variable(name)?.put_values(&values, extents)
+            }
+        }
+    }
+}
+
+impl FrameWriter for NcWriter {
+    fn write_block(&mut self, region: &Region, values: &[f64]) -> Result<(), WriteError> {
+        Ok(self.put_block(region, values)?)
+    }
+
+    fn close(self: Box<Self>) -> Result<(), WriteError> {
+        if let (_, Storage::Int16 { scale }) = self.fields[0] {
+            if self.clipped > 0 {
+                eprintln!(
+                    "warning: {} values beyond ±{} were clipped in {}; raise --pack-range",
+                    self.clipped,
+                    scale * PACKED_MAX,
+                    self.path.display()
+                );
+            }
+        }
+        Ok(self.file.close()?)
+    }
+}
+
+/// Writes the traces of `probes` into the `probes` group of the netCDF file
+/// at `path`: `u` over `step, probe`, with the iteration and time of each
+/// step and the name and cell of each probe. The group is created the first
+/// time; a restarted run overwrites it with every row, old ones included.
+pub fn write_probes(
+    path: &Path,
+    probes: &[Probe],
+    rows: &[TraceRow],
+    dt: f64,
+) -> netcdf::Result<()> {
+    let mut file = append(path)?;
+    if file.group("probes")?.is_none() {
+        file.add_group("probes")?;
+        file.add_unlimited_dimension("probes/step")?;
+        file.add_dimension("probes/probe", probes.len())?;
+        let mut u = file.add_variable::<f64>("probes/u", &["step", "probe"])?;
+        u.put_attribute("long_name", "wave displacement at the probes")?;
+        u.put_attribute("units", "1")?;
+        u.put_attribute("coordinates", "iteration name")?;
+        let mut time = file.add_variable::<f64>("probes/time", &["step"])?;
+        time.put_attribute("long_name", "simulated time")?;
+        time.put_attribute("units", "s")?;
+        time.put_attribute("axis", "T")?;
+        let mut iteration = file.add_variable::<u64>("probes/iteration", &["step"])?;
+        iteration.put_attribute("long_name", "iteration after which u was recorded")?;
+        let mut name = file.add_string_variable("probes/name", &["probe"])?;
+        name.put_attribute("long_name", "probe name")?;
+        for (k, probe) in probes.iter().enumerate() {
+            name.put_string(&probe.name, [k])?;
+        }
+        for (var, cells) in [
+            (
+                "probes/row",
+                probes.iter().map(|p| p.row as u64).collect::<Vec<_>>(),
+            ),
+            ("probes/col", probes.iter().map(|p| p.col as u64).collect()),
+        ] {
+            let mut var = file.add_variable::<u64>(var, &["probe"])?;
+            var.put_attribute("long_name", "grid cell of the probe")?;
+            var.put_values(&cells, ..)?;
+        }
+    }
+    let iters: Vec<u64> = rows.iter().map(|(iter, _)| *iter as u64).collect();
+    let times: Vec<f64> = rows.iter().map(|(iter, _)| *iter as f64 * dt).collect();
+    let values: Vec<f64> = rows
+        .iter()
+        .flat_map(|(_, row)| row.iter().copied())
+        .collect();
+    let steps = 0..rows.len();
+    group_variable(&mut file, path, "probes/iteration")?.put_values(&iters, steps.clone())?;
+    group_variable(&mut file, path, "probes/time")?.put_values(&times, steps)?;
+    let extents = [
+        Extent::SliceCount {
+            start: 0,
+            count: rows.len(),
+            stride: 1,
+        },
+        Extent::SliceCount {
+            start: 0,
+            count: probes.len(),
+            stride: 1,
+        },
+    ];
+    group_variable(&mut file, path, "probes/u")?.put_values(&values, extents)?;
+    file.close()
+}
+
+fn group_variable<'f>(
+    file: &'f mut FileMut,
+    path: &Path,
+    name: &str,
+) -> netcdf::Result<netcdf::VariableMut<'f>> {
+    file.variable_mut(name)
+        .ok_or_else(|| format!("{} has no {} variable", path.display(), name).into())
+}
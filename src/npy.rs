@@ -0,0 +1,589 @@
+use crate::controlblock::ControlBlock;
+use crate::fields::Field;
+use crate::output::{FrameWriter, Region, WriteError};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 6] = b"\x93NUMPY";
+/// Bytes before the data of every array written here: magic, version,
+/// header length and the header dict padded with spaces, so the dict can be
+/// rewritten in place whatever the number of frames.
+const DATA_OFFSET: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dtype {
+    F64,
+    F32,
+    U64,
+}
+
+impl Dtype {
+    fn descr(self) -> &'static str {
+        match self {
+            Dtype::F64 => "<f8",
+            Dtype::F32 => "<f4",
+            Dtype::U64 => "<u8",
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F64 | Dtype::U64 => 8,
+        }
+    }
+
+    fn encode(self, values: &[f64]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(values.len() * self.size());
+        for &v in values {
+            match self {
+                Dtype::F64 => bytes.extend_from_slice(&v.to_le_bytes()),
+                Dtype::F32 => bytes.extend_from_slice(&(v as f32).to_le_bytes()),
+                Dtype::U64 => bytes.extend_from_slice(&(v as u64).to_le_bytes()),
+            }
+        }
+        bytes
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A .npy array on disk. Arrays that grow have a leading frame dimension,
+/// which the header is updated with as frames are completed.
+struct NpyArray {
+    file: File,
+    path: PathBuf,
+    dtype: Dtype,
+    /// shape after the frame dimension, or the whole shape of a static array
+    tail: Vec<usize>,
+    /// number of frames, for arrays that grow
+    frames: Option<usize>,
+}
+
+impl NpyArray {
+    fn create(path: &Path, dtype: Dtype, tail: &[usize], grows: bool) -> io::Result<Self> {
+        let mut array = NpyArray {
+            file: File::create(path)?,
+            path: path.to_path_buf(),
+            dtype,
+            tail: tail.to_vec(),
+            frames: grows.then_some(0),
+        };
+        array.write_header()?;
+        Ok(array)
+    }
+
+    /// Reopens a growing array written by `create` to continue it after its
+    /// first `frames` frames; any frames beyond are dropped.
+    fn reopen(path: &Path, dtype: Dtype, tail: &[usize], frames: usize) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut head = vec![0u8; DATA_OFFSET];
+        file.read_exact(&mut head)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        let header_len = u16::from_le_bytes([head[8], head[9]]) as usize;
+        if &head[..6] != MAGIC || head[6] != 1 || header_len + 10 != DATA_OFFSET {
+            return Err(invalid(format!(
+                "{} is not a .npy file written by wave_2d",
+                path.display()
+            )));
+        }
+        let mut array = NpyArray {
+            file,
+            path: path.to_path_buf(),
+            dtype,
+            tail: tail.to_vec(),
+            frames: Some(frames),
+        };
+        let expected = array.header_dict(Some(0));
+        let dict = String::from_utf8_lossy(&head[10..]);
+        // the dicts agree up to the number of frames
+        let prefix = |d: &str| d[..d.find("'shape': (").unwrap_or(d.len())].to_string();
+        let tail_of = |d: &str| d.split_once(", ").map(|(_, rest)| rest.trim().to_string());
+        let shape_of = |d: &str| {
+            d.split_once("'shape': (")
+                .and_then(|(_, rest)| rest.split_once(')'))
+                .map(|(shape, _)| shape.to_string())
+                .unwrap_or_default()
+        };
+        if prefix(&dict) != prefix(&expected)
+            || tail_of(&shape_of(&dict)) != tail_of(&shape_of(&expected))
+        {
+            return Err(invalid(format!(
+                "{} holds {}, not {}",
+                path.display(),
+                dict.trim(),
+                expected.trim()
+            )));
+        }
+        let len = DATA_OFFSET + frames * array.frame_len() * dtype.size();
+        array.file.set_len(len as u64)?;
+        array.write_header()?;
+        Ok(array)
+    }
+
+    fn frame_len(&self) -> usize {
+        self.tail.iter().product()
+    }
+
+    fn header_dict(&self, frames: Option<usize>) -> String {
+        let dims: Vec<String> = frames
+            .iter()
+            .chain(self.tail.iter())
+            .map(|d| d.to_string())
+            .collect();
+        let shape = match dims.len() {
+            1 => format!("({},)", dims[0]),
+            _ => format!("({})", dims.join(", ")),
+        };
+        let mut dict = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            self.dtype.descr(),
+            shape
+        );
+        while dict.len() < DATA_OFFSET - 11 {
+            dict.push(' ');
+        }
+        dict.push('\n');
+        dict
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let dict = self.header_dict(self.frames);
+        if dict.len() != DATA_OFFSET - 10 {
+            return Err(invalid(format!(
+                "{}: header of shape {:?} does not fit",
+                self.path.display(),
+                self.tail
+            )));
+        }
+        let mut head = Vec::with_capacity(DATA_OFFSET);
+        head.extend_from_slice(MAGIC);
+        head.extend_from_slice(&[1, 0]);
+        head.extend_from_slice(&((DATA_OFFSET - 10) as u16).to_le_bytes());
+        head.extend_from_slice(dict.as_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&head)
+    }
+
+    /// Writes `values` from element `offset` of frame `frame` on, or of the
+    /// whole array if it does not grow.
+    fn write_at(&mut self, frame: usize, offset: usize, values: &[f64]) -> io::Result<()> {
+        let start = match self.frames {
+            Some(_) => frame * self.frame_len() + offset,
+            None => offset,
+        };
+        let pos = DATA_OFFSET + start * self.dtype.size();
+        self.file.seek(SeekFrom::Start(pos as u64))?;
+        self.file.write_all(&self.dtype.encode(values))
+    }
+
+    fn set_frames(&mut self, frames: usize) -> io::Result<()> {
+        self.frames = Some(frames);
+        self.write_header()
+    }
+}
+
+/// Writes the frames as NumPy arrays: `data` alone as a single .npy file of
+/// frames x rows x cols, or an .npz bundle of every field, the static ones
+/// without the frame dimension, plus `iteration` and `time` per frame.
+///
+/// A zip archive can only be put together once its members are complete, so
+/// until `close` the members of an .npz are .npy files in `<output>.arrays/`.
+pub struct NpyWriter {
+    path: PathBuf,
+    /// directory of the members of an .npz
+    members: Option<PathBuf>,
+    rows: usize,
+    cols: usize,
+    /// array of each field, in block order; none for a static field that an
+    /// earlier run has already written
+    arrays: Vec<(Field, Option<NpyArray>)>,
+    /// iteration and time of every frame, in an .npz
+    stamps: Option<(NpyArray, NpyArray)>,
+    dt: f64,
+    /// iteration each frame of this run shows, from `first_frame` on
+    frame_iters: Vec<usize>,
+    first_frame: usize,
+    /// values written so far of the frames still incomplete
+    filled: BTreeMap<usize, usize>,
+    /// frames before this one are complete and counted in the headers
+    complete: usize,
+}
+
+/// Directory the members of the .npz at `output` are kept in while written.
+fn members_dir(output: &Path) -> PathBuf {
+    let mut dir = output.as_os_str().to_owned();
+    dir.push(".arrays");
+    PathBuf::from(dir)
+}
+
+impl NpyWriter {
+    pub fn create(cb: &ControlBlock) -> io::Result<Self> {
+        Self::open(cb, false)
+    }
+
+    /// Continues the output of the run `cb` restarts, after its first
+    /// `cb.first_frame` frames.
+    pub fn append(cb: &ControlBlock) -> io::Result<Self> {
+        Self::open(cb, true)
+    }
+
+    fn open(cb: &ControlBlock, resume: bool) -> io::Result<Self> {
+        let dtype = match cb.precision.as_str() {
+            "f32" => Dtype::F32,
+            _ => Dtype::F64,
+        };
+        let sampling = cb.sampling();
+        let frame = [sampling.rows(), sampling.cols()];
+        let grown = |path: &Path, dtype: Dtype, tail: &[usize]| {
+            if resume {
+                NpyArray::reopen(path, dtype, tail, cb.first_frame)
+            } else {
+                NpyArray::create(path, dtype, tail, true)
+            }
+        };
+        let fields = Field::selected(&cb.fields);
+        let (members, arrays, stamps) = if cb.format == "npz" {
+            let dir = members_dir(&cb.output);
+            fs::create_dir_all(&dir)?;
+            if resume {
+                unzip_stored(&cb.output, &dir)?;
+            }
+            let mut arrays = vec![];
+            for field in fields {
+                let path = dir.join(format!("{}.npy", field.var_name()));
+                let array = if !field.is_static() {
+                    Some(grown(&path, dtype, &frame)?)
+                } else if !resume {
+                    Some(NpyArray::create(&path, dtype, &frame, false)?)
+                } else {
+                    None
+                };
+                arrays.push((field, array));
+            }
+            let iteration = grown(&dir.join("iteration.npy"), Dtype::U64, &[])?;
+            let time = grown(&dir.join("time.npy"), Dtype::F64, &[])?;
+            (Some(dir), arrays, Some((iteration, time)))
+        } else {
+            // --fields is rejected for a single .npy
+            let data = grown(&cb.output, dtype, &frame)?;
+            (None, vec![(Field::U, Some(data))], None)
+        };
+        Ok(NpyWriter {
+            path: cb.output.clone(),
+            members,
+            rows: frame[0],
+            cols: frame[1],
+            arrays,
+            stamps,
+            dt: cb.dt,
+            frame_iters: cb.output_iters(),
+            first_frame: cb.first_frame,
+            filled: BTreeMap::new(),
+            complete: cb.first_frame,
+        })
+    }
+
+    fn put_block(&mut self, region: &Region, values: &[f64]) -> io::Result<()> {
+        let size = region.m * region.n;
+        let first_frame = self.first_frame;
+        for ((field, array), plane) in self.arrays.iter_mut().zip(values.chunks_exact(size)) {
+            let Some(array) = array else {
+                continue;
+            };
+            if field.is_static() && region.frame_id != first_frame {
+                continue;
+            }
+            for i in 0..region.m {
+                let offset = (region.start_row + i) * self.cols + region.start_col;
+                let row = &plane[i * region.n..(i + 1) * region.n];
+                array.write_at(region.frame_id, offset, row)?;
+            }
+        }
+
+        *self.filled.entry(region.frame_id).or_insert(0) += size;
+        let before = self.complete;
+        while self.filled.get(&self.complete) == Some(&(self.rows * self.cols)) {
+            self.filled.remove(&self.complete);
+            if let Some((iteration, time)) = &mut self.stamps {
+                let iter = self.frame_iters[self.complete - self.first_frame] as f64;
+                iteration.write_at(self.complete, 0, &[iter])?;
+                time.write_at(self.complete, 0, &[iter * self.dt])?;
+            }
+            self.complete += 1;
+        }
+        if self.complete > before {
+            let grown = self
+                .arrays
+                .iter_mut()
+                .filter_map(|(_, array)| array.as_mut());
+            let stamps = self.stamps.iter_mut().flat_map(|(i, t)| [i, t]);
+            for array in grown.chain(stamps).filter(|a| a.frames.is_some()) {
+                array.set_frames(self.complete)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bundles the members into the .npz and removes them.
+    fn bundle(&mut self, dir: &Path) -> io::Result<()> {
+        let mut names: Vec<&str> = self.arrays.iter().map(|(f, _)| f.var_name()).collect();
+        names.extend(["iteration", "time"]);
+        let members: Vec<(String, PathBuf)> = names
+            .iter()
+            .map(|name| (format!("{}.npy", name), dir.join(format!("{}.npy", name))))
+            .collect();
+        // the arrays' files must be flushed and closed first
+        self.arrays.clear();
+        self.stamps = None;
+        zip_stored(&self.path, &members)?;
+        fs::remove_dir_all(dir)
+    }
+}
+
+impl FrameWriter for NpyWriter {
+    fn write_block(&mut self, region: &Region, values: &[f64]) -> Result<(), WriteError> {
+        Ok(self.put_block(region, values)?)
+    }
+
+    fn close(mut self: Box<Self>) -> Result<(), WriteError> {
+        if let Some((&frame_id, _)) = self.filled.iter().next() {
+            return Err(
+                format!("{}: frame {} is incomplete", self.path.display(), frame_id).into(),
+            );
+        }
+        if let Some(dir) = self.members.take() {
+            self.bundle(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE) lookup table, as zip archives and PNG chunks use.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in bytes {
+        c = CRC_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+/// Version 4.5, the first with zip64 extensions.
+const ZIP64_VERSION: u16 = 45;
+
+/// Writes the files `members` as the stored, uncompressed entries of a zip
+/// archive at `out`, named as given. Every size and offset goes in zip64
+/// fields, as frames easily add up to more than 4 GiB.
+fn zip_stored(out: &Path, members: &[(String, PathBuf)]) -> io::Result<()> {
+    let mut part = out.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let mut zip = BufWriter::new(File::create(&part)?);
+    let mut central = vec![];
+    let mut offset = 0u64;
+    for (name, path) in members {
+        let size = fs::metadata(path)?.len();
+        let mut crc = 0;
+        let mut file = BufReader::new(File::open(path)?);
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            crc = crc32(crc, &buf[..n]);
+        }
+
+        let mut local = vec![];
+        put_u32(&mut local, LOCAL_HEADER);
+        put_u16(&mut local, ZIP64_VERSION);
+        put_u16(&mut local, 0); // flags
+        put_u16(&mut local, 0); // stored
+        put_u32(&mut local, 0); // time and date
+        put_u32(&mut local, crc);
+        put_u32(&mut local, u32::MAX); // sizes in the zip64 field
+        put_u32(&mut local, u32::MAX);
+        put_u16(&mut local, name.len() as u16);
+        put_u16(&mut local, 20); // zip64 field
+        local.extend_from_slice(name.as_bytes());
+        put_u16(&mut local, 1);
+        put_u16(&mut local, 16);
+        put_u64(&mut local, size);
+        put_u64(&mut local, size);
+        zip.write_all(&local)?;
+        let mut file = file.into_inner();
+        file.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut file, &mut zip)?;
+        if copied != size {
+            return Err(invalid(format!("{} changed while zipped", path.display())));
+        }
+
+        put_u32(&mut central, CENTRAL_HEADER);
+        put_u16(&mut central, ZIP64_VERSION); // made by
+        put_u16(&mut central, ZIP64_VERSION); // needed
+        put_u16(&mut central, 0);
+        put_u16(&mut central, 0);
+        put_u32(&mut central, 0);
+        put_u32(&mut central, crc);
+        put_u32(&mut central, u32::MAX);
+        put_u32(&mut central, u32::MAX);
+        put_u16(&mut central, name.len() as u16);
+        put_u16(&mut central, 28); // zip64 field
+        put_u16(&mut central, 0); // comment
+        put_u16(&mut central, 0); // disk
+        put_u16(&mut central, 0); // internal attributes
+        put_u32(&mut central, 0); // external attributes
+        put_u32(&mut central, u32::MAX); // offset in the zip64 field
+        central.extend_from_slice(name.as_bytes());
+        put_u16(&mut central, 1);
+        put_u16(&mut central, 24);
+        put_u64(&mut central, size);
+        put_u64(&mut central, size);
+        put_u64(&mut central, offset);
+        offset += local.len() as u64 + size;
+    }
+
+    let entries = members.len() as u64;
+    let mut end = central;
+    let central_len = end.len() as u64;
+    put_u32(&mut end, ZIP64_END);
+    put_u64(&mut end, 44); // size of the rest of the record
+    put_u16(&mut end, ZIP64_VERSION);
+    put_u16(&mut end, ZIP64_VERSION);
+    put_u32(&mut end, 0); // this disk
+    put_u32(&mut end, 0); // disk of the directory
+    put_u64(&mut end, entries);
+    put_u64(&mut end, entries);
+    put_u64(&mut end, central_len);
+    put_u64(&mut end, offset);
+    put_u32(&mut end, ZIP64_LOCATOR);
+    put_u32(&mut end, 0);
+    put_u64(&mut end, offset + central_len);
+    put_u32(&mut end, 1); // disks
+    put_u32(&mut end, END_OF_DIRECTORY);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, u16::MAX); // counts, size and offset in zip64 records
+    put_u16(&mut end, u16::MAX);
+    put_u32(&mut end, u32::MAX);
+    put_u32(&mut end, u32::MAX);
+    put_u16(&mut end, 0); // comment
+    zip.write_all(&end)?;
+    zip.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&part, out)
+}
+
+/// Extracts the stored entries of a zip archive written by `zip_stored`
+/// into `dir`.
+fn unzip_stored(zip: &Path, dir: &Path) -> io::Result<()> {
+    let not_ours = || {
+        invalid(format!(
+            "{} is not an .npz written by wave_2d",
+            zip.display()
+        ))
+    };
+    let mut file = File::open(zip)?;
+    let len = file.metadata()?.len();
+    // zip64 end record, locator and end of directory, without a comment
+    let tail_len = 56 + 20 + 22;
+    if len < tail_len {
+        return Err(not_ours());
+    }
+    let mut tail = vec![0u8; tail_len as usize];
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    file.read_exact(&mut tail)?;
+    if get_u32(&tail, 0) != ZIP64_END || get_u32(&tail, 56) != ZIP64_LOCATOR {
+        return Err(not_ours());
+    }
+    let entries = get_u64(&tail, 32);
+    let central_len = get_u64(&tail, 40);
+    let central_start = get_u64(&tail, 48);
+    let mut central = vec![0u8; central_len as usize];
+    file.seek(SeekFrom::Start(central_start))?;
+    file.read_exact(&mut central)?;
+
+    let mut at = 0;
+    for _ in 0..entries {
+        if central.len() < at + 46 || get_u32(&central, at) != CENTRAL_HEADER {
+            return Err(not_ours());
+        }
+        let name_len = get_u16(&central, at + 28) as usize;
+        let extra_len = get_u16(&central, at + 30) as usize;
+        let name = String::from_utf8_lossy(&central[at + 46..at + 46 + name_len]).into_owned();
+        let extra = at + 46 + name_len;
+        if extra_len != 28 || get_u16(&central, extra) != 1 || name.contains('/') {
+            return Err(not_ours());
+        }
+        let size = get_u64(&central, extra + 4);
+        let offset = get_u64(&central, extra + 20);
+        at = extra + extra_len + get_u16(&central, at + 32) as usize;
+
+        let mut local = [0u8; 30];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut local)?;
+        if get_u32(&local, 0) != LOCAL_HEADER {
+            return Err(not_ours());
+        }
+        let data = offset + 30 + get_u16(&local, 26) as u64 + get_u16(&local, 28) as u64;
+        file.seek(SeekFrom::Start(data))?;
+        let mut member = File::create(dir.join(&name))?;
+        let copied = io::copy(&mut (&mut file).take(size), &mut member)?;
+        if copied != size {
+            return Err(not_ours());
+        }
+    }
+    Ok(())
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
@@ -1,4 +1,5 @@
 use crate::buffer::ArrBuffer;
+use serde_json::Value;
 use std::sync::{Arc, Mutex};
 
 pub fn clear_alpha_region<'a>(
@@ -20,3 +21,41 @@ pub fn clear_alpha_region<'a>(
     }
 }
 
+/// Global m x n mask of the cells that keep a non-zero alpha once every
+/// `rectobstacle` in `config` has been cleared, row-major.
+pub fn active_mask(m: usize, n: usize, config: &Value) -> Vec<bool> {
+    let mut active = vec![true; m * n];
+    let Some(objects) = config.get("objects").and_then(|v| v.as_array()) else {
+        return active;
+    };
+    for object in objects {
+        if object.get("type").and_then(|v| v.as_str()) != Some("rectobstacle") {
+            continue;
+        }
+        let get = |key| object.get(key).and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+        let (row, col) = (get("row"), get("col"));
+        for r in row..(row + get("height")).min(m) {
+            for c in col..(col + get("width")).min(n) {
+                active[r * n + c] = false;
+            }
+        }
+    }
+    active
+}
+
+/// Cells of the `sine` sources in `config` that lie on the m x n grid, in
+/// the order of the objects.
+pub fn source_cells(m: usize, n: usize, config: &Value) -> Vec<(usize, usize)> {
+    let Some(objects) = config.get("objects").and_then(|v| v.as_array()) else {
+        return vec![];
+    };
+    objects
+        .iter()
+        .filter(|object| object.get("type").and_then(|v| v.as_str()) == Some("sine"))
+        .map(|object| {
+            let get = |key| object.get(key).and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+            (get("row"), get("col"))
+        })
+        .filter(|&(row, col)| row < m && col < n)
+        .collect()
+}
@@ -0,0 +1,290 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use tokio::sync::{mpsc as queue, watch};
+
+/// Where a block of values sits in the output: frame `frame_id`, global rows
+/// `start_row..start_row + m` and columns `start_col..start_col + n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub frame_id: usize,
+    pub start_row: usize,
+    pub start_col: usize,
+    pub m: usize,
+    pub n: usize,
+}
+
+/// One tile's share of a frame: a plane of `m x n` values per field.
+#[derive(Debug, Clone)]
+pub struct TileBlock {
+    pub region: Region,
+    pub data: Vec<f64>,
+}
+
+/// Error a `FrameWriter` fails with, whatever its backend.
+pub type WriteError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An output format the writer thread stores frames in.
+pub trait FrameWriter {
+    /// Stores the block of frame values at `region`: a plane of `m x n`
+    /// row-major values per output field, in the order of the fields.
+    /// Blocks may come in any order; a whole frame is just one block.
+    fn write_block(&mut self, region: &Region, values: &[f64]) -> Result<(), WriteError>;
+
+    /// Completes the output once every frame has been written.
+    fn close(self: Box<Self>) -> Result<(), WriteError>;
+}
+
+/// Where tiles deliver their frames.
+pub enum FrameSink {
+    /// Gathered into whole frames, which are written one at a time.
+    Gather(FramePipeline),
+    /// Written block by block as tiles deliver them, so no frame is ever
+    /// held whole in memory.
+    Blocks(BlockQueue),
+}
+
+/// The receiving end a writer thread drains a `FrameSink` from.
+pub enum Ready {
+    Frames(Receiver<GatheredFrame>),
+    Blocks(queue::Receiver<TileBlock>),
+}
+
+impl FrameSink {
+    /// Number of planes, one per output field, in every block.
+    pub fn fields(&self) -> usize {
+        match self {
+            FrameSink::Gather(pipeline) => pipeline.fields,
+            FrameSink::Blocks(queue) => queue.fields,
+        }
+    }
+
+    pub async fn submit(&self, block: TileBlock) {
+        match self {
+            FrameSink::Gather(pipeline) => pipeline.submit_block(block).await,
+            FrameSink::Blocks(queue) => queue.submit(block).await,
+        }
+    }
+
+    /// Signals the writer that no more frames will be submitted.
+    pub fn close(&self) {
+        match self {
+            FrameSink::Gather(pipeline) => pipeline.close(),
+            FrameSink::Blocks(queue) => queue.close(),
+        }
+    }
+
+    /// Runs on the writer thread: hands everything submitted to `write`,
+    /// whole frames or single tiles' blocks, until `close` has been called
+    /// and nothing is left.
+    pub fn drain<E, W>(&self, ready: Ready, mut write: W) -> Result<(), E>
+    where
+        W: FnMut(&Region, &[f64]) -> Result<(), E>,
+    {
+        match (self, ready) {
+            (FrameSink::Gather(pipeline), Ready::Frames(ready)) => {
+                pipeline.drain(ready, |frame_id, grid| {
+                    let whole = Region {
+                        frame_id,
+                        start_row: 0,
+                        start_col: 0,
+                        m: pipeline.rows,
+                        n: pipeline.cols,
+                    };
+                    write(&whole, grid)
+                })
+            }
+            (FrameSink::Blocks(_), Ready::Blocks(mut ready)) => {
+                // a failed write drops `ready`, which fails the tiles' sends
+                while let Some(block) = ready.blocking_recv() {
+                    write(&block.region, &block.data)?;
+                }
+                Ok(())
+            }
+            _ => panic!("frame sink drained from the wrong kind of receiver"),
+        }
+    }
+}
+
+/// Bounded queue of tile blocks in front of a single writer thread, which
+/// serialises their writes. A tile that gets too far ahead of the writer
+/// waits for room in the queue.
+pub struct BlockQueue {
+    pub fields: usize,
+    tx: Mutex<Option<queue::Sender<TileBlock>>>,
+}
+
+impl BlockQueue {
+    /// Room for `depth` frames of `num_tiles` blocks each.
+    pub fn new(fields: usize, num_tiles: usize, depth: usize) -> (Self, Ready) {
+        let (tx, rx) = queue::channel(num_tiles * depth);
+        let queue = BlockQueue {
+            fields,
+            tx: Mutex::new(Some(tx)),
+        };
+        (queue, Ready::Blocks(rx))
+    }
+
+    async fn submit(&self, block: TileBlock) {
+        let frame_id = block.region.frame_id;
+        // a tile outside the output's cells has nothing to write
+        if block.region.m * block.region.n == 0 {
+            return;
+        }
+        let tx = self.tx.lock().unwrap().clone();
+        let sent = match tx {
+            Some(tx) => tx.send(block).await.is_ok(),
+            None => false,
+        };
+        if !sent {
+            panic!("frame writer stopped before frame {}", frame_id);
+        }
+    }
+
+    fn close(&self) {
+        self.tx.lock().unwrap().take();
+    }
+}
+
+/// Gathers the tiles' blocks of each frame for a writer thread, which
+/// assembles and writes whole frames.
+///
+/// A frame holds `fields` planes of `rows x cols` values one after another,
+/// one per output field. Tiles only hand over their blocks, so no tile
+/// touches the frame the writer assembles; the writer copies the blocks of
+/// a complete frame into it. At most two frames are in flight: a tile that
+/// is two frames ahead of the writer waits until the frame before has been
+/// written out.
+pub struct FramePipeline {
+    pub rows: usize,
+    pub cols: usize,
+    pub fields: usize,
+    num_tiles: usize,
+    gather: Mutex<GatherState>,
+    flushed: watch::Sender<usize>,
+    failed: AtomicBool,
+}
+
+/// A complete frame as the writer receives it: its id and every tile's
+/// block.
+pub type GatheredFrame = (usize, Vec<TileBlock>);
+
+struct GatherState {
+    /// blocks of frame `k` so far, in slot `k % 2`
+    blocks: [Vec<TileBlock>; 2],
+    ready: Option<Sender<GatheredFrame>>,
+}
+
+impl FramePipeline {
+    pub fn new(
+        rows: usize,
+        cols: usize,
+        fields: usize,
+        num_tiles: usize,
+    ) -> (Self, Receiver<GatheredFrame>) {
+        Self::starting_at(rows, cols, fields, num_tiles, 0)
+    }
+
+    /// Like `new`, for a run whose first frame is `first_frame`.
+    pub fn starting_at(
+        rows: usize,
+        cols: usize,
+        fields: usize,
+        num_tiles: usize,
+        first_frame: usize,
+    ) -> (Self, Receiver<GatheredFrame>) {
+        let (tx, rx) = mpsc::channel();
+        // counts frames on disk, including those before `first_frame`
+        let (flushed, _) = watch::channel(first_frame);
+        let pipeline = FramePipeline {
+            rows,
+            cols,
+            fields,
+            num_tiles,
+            gather: Mutex::new(GatherState {
+                blocks: [
+                    Vec::with_capacity(num_tiles),
+                    Vec::with_capacity(num_tiles),
+                ],
+                ready: Some(tx),
+            }),
+            flushed,
+            failed: AtomicBool::new(false),
+        };
+        (pipeline, rx)
+    }
+
+    /// Hands a tile's block to the writer. Once the last tile of its frame
+    /// has contributed, the frame goes to the writer.
+    pub async fn submit_block(&self, block: TileBlock) {
+        let frame_id = block.region.frame_id;
+        // frame_id - 2 was gathered in the same slot; wait for it to reach
+        // the disk
+        let mut flushed = self.flushed.subscribe();
+        let _ = flushed
+            .wait_for(|&n| n + 2 > frame_id || self.failed.load(Ordering::Acquire))
+            .await;
+        if self.failed.load(Ordering::Acquire) {
+            panic!("frame writer stopped before frame {}", frame_id);
+        }
+
+        // handed over under the lock so frames reach the writer in order
+        let mut gather = self.gather.lock().unwrap();
+        let blocks = &mut gather.blocks[frame_id % 2];
+        blocks.push(block);
+        if blocks.len() == self.num_tiles {
+            let blocks = std::mem::replace(blocks, Vec::with_capacity(self.num_tiles));
+            if let Some(tx) = gather.ready.as_ref() {
+                let _ = tx.send((frame_id, blocks));
+            }
+        }
+    }
+
+    /// Signals the writer that no more frames will be submitted.
+    pub fn close(&self) {
+        self.gather.lock().unwrap().ready.take();
+    }
+
+    /// Runs on the writer thread: assembles every complete frame from its
+    /// blocks and hands it to `write`, in order. Returns once `close` has
+    /// been called and all pending frames are written.
+    pub fn drain<E, W>(&self, ready: Receiver<GatheredFrame>, mut write: W) -> Result<(), E>
+    where
+        W: FnMut(usize, &[f64]) -> Result<(), E>,
+    {
+        let (rows, cols) = (self.rows, self.cols);
+        let mut grid = vec![0.0; self.fields * rows * cols];
+        for (frame_id, blocks) in ready {
+            for block in &blocks {
+                let Region {
+                    start_row,
+                    start_col,
+                    m,
+                    n,
+                    ..
+                } = block.region;
+                if m * n == 0 {
+                    continue;
+                }
+                for (plane, values) in grid
+                    .chunks_exact_mut(rows * cols)
+                    .zip(block.data.chunks_exact(m * n))
+                {
+                    for i in 0..m {
+                        let row = cols * (start_row + i) + start_col;
+                        plane[row..row + n].copy_from_slice(&values[i * n..(i + 1) * n]);
+                    }
+                }
+            }
+            // free the blocks before the write, which may take a while
+            drop(blocks);
+            if let Err(e) = write(frame_id, &grid) {
+                self.failed.store(true, Ordering::Release);
+                self.flushed.send_modify(|_| {});
+                return Err(e);
+            }
+            self.flushed.send_modify(|n| *n += 1);
+        }
+        Ok(())
+    }
+}
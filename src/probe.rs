@@ -0,0 +1,221 @@
+use crate::controlblock::ControlBlock;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A cell whose `u` is recorded after every iteration: a `probe` object of
+/// the config, with `row`, `col` and an optional `name`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Probe {
+    pub name: String,
+    pub row: usize,
+    pub col: usize,
+}
+
+/// The `probe` objects of `config`, in order. Each must lie on the m x n
+/// grid and have a name of its own; unnamed ones are called
+/// `probe_<row>_<col>`.
+pub fn probes(m: usize, n: usize, config: &Value) -> Result<Vec<Probe>, String> {
+    let Some(objects) = config.get("objects").and_then(|v| v.as_array()) else {
+        return Ok(vec![]);
+    };
+    let mut probes: Vec<Probe> = vec![];
+    for object in objects {
+        if object.get("type").and_then(|v| v.as_str()) != Some("probe") {
+            continue;
+        }
+        let get = |key| object.get(key).and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+        let (row, col) = (get("row"), get("col"));
+        let name = match object.get("name").and_then(|v| v.as_str()) {
+            Some(name) => name.to_string(),
+            None => format!("probe_{}_{}", row, col),
+        };
+        if row >= m || col >= n {
+            return Err(format!(
+                "probe {} at ({}, {}) is off the {}x{} grid",
+                name, row, col, m, n
+            ));
+        }
+        if name.is_empty() || name.contains([',', '"', '\n']) {
+            return Err(format!("probe name {:?} cannot go in a CSV header", name));
+        }
+        if probes.iter().any(|p| p.name == name) {
+            return Err(format!("two probes are called {}", name));
+        }
+        probes.push(Probe { name, row, col });
+    }
+    Ok(probes)
+}
+
+/// `u` at probe `probe` (its index in `ControlBlock::probes`) after
+/// iteration `iter`, as the tile owning the cell saw it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeSample {
+    pub probe: usize,
+    pub iter: usize,
+    pub value: f64,
+}
+
+/// Iteration and the value of every probe after it.
+pub type TraceRow = (usize, Vec<f64>);
+
+/// Puts the samples tiles send in any order back into one row per
+/// iteration, and appends each row to the CSV file `probe_output` once it
+/// is complete and all rows before it are written: `iteration,time,` then
+/// one column per probe.
+pub struct ProbeRecorder {
+    traces: Mutex<Traces>,
+}
+
+struct Traces {
+    csv: Option<(BufWriter<File>, PathBuf)>,
+    num_probes: usize,
+    dt: f64,
+    /// complete rows, in order
+    rows: Vec<TraceRow>,
+    /// iteration the next complete row is for
+    next: usize,
+    /// rows still missing samples
+    pending: BTreeMap<usize, Vec<Option<f64>>>,
+}
+
+impl ProbeRecorder {
+    /// Starts a new CSV file with the header of `cb`'s probes.
+    pub fn create(cb: &ControlBlock) -> io::Result<Self> {
+        let mut csv = BufWriter::new(File::create(&cb.probe_output)?);
+        csv.write_all(csv_header(cb).as_bytes())?;
+        csv.flush()?;
+        Ok(Self::with_rows(cb, Some(csv), vec![]))
+    }
+
+    /// Continues the CSV file of the run `cb` restarts: it keeps its rows
+    /// up to iteration `cb.first_iter`, which must all be there.
+    pub fn append(cb: &ControlBlock) -> io::Result<Self> {
+        let path = &cb.probe_output;
+        let invalid = |what: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} {}", path.display(), what),
+            )
+        };
+        let text = fs::read_to_string(path)?;
+        let header = csv_header(cb);
+        if !text.starts_with(&header) {
+            return Err(invalid(format!("does not start with {:?}", header.trim())));
+        }
+        let mut rows = vec![];
+        let mut kept = header.len();
+        for line in text[header.len()..].split_inclusive('\n') {
+            let row = parse_row(line, cb.probes.len()).filter(|(iter, _)| *iter < cb.first_iter);
+            let Some(row) = row else {
+                break;
+            };
+            rows.push(row);
+            kept += line.len();
+        }
+        if cb.first_iter > 0 && rows.last().map(|(iter, _)| iter + 1) != Some(cb.first_iter) {
+            return Err(invalid(format!(
+                "ends before iteration {}",
+                cb.first_iter - 1
+            )));
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        file.set_len(kept as u64)?;
+        Ok(Self::with_rows(cb, Some(BufWriter::new(file)), rows))
+    }
+
+    /// Keeps the rows without writing them anywhere.
+    pub fn in_memory(cb: &ControlBlock) -> Self {
+        Self::with_rows(cb, None, vec![])
+    }
+
+    fn with_rows(cb: &ControlBlock, csv: Option<BufWriter<File>>, rows: Vec<TraceRow>) -> Self {
+        ProbeRecorder {
+            traces: Mutex::new(Traces {
+                csv: csv.map(|csv| (csv, cb.probe_output.clone())),
+                num_probes: cb.probes.len(),
+                dt: cb.dt,
+                rows,
+                next: cb.first_iter,
+                pending: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Files `samples` and writes out the rows they complete.
+    pub fn record(&self, samples: &[ProbeSample]) -> io::Result<()> {
+        let mut traces = self.traces.lock().unwrap();
+        let traces = &mut *traces;
+        for sample in samples {
+            let row = traces
+                .pending
+                .entry(sample.iter)
+                .or_insert_with(|| vec![None; traces.num_probes]);
+            row[sample.probe] = Some(sample.value);
+        }
+        let first = traces.rows.len();
+        while let Some(row) = traces.pending.get(&traces.next) {
+            if row.iter().any(Option::is_none) {
+                break;
+            }
+            let row = traces.pending.remove(&traces.next).unwrap();
+            traces
+                .rows
+                .push((traces.next, row.into_iter().flatten().collect()));
+            traces.next += 1;
+        }
+        if let Some((csv, _)) = &mut traces.csv {
+            for (iter, values) in &traces.rows[first..] {
+                write!(csv, "{},{}", iter, *iter as f64 * traces.dt)?;
+                for value in values {
+                    write!(csv, ",{}", value)?;
+                }
+                writeln!(csv)?;
+            }
+            csv.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Every row recorded, those kept from a restarted run first. Fails if
+    /// samples are still missing.
+    pub fn finish(&self) -> io::Result<Vec<TraceRow>> {
+        let traces = self.traces.lock().unwrap();
+        if let Some((&iter, _)) = traces.pending.iter().next() {
+            let path = match &traces.csv {
+                Some((_, path)) => path.display().to_string(),
+                None => "probe traces".to_string(),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{}: iteration {} is incomplete", path, iter),
+            ));
+        }
+        Ok(traces.rows.clone())
+    }
+}
+
+fn csv_header(cb: &ControlBlock) -> String {
+    let mut header = "iteration,time".to_string();
+    for probe in &cb.probes {
+        header.push(',');
+        header.push_str(&probe.name);
+    }
+    header + "\n"
+}
+
+/// A whole CSV line of `num_probes` values, or `None` for anything else,
+/// such as the part of a line a failed run left behind.
+fn parse_row(line: &str, num_probes: usize) -> Option<TraceRow> {
+    let mut items = line.strip_suffix('\n')?.split(',');
+    let iter = items.next()?.parse().ok()?;
+    items.next()?;
+    let values = items
+        .map(|v| v.parse().ok())
+        .collect::<Option<Vec<f64>>>()?;
+    (values.len() == num_probes).then_some((iter, values))
+}
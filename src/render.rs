@@ -0,0 +1,382 @@
+use crate::controlblock::ControlBlock;
+use crate::npy::crc32;
+use crate::obstacle::{active_mask, source_cells};
+use crate::output::{FrameWriter, Region, WriteError};
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::fd::FromRawFd;
+use std::path::PathBuf;
+
+/// Colormap anchors, evenly spaced from the low end to the high end.
+const SEISMIC: [[f64; 3]; 5] = [
+    [0.0, 0.0, 0.3],
+    [0.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0],
+    [1.0, 0.0, 0.0],
+    [0.5, 0.0, 0.0],
+];
+const GRAYSCALE: [[f64; 3]; 2] = [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0]];
+/// viridis at nine evenly spaced points.
+const VIRIDIS: [[f64; 3]; 9] = [
+    [0.266667, 0.003922, 0.329412],
+    [0.278431, 0.176471, 0.482353],
+    [0.231373, 0.321569, 0.545098],
+    [0.172549, 0.447059, 0.556863],
+    [0.129412, 0.568627, 0.549020],
+    [0.156863, 0.682353, 0.501961],
+    [0.368627, 0.788235, 0.384314],
+    [0.678431, 0.862745, 0.188235],
+    [0.992157, 0.905882, 0.145098],
+];
+
+/// Color of `t` in `0..=1` along `anchors`, interpolated linearly.
+fn color(anchors: &[[f64; 3]], t: f64) -> [u8; 3] {
+    let x = t.clamp(0.0, 1.0) * (anchors.len() - 1) as f64;
+    let i = (x.floor() as usize).min(anchors.len() - 2);
+    let f = x - i as f64;
+    let (a, b) = (anchors[i], anchors[i + 1]);
+    [0, 1, 2].map(|k| ((a[k] + f * (b[k] - a[k])) * 255.0).round() as u8)
+}
+
+/// How frames of `data` are drawn, the same for every image output: the
+/// colormap spans the color limits, obstacle cells get a solid color and
+/// each source a cross.
+struct Painter {
+    rows: usize,
+    cols: usize,
+    anchors: &'static [[f64; 3]],
+    limits: Option<(f64, f64)>,
+    obstacle_color: [u8; 3],
+    source_color: [u8; 3],
+    /// cells outside every obstacle, row-major
+    active: Vec<bool>,
+    sources: Vec<(usize, usize)>,
+}
+
+impl Painter {
+    fn new(cb: &ControlBlock) -> Self {
+        let sampling = cb.sampling();
+        let (rows, cols) = (sampling.rows(), sampling.cols());
+        // an output cell shows an obstacle if the first cell it stands for is one
+        let active = active_mask(cb.m, cb.n, &cb.config);
+        let active = (0..rows * cols)
+            .map(|k| active[sampling.row_of(k / cols) * cb.n + sampling.col_of(k % cols)])
+            .collect();
+        let sources = source_cells(cb.m, cb.n, &cb.config)
+            .into_iter()
+            .filter(|&(r, c)| r >= sampling.row && c >= sampling.col)
+            .map(|(r, c)| {
+                (
+                    (r - sampling.row) / sampling.stride,
+                    (c - sampling.col) / sampling.stride,
+                )
+            })
+            .filter(|&(i, j)| i < rows && j < cols)
+            .collect();
+        Painter {
+            rows,
+            cols,
+            anchors: match cb.colormap.as_str() {
+                "viridis" => &VIRIDIS,
+                "grayscale" => &GRAYSCALE,
+                _ => &SEISMIC,
+            },
+            limits: cb.color_limits,
+            obstacle_color: cb.obstacle_color,
+            source_color: cb.source_color,
+            active,
+            sources,
+        }
+    }
+
+    /// RGB pixels of frame `u`, row by row.
+    fn paint(&self, u: &[f64]) -> Vec<u8> {
+        let (low, high) = self.limits.unwrap_or_else(|| {
+            let max = u.iter().fold(0.0f64, |max, v| max.max(v.abs()));
+            let max = if max > 0.0 { max } else { 1.0 };
+            (-max, max)
+        });
+        let mut pixels = Vec::with_capacity(3 * u.len());
+        for (cell, &v) in u.iter().enumerate() {
+            let rgb = if self.active[cell] {
+                color(self.anchors, (v - low) / (high - low))
+            } else {
+                self.obstacle_color
+            };
+            pixels.extend_from_slice(&rgb);
+        }
+        for &(row, col) in &self.sources {
+            let arm = 2;
+            let cross = (row.saturating_sub(arm)..=row + arm)
+                .map(|r| (r, col))
+                .chain((col.saturating_sub(arm)..=col + arm).map(|c| (row, c)));
+            for (r, c) in cross.filter(|&(r, c)| r < self.rows && c < self.cols) {
+                let at = 3 * (r * self.cols + c);
+                pixels[at..at + 3].copy_from_slice(&self.source_color);
+            }
+        }
+        pixels
+    }
+}
+
+/// Whole frames of `data` put together from tiles' blocks.
+struct Assembler {
+    rows: usize,
+    cols: usize,
+    /// frames with blocks still missing, with the values they have so far
+    pending: BTreeMap<usize, (Vec<f64>, usize)>,
+}
+
+impl Assembler {
+    fn new(rows: usize, cols: usize) -> Self {
+        Assembler {
+            rows,
+            cols,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the `data` plane of a block, the first of `values`, and returns
+    /// its frame once complete.
+    fn add(&mut self, region: &Region, values: &[f64]) -> Option<Vec<f64>> {
+        let frame = self.rows * self.cols;
+        let size = region.m * region.n;
+        if size == frame {
+            return Some(values[..size].to_vec());
+        }
+        let (u, filled) = self
+            .pending
+            .entry(region.frame_id)
+            .or_insert_with(|| (vec![0.0; frame], 0));
+        for i in 0..region.m {
+            let at = (region.start_row + i) * self.cols + region.start_col;
+            u[at..at + region.n].copy_from_slice(&values[i * region.n..(i + 1) * region.n]);
+        }
+        *filled += size;
+        if *filled < frame {
+            return None;
+        }
+        self.pending.remove(&region.frame_id).map(|(u, _)| u)
+    }
+
+    /// Fails if any frame is still missing blocks.
+    fn finish(&self) -> Result<(), WriteError> {
+        match self.pending.keys().next() {
+            Some(frame_id) => Err(format!("frame {} is incomplete", frame_id).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Draws every frame as an RGB PNG, `<stem>_NNNNNN.png` next to the output.
+pub struct PngWriter {
+    /// `<stem>` of the frames' paths, directory included
+    prefix: PathBuf,
+    painter: Painter,
+    frames: Assembler,
+}
+
+impl PngWriter {
+    pub fn create(cb: &ControlBlock) -> io::Result<Self> {
+        let stem = cb
+            .output
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "output".to_string());
+        let prefix = cb.output.with_file_name(stem);
+        if let Some(dir) = prefix.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(PngWriter {
+            prefix,
+            painter: Painter::new(cb),
+            frames: Assembler::new(cb.sampling().rows(), cb.sampling().cols()),
+        })
+    }
+
+    fn path(&self, frame_id: usize) -> PathBuf {
+        let mut path = self.prefix.as_os_str().to_owned();
+        path.push(format!("_{:06}.png", frame_id));
+        PathBuf::from(path)
+    }
+}
+
+impl FrameWriter for PngWriter {
+    fn write_block(&mut self, region: &Region, values: &[f64]) -> Result<(), WriteError> {
+        if let Some(u) = self.frames.add(region, values) {
+            let (rows, cols) = (self.painter.rows, self.painter.cols);
+            let png = encode_png(cols, rows, &self.painter.paint(&u));
+            fs::write(self.path(region.frame_id), png)?;
+        }
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), WriteError> {
+        self.frames.finish()
+    }
+}
+
+/// Streams the frames as YUV4MPEG2 video, 4:4:4 in limited-range BT.601,
+/// to a file or to stdout for an encoder to read.
+pub struct Y4mWriter {
+    out: BufWriter<File>,
+    painter: Painter,
+    frames: Assembler,
+    /// frame the stream continues with
+    next: usize,
+    /// frames complete before those ahead of them
+    ready: BTreeMap<usize, Vec<f64>>,
+}
+
+impl Y4mWriter {
+    /// A stream to `cb.output`, or to `stdout` if given.
+    pub fn create(cb: &ControlBlock, stdout: Option<File>) -> io::Result<Self> {
+        let mut out = match stdout {
+            Some(stdout) => stdout,
+            None => File::create(&cb.output)?,
+        };
+        out.write_all(stream_header(cb).as_bytes())?;
+        Ok(Self::continuing(cb, out))
+    }
+
+    /// Continues the stream of the run `cb` restarts after its first
+    /// `cb.first_frame` frames.
+    pub fn append(cb: &ControlBlock) -> io::Result<Self> {
+        let header = stream_header(cb);
+        let mut out = OpenOptions::new().read(true).write(true).open(&cb.output)?;
+        let mut found = vec![0u8; header.len()];
+        out.read_exact(&mut found)?;
+        if found != header.as_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} does not start with {:?}",
+                    cb.output.display(),
+                    header.trim()
+                ),
+            ));
+        }
+        let sampling = cb.sampling();
+        let frame_len = FRAME_MARK.len() + 3 * sampling.rows() * sampling.cols();
+        let end = (header.len() + cb.first_frame * frame_len) as u64;
+        if out.metadata()?.len() < end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} ends before frame {}",
+                    cb.output.display(),
+                    cb.first_frame
+                ),
+            ));
+        }
+        out.set_len(end)?;
+        out.seek(SeekFrom::Start(end))?;
+        Ok(Self::continuing(cb, out))
+    }
+
+    fn continuing(cb: &ControlBlock, out: File) -> Self {
+        Y4mWriter {
+            out: BufWriter::new(out),
+            painter: Painter::new(cb),
+            frames: Assembler::new(cb.sampling().rows(), cb.sampling().cols()),
+            next: cb.first_frame,
+            ready: BTreeMap::new(),
+        }
+    }
+
+    fn put_frame(&mut self, u: &[f64]) -> io::Result<()> {
+        let rgb = self.painter.paint(u);
+        let cells = rgb.len() / 3;
+        let mut planes = vec![0u8; 3 * cells];
+        let (y, chroma) = planes.split_at_mut(cells);
+        let (cb, cr) = chroma.split_at_mut(cells);
+        for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+            let [r, g, b] = [0, 1, 2].map(|k| pixel[k] as f64 / 255.0);
+            y[i] = (16.0 + 65.481 * r + 128.553 * g + 24.966 * b).round() as u8;
+            cb[i] = (128.0 - 37.797 * r - 74.203 * g + 112.0 * b).round() as u8;
+            cr[i] = (128.0 + 112.0 * r - 93.786 * g - 18.214 * b).round() as u8;
+        }
+        self.out.write_all(FRAME_MARK)?;
+        self.out.write_all(&planes)
+    }
+}
+
+/// Leads every frame of a YUV4MPEG2 stream.
+const FRAME_MARK: &[u8] = b"FRAME\n";
+
+/// Stream header of the frames of `cb`.
+fn stream_header(cb: &ControlBlock) -> String {
+    format!(
+        "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444 XCOLORRANGE=LIMITED\n",
+        cb.sampling().cols(),
+        cb.sampling().rows(),
+        cb.fps
+    )
+}
+
+impl FrameWriter for Y4mWriter {
+    fn write_block(&mut self, region: &Region, values: &[f64]) -> Result<(), WriteError> {
+        if let Some(u) = self.frames.add(region, values) {
+            self.ready.insert(region.frame_id, u);
+        }
+        while let Some(u) = self.ready.remove(&self.next) {
+            self.put_frame(&u)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), WriteError> {
+        self.frames.finish()?;
+        if let Some(frame_id) = self.ready.keys().next() {
+            return Err(format!("frame {} is incomplete", self.next.min(*frame_id)).into());
+        }
+        self.out.into_inner().map_err(|e| e.into_error())?.flush()?;
+        Ok(())
+    }
+}
+
+/// Takes stdout over for a stream: returns the original stdout and points
+/// file descriptor 1 at stderr, so nothing else printed ends up in the
+/// stream, including the output of tile processes started afterwards.
+pub fn take_stdout() -> io::Result<File> {
+    io::stdout().flush()?;
+    // the duplicate is owned by the File from here on
+    unsafe {
+        let fd = libc::dup(1);
+        if fd < 0 || libc::dup2(2, 1) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+/// An 8-bit RGB PNG of `pixels`, `width` by `height`.
+fn encode_png(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    // each scanline is led by its filter type, none here
+    let mut rows = Vec::with_capacity(pixels.len() + height);
+    for line in pixels.chunks_exact(3 * width) {
+        rows.push(0);
+        rows.extend_from_slice(line);
+    }
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth 8, truecolor, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    for (kind, data) in [
+        (b"IHDR", header),
+        (b"IDAT", compress_to_vec_zlib(&rows, 6)),
+        (b"IEND", vec![]),
+    ] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(&data);
+        let crc = crc32(crc32(0, kind), &data);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
@@ -0,0 +1,136 @@
+use crate::decomposition::{Decomposition, MIN_TILE};
+use std::ops::Range;
+
+/// Which cells of the m x n grid reach the output frames: those of the crop
+/// rectangle `height x width` at (`row`, `col`), every `stride`-th of them
+/// along each axis, or the mean of each `stride x stride` block if
+/// `average`, in which case cells of an incomplete last block are left out.
+///
+/// Tiles sample their own cells before sending them, so output row `i`
+/// belongs to the tile holding global row `row_of(i)`. An averaged block
+/// must lie in one tile, which `align` sees to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampling {
+    pub row: usize,
+    pub col: usize,
+    pub height: usize,
+    pub width: usize,
+    pub stride: usize,
+    pub average: bool,
+}
+
+impl Sampling {
+    /// Output cells along an axis of `len` crop cells.
+    fn cells(&self, len: usize) -> usize {
+        if self.average {
+            len / self.stride
+        } else {
+            len.div_ceil(self.stride)
+        }
+    }
+
+    /// Rows and columns of an output frame.
+    pub fn rows(&self) -> usize {
+        self.cells(self.height)
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cells(self.width)
+    }
+
+    /// Global row of the first cell of output row `i`.
+    pub fn row_of(&self, i: usize) -> usize {
+        self.row + i * self.stride
+    }
+
+    pub fn col_of(&self, j: usize) -> usize {
+        self.col + j * self.stride
+    }
+
+    /// Where output row `i` sits, in cells from the grid origin: on its
+    /// cell, or in the middle of its block.
+    pub fn row_position(&self, i: usize) -> f64 {
+        self.row_of(i) as f64 + self.block_centre()
+    }
+
+    pub fn col_position(&self, j: usize) -> f64 {
+        self.col_of(j) as f64 + self.block_centre()
+    }
+
+    fn block_centre(&self) -> f64 {
+        if self.average {
+            (self.stride - 1) as f64 / 2.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Output cells of size `count` whose first cell, `origin + i * stride`,
+    /// lies in `start..start + len`.
+    fn owned(&self, origin: usize, count: usize, start: usize, len: usize) -> Range<usize> {
+        let first = |at: usize| (at.saturating_sub(origin)).div_ceil(self.stride).min(count);
+        first(start)..first(start + len)
+    }
+
+    /// Output rows a tile holding global rows `start..start + len` produces.
+    pub fn owned_rows(&self, start: usize, len: usize) -> Range<usize> {
+        self.owned(self.row, self.rows(), start, len)
+    }
+
+    pub fn owned_cols(&self, start: usize, len: usize) -> Range<usize> {
+        self.owned(self.col, self.cols(), start, len)
+    }
+
+    /// `decomp` with its cuts moved onto block edges, so every averaged
+    /// block lies in one tile. Cuts snap to the nearest edge, or to the
+    /// edge nearest them on the way back to the matching cut of `from`
+    /// (already aligned), so a rebalancing never moves cells further than
+    /// it asked to. `None` if that leaves a tile with too few cells.
+    pub fn align(
+        &self,
+        decomp: &Decomposition,
+        from: Option<&Decomposition>,
+    ) -> Option<Decomposition> {
+        if !self.average || self.stride == 1 {
+            return Some(decomp.clone());
+        }
+        let axis = |starts: &[usize], from: Option<&Vec<usize>>, origin: usize, count: usize| {
+            let end = origin + count * self.stride;
+            let mut aligned = starts.to_vec();
+            let inner = aligned.len() - 1;
+            for (k, cut) in aligned.iter_mut().enumerate().take(inner).skip(1) {
+                if *cut <= origin || *cut >= end || (*cut - origin).is_multiple_of(self.stride) {
+                    continue;
+                }
+                let below = origin + (*cut - origin) / self.stride * self.stride;
+                let above = below + self.stride;
+                *cut = match from.map(|f| f[k]) {
+                    Some(old) if old <= below => below,
+                    Some(old) if old >= above => above,
+                    Some(_) => below,
+                    None if *cut - below < above - *cut => below,
+                    None => above,
+                };
+            }
+            aligned
+                .windows(2)
+                .all(|w| w[1] >= w[0] + MIN_TILE)
+                .then_some(aligned)
+        };
+        Some(Decomposition {
+            row_starts: axis(
+                &decomp.row_starts,
+                from.map(|f| &f.row_starts),
+                self.row,
+                self.rows(),
+            )?,
+            col_starts: axis(
+                &decomp.col_starts,
+                from.map(|f| &f.col_starts),
+                self.col,
+                self.cols(),
+            )?,
+            ..decomp.clone()
+        })
+    }
+}
@@ -0,0 +1,712 @@
+use crate::balance::LoadBalancer;
+use crate::buffer::{ArrBuffer, Axis};
+use crate::checkpoint::{read_resliced, CheckpointWriter, TileState};
+use crate::controlblock::ControlBlock;
+use crate::decomposition::Decomposition;
+use crate::fields::Field;
+use crate::halo::{exchange_ghost_cells, migrate, HaloError, HaloState};
+use crate::interrupt;
+use crate::kernel::{compute_edge_u, compute_u};
+use crate::obstacle::clear_alpha_region;
+use crate::output::{FrameSink, Region, TileBlock};
+use crate::probe::{ProbeRecorder, ProbeSample};
+use crate::stimulus::{Stimulus, DEFAULT_AMPLITUDE};
+use crate::transport::{
+    bytes_to_f64s, f64s_to_bytes, HaloTransport, Listener, ReadHalf, Stream, WriteHalf,
+};
+use futures::FutureExt;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+/// The first failure among the tiles of this process. Once one is
+/// recorded, every tile running under `guard` stops.
+pub struct Failure {
+    first: watch::Sender<Option<String>>,
+}
+
+impl Default for Failure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Failure {
+    pub fn new() -> Self {
+        Failure {
+            first: watch::channel(None).0,
+        }
+    }
+
+    /// Records `reason`, unless an earlier failure is already known: what
+    /// other tiles report afterwards is usually a consequence of it.
+    pub fn report(&self, reason: String) {
+        self.first.send_if_modified(|first| {
+            if first.is_none() {
+                *first = Some(reason);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.first.borrow().clone()
+    }
+
+    async fn occurred(&self) {
+        let _ = self.first.subscribe().wait_for(Option::is_some).await;
+    }
+}
+
+/// Why a tile stopped short of the end of its run.
+#[derive(Debug)]
+pub enum TileError {
+    /// Saving its state before iteration `next_iter` failed.
+    Checkpoint { next_iter: usize, error: io::Error },
+    /// Handing its probe samples to the recorder failed.
+    Probes { error: io::Error },
+    /// Reading its block of the checkpoint it restarts from failed.
+    Restart(io::Error),
+    /// Exchanging ghost cells with a neighbour failed.
+    Halo(HaloError),
+    /// Sending output frame `frame` failed.
+    Frame { frame: usize, error: io::Error },
+    /// Reporting its load to the balancer failed.
+    Load { error: io::Error },
+    /// Moving its boundaries along `axis` to the rebalanced layout failed.
+    Migrate { axis: Axis, error: HaloError },
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::Checkpoint { next_iter, error } => {
+                write!(f, "checkpoint before iteration {} failed: {}", next_iter, error)
+            }
+            TileError::Probes { error } => write!(f, "recording probes failed: {}", error),
+            TileError::Restart(error) => {
+                write!(f, "reading the restart checkpoint failed: {}", error)
+            }
+            TileError::Halo(error) => write!(f, "{}", error),
+            TileError::Frame { frame, error } => {
+                write!(f, "sending frame {} failed: {}", frame, error)
+            }
+            TileError::Load { error } => write!(f, "load report failed: {}", error),
+            TileError::Migrate { axis, error } => {
+                write!(f, "migrating {:?} failed: {}", axis, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TileError {}
+
+impl From<HaloError> for TileError {
+    fn from(error: HaloError) -> Self {
+        TileError::Halo(error)
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Runs tile `tid` until it finishes or any tile of the process fails. An
+/// error or panic of the tile is recorded in `failure` and stops all the
+/// others, wherever they are waiting.
+pub async fn guard<F: Future<Output = Result<(), TileError>>>(
+    tid: usize,
+    failure: Arc<Failure>,
+    tile: F,
+) {
+    let run = AssertUnwindSafe(tile).catch_unwind();
+    tokio::select! {
+        biased;
+        _ = failure.occurred() => {}
+        result = run => match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => failure.report(format!("tile {} failed: {}", tid, e)),
+            Err(panic) => {
+                failure.report(format!("tile {} failed: {}", tid, panic_message(&*panic)))
+            }
+        }
+    }
+}
+
+/// A tile's link to the process that writes the output frames, probe
+/// traces and checkpoints and balances the load between tiles.
+pub enum Coordinator {
+    /// Tasks of this process share the frame sink, the balancer, the
+    /// checkpoint writer and the probe recorder.
+    Local {
+        sink: Arc<FrameSink>,
+        balancer: Arc<LoadBalancer>,
+        checkpoints: Arc<CheckpointWriter>,
+        probes: Arc<ProbeRecorder>,
+    },
+    /// Over a socket to the launcher process, which owns all four.
+    Remote { reader: ReadHalf, writer: WriteHalf },
+}
+
+// message tags on the socket between a tile process and the launcher
+const FRAME: u64 = 0;
+const LOAD: u64 = 1;
+const CHECKPOINT: u64 = 2;
+const PROBES: u64 = 3;
+
+/// Probe samples a tile collects before sending them on.
+const PROBE_BATCH: usize = 4096;
+
+impl Coordinator {
+    pub fn remote(stream: Stream) -> Self {
+        let (reader, writer) = stream.split();
+        Coordinator::Remote { reader, writer }
+    }
+
+    async fn submit(
+        &mut self,
+        frame_id: usize,
+        buffers: &Mutex<ArrBuffer<'_>>,
+        cb: &ControlBlock,
+    ) -> io::Result<()> {
+        let block = {
+            let u = buffers.lock().unwrap();
+            let fields = Field::selected(&cb.fields);
+            // this tile's output cells, possibly none
+            let sampling = cb.sampling();
+            let rows = sampling.owned_rows(u.start_row, u.m);
+            let cols = sampling.owned_cols(u.start_col, u.n);
+            let (stride, cells) = match sampling.average {
+                true => (sampling.stride, (sampling.stride * sampling.stride) as f64),
+                false => (1, 1.0),
+            };
+            let mut data = Vec::with_capacity(fields.len() * rows.len() * cols.len());
+            for field in &fields {
+                for i in rows.clone() {
+                    // interior cells start at (1, 1)
+                    let r = sampling.row_of(i) - u.start_row + 1;
+                    for j in cols.clone() {
+                        let c = sampling.col_of(j) - u.start_col + 1;
+                        let mut sum = 0.0;
+                        for dr in 0..stride {
+                            for dc in 0..stride {
+                                sum += field.value(&u, r + dr, c + dc, cb.dx, cb.dt);
+                            }
+                        }
+                        data.push(sum / cells);
+                    }
+                }
+            }
+            let region = Region {
+                frame_id,
+                start_row: rows.start,
+                start_col: cols.start,
+                m: rows.len(),
+                n: cols.len(),
+            };
+            TileBlock { region, data }
+        };
+        match self {
+            Coordinator::Local { sink, .. } => {
+                sink.submit(block).await;
+                Ok(())
+            }
+            Coordinator::Remote { writer: w, .. } => {
+                let r = block.region;
+                w.write_u64_le(FRAME).await?;
+                for v in [frame_id, r.start_row, r.start_col, r.m, r.n] {
+                    w.write_u64_le(v as u64).await?;
+                }
+                w.write_all(&f64s_to_bytes(&block.data)).await?;
+                w.flush().await
+            }
+        }
+    }
+
+    /// Hands the tile's state before iteration `next_iter` to the checkpoint
+    /// writer.
+    async fn checkpoint(
+        &mut self,
+        next_iter: usize,
+        next_frame: usize,
+        state: &TileState,
+    ) -> io::Result<()> {
+        match self {
+            Coordinator::Local { checkpoints, .. } => {
+                checkpoints.add_tile(next_iter, next_frame, state)
+            }
+            Coordinator::Remote { writer, .. } => {
+                let mut block = vec![];
+                state.write_to(&mut block)?;
+                for v in [
+                    CHECKPOINT,
+                    next_iter as u64,
+                    next_frame as u64,
+                    block.len() as u64,
+                ] {
+                    writer.write_u64_le(v).await?;
+                }
+                writer.write_all(&block).await?;
+                writer.flush().await
+            }
+        }
+    }
+
+    /// Sends the probe samples collected so far, leaving `samples` empty.
+    async fn record_probes(&mut self, samples: &mut Vec<ProbeSample>) -> io::Result<()> {
+        match self {
+            Coordinator::Local { probes, .. } => probes.record(samples)?,
+            Coordinator::Remote { writer, .. } => {
+                writer.write_u64_le(PROBES).await?;
+                writer.write_u64_le(samples.len() as u64).await?;
+                for sample in samples.iter() {
+                    writer.write_u64_le(sample.probe as u64).await?;
+                    writer.write_u64_le(sample.iter as u64).await?;
+                    writer.write_f64_le(sample.value).await?;
+                }
+                writer.flush().await?;
+            }
+        }
+        samples.clear();
+        Ok(())
+    }
+
+    /// Reports the compute time of tile `tid` since the last check and
+    /// returns the decomposition all tiles continue with.
+    async fn report_load(
+        &mut self,
+        iter: usize,
+        tid: usize,
+        busy: Duration,
+        current: &Decomposition,
+    ) -> io::Result<Decomposition> {
+        match self {
+            Coordinator::Local { balancer, .. } => Ok(balancer.report(iter, tid, busy).await),
+            Coordinator::Remote { reader, writer } => {
+                for v in [LOAD, iter as u64, tid as u64, busy.as_nanos() as u64] {
+                    writer.write_u64_le(v).await?;
+                }
+                writer.flush().await?;
+                let mut next = current.clone();
+                for start in next.row_starts.iter_mut().chain(next.col_starts.iter_mut()) {
+                    *start = reader.read_u64_le().await? as usize;
+                }
+                Ok(next)
+            }
+        }
+    }
+}
+
+/// Launcher side of `Coordinator::Remote`: accepts one connection per tile
+/// process, feeds the received tile frames into `sink`, their states into
+/// `checkpoints` and their probe samples into `probes`, and answers load
+/// reports through `balancer`.
+pub async fn serve_gather(
+    listener: Listener,
+    sink: Arc<FrameSink>,
+    balancer: Arc<LoadBalancer>,
+    checkpoints: Arc<CheckpointWriter>,
+    probes: Arc<ProbeRecorder>,
+    num_tiles: usize,
+) -> io::Result<()> {
+    let mut handlers = vec![];
+    for _ in 0..num_tiles {
+        let (reader, writer) = listener.accept().await?.split();
+        handlers.push(tokio::spawn(serve_tile(
+            reader,
+            writer,
+            Arc::clone(&sink),
+            Arc::clone(&balancer),
+            Arc::clone(&checkpoints),
+            Arc::clone(&probes),
+        )));
+    }
+    for h in handlers {
+        h.await.expect("gather reader panicked")?;
+    }
+    Ok(())
+}
+
+async fn serve_tile(
+    mut reader: ReadHalf,
+    mut writer: WriteHalf,
+    sink: Arc<FrameSink>,
+    balancer: Arc<LoadBalancer>,
+    checkpoints: Arc<CheckpointWriter>,
+    probes: Arc<ProbeRecorder>,
+) -> io::Result<()> {
+    loop {
+        let tag = match reader.read_u64_le().await {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match tag {
+            FRAME => read_tile_frame(&mut reader, &sink).await?,
+            LOAD => {
+                let mut fields = [0u64; 3];
+                for v in fields.iter_mut() {
+                    *v = reader.read_u64_le().await?;
+                }
+                let [iter, tid, busy] = fields;
+                let decomp = balancer
+                    .report(iter as usize, tid as usize, Duration::from_nanos(busy))
+                    .await;
+                for &start in decomp.row_starts.iter().chain(decomp.col_starts.iter()) {
+                    writer.write_u64_le(start as u64).await?;
+                }
+                writer.flush().await?;
+            }
+            CHECKPOINT => {
+                let next_iter = reader.read_u64_le().await? as usize;
+                let next_frame = reader.read_u64_le().await? as usize;
+                let mut block = vec![0u8; reader.read_u64_le().await? as usize];
+                reader.read_exact(&mut block).await?;
+                let state = TileState::read_from(&mut block.as_slice())?;
+                checkpoints.add_tile(next_iter, next_frame, &state)?;
+            }
+            PROBES => {
+                let count = reader.read_u64_le().await? as usize;
+                let mut samples = Vec::with_capacity(count);
+                for _ in 0..count {
+                    samples.push(ProbeSample {
+                        probe: reader.read_u64_le().await? as usize,
+                        iter: reader.read_u64_le().await? as usize,
+                        value: reader.read_f64_le().await?,
+                    });
+                }
+                probes.record(&samples)?;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message tag {} from a tile", tag),
+                ))
+            }
+        }
+    }
+}
+
+async fn read_tile_frame<R: AsyncRead + Unpin>(reader: &mut R, sink: &FrameSink) -> io::Result<()> {
+    let mut header = [0usize; 5];
+    for v in header.iter_mut() {
+        *v = reader.read_u64_le().await? as usize;
+    }
+    let [frame_id, start_row, start_col, m, n] = header;
+    // one m x n plane per field
+    let mut bytes = vec![0u8; sink.fields() * m * n * 8];
+    reader.read_exact(&mut bytes).await?;
+    let region = Region {
+        frame_id,
+        start_row,
+        start_col,
+        m,
+        n,
+    };
+    sink.submit(TileBlock {
+        region,
+        data: bytes_to_f64s(&bytes),
+    })
+    .await;
+    Ok(())
+}
+
+/// Number of rounds needed to move the cells that change tile along `axis`
+/// between `old` and `new` in messages of at most `chunk_len` values, or 0
+/// if no boundary on that axis moves. Every tile gets the same answer.
+fn migration_chunks(
+    old: &Decomposition,
+    new: &Decomposition,
+    axis: Axis,
+    chunk_len: usize,
+) -> usize {
+    let (old_starts, new_starts, across) = match axis {
+        Axis::Rows => (&old.row_starts, &new.row_starts, &old.col_starts),
+        Axis::Cols => (&old.col_starts, &new.col_starts, &new.row_starts),
+    };
+    let shift = old_starts
+        .iter()
+        .zip(new_starts)
+        .map(|(&a, &b)| a.abs_diff(b))
+        .max()
+        .unwrap_or(0);
+    if shift == 0 {
+        return 0;
+    }
+    let line = across.windows(2).map(|w| w[1] - w[0]).max().unwrap_or(0) + 2;
+    (3 * shift * line).div_ceil(chunk_len).max(1)
+}
+
+/// Saves the tile's state before iteration `next_iter`, after the probe
+/// samples up to there, so a restart finds their rows complete.
+async fn save_checkpoint(
+    coordinator: &mut Coordinator,
+    tid: usize,
+    next_iter: usize,
+    next_frame: usize,
+    buffers: &Mutex<ArrBuffer<'_>>,
+    sources: &[(usize, Stimulus<'_>)],
+    samples: &mut Vec<ProbeSample>,
+) -> Result<(), TileError> {
+    send_probes(coordinator, samples).await?;
+    let state = {
+        let ticks = sources
+            .iter()
+            .map(|(index, s)| (*index, s.tick()))
+            .collect();
+        TileState::capture(tid, &buffers.lock().unwrap(), ticks)
+    };
+    coordinator
+        .checkpoint(next_iter, next_frame, &state)
+        .await
+        .map_err(|error| TileError::Checkpoint { next_iter, error })
+}
+
+async fn send_probes(
+    coordinator: &mut Coordinator,
+    samples: &mut Vec<ProbeSample>,
+) -> Result<(), TileError> {
+    if !samples.is_empty() {
+        coordinator
+            .record_probes(samples)
+            .await
+            .map_err(|error| TileError::Probes { error })?;
+    }
+    Ok(())
+}
+
+/// Runs tile `tid` of the decomposition described by `cb` to completion,
+/// exchanging halos through `transport` and sending frames, probe samples,
+/// load reports and checkpoints to `coordinator`. After a SIGINT or SIGTERM all tiles stop
+/// before the same iteration and save a checkpoint there. Fails when an
+/// exchange with a neighbour fails, its restart block cannot be read or
+/// anything cannot be handed over.
+pub async fn run_tile<T: HaloTransport>(
+    cb: ControlBlock,
+    tid: usize,
+    mut transport: T,
+    mut coordinator: Coordinator,
+) -> Result<(), TileError> {
+    let top_global_edge = tid < cb.px;
+    let bot_global_edge = tid >= cb.px * (cb.py - 1);
+    let left_global_edge = tid.is_multiple_of(cb.px);
+    let right_global_edge = (tid + 1).is_multiple_of(cb.px);
+    let arr_buffers: Arc<Mutex<ArrBuffer<'_>>> =
+        Arc::new(Mutex::new(ArrBuffer::new(&cb, tid as i32)));
+
+    // sources keep their index among the objects to match checkpoint ticks
+    let mut s_list: Vec<(usize, Stimulus)> = Vec::new();
+    if cb.config.get("objects").is_some() {
+        let objects = cb.config.get("objects").unwrap();
+        for (index, object) in objects.as_array().unwrap().iter().enumerate() {
+            let obj_type = object.get("type").and_then(|v| v.as_str()).unwrap_or("");
+            match obj_type {
+                "sine" => {
+                    let start_time =
+                        object.get("start").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+                    let duration =
+                        object.get("duration").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+                    let row = object.get("row").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+                    let col = object.get("col").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+                    let period = object.get("period").and_then(|v| v.as_i64()).unwrap_or(0) as i32;
+                    let amplitude = object
+                        .get("amplitude")
+                        .and_then(|v| v.as_f64())
+                        .unwrap_or(DEFAULT_AMPLITUDE);
+
+                    let buffers = Arc::clone(&arr_buffers);
+                    let s =
+                        Stimulus::new(buffers, start_time, duration, row, col, period, amplitude);
+                    s_list.push((index, s));
+                }
+
+                "rectobstacle" => {
+                    let row = object.get("row").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+                    let col = object.get("col").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+                    let width = object.get("width").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+                    let height =
+                        object.get("height").and_then(|v| v.as_i64()).unwrap_or(0) as usize;
+                    clear_alpha_region(Arc::clone(&arr_buffers), row, col, width, height);
+                }
+
+                // recorded below, wherever the cell is
+                "probe" => {}
+
+                _ => {
+                    eprintln!("Unknown object type: {:?}", obj_type);
+                }
+            }
+        }
+    }
+    arr_buffers.lock().unwrap().build_block_mask();
+    if let Some(path) = &cb.restart {
+        let state = read_resliced(path, &cb.decomp, tid).map_err(TileError::Restart)?;
+        arr_buffers
+            .lock()
+            .unwrap()
+            .restore(&state.prev, &state.cur, &state.alpha);
+        // sources that had already finished are dropped
+        s_list.retain_mut(|(index, s)| {
+            match state.ticks.iter().find(|(source, _)| source == index) {
+                Some(&(_, tick)) => {
+                    s.set_tick(tick);
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    let mut halo = HaloState::new();
+    if cb.halo_timeout != 0 {
+        halo = halo.watched(Duration::from_secs(cb.halo_timeout), tid, cb.px, cb.py);
+    }
+    let mut decomp = cb.decomp.clone();
+    let mut busy = Duration::ZERO;
+    let mut round = 0;
+    let mut frame_id = cb.first_frame;
+    let mut iter = cb.first_iter;
+    let mut samples = vec![];
+    while iter < cb.niters {
+        if halo.stop_at.is_none() && interrupt::requested().is_some() {
+            // no tile is more than px + py - 2 hops away, and the proposal
+            // travels one hop per exchange round, so all tiles learn of it
+            // before they reach it
+            halo.propose_stop((iter + cb.px + cb.py - 1) as u64);
+        }
+        if halo.stop_at.is_some_and(|stop_at| iter as u64 >= stop_at) {
+            break;
+        }
+        if !s_list.is_empty() {
+            s_list.retain_mut(|(_, it)| it.trigger_if_available(iter as i32));
+        }
+        if cb.px * cb.py != 1 {
+            exchange_ghost_cells(Arc::clone(&arr_buffers), &mut halo, &mut transport, round)
+                .await?;
+            round += 1;
+        }
+
+        let started = Instant::now();
+        compute_u(Arc::clone(&arr_buffers));
+        compute_edge_u(
+            Arc::clone(&arr_buffers),
+            top_global_edge,
+            bot_global_edge,
+            left_global_edge,
+            right_global_edge,
+        );
+        busy += started.elapsed();
+
+        if !cb.probes.is_empty() {
+            // the tile owning a probe's cell records it, which may change
+            // with every rebalancing
+            let u = arr_buffers.lock().unwrap();
+            for (probe, p) in cb.probes.iter().enumerate() {
+                if u.check_bounds(p.row, p.col) {
+                    let (r, c) = u.map_to_local(p.row as i32, p.col as i32);
+                    let value = Field::U.value(&u, r, c, cb.dx, cb.dt);
+                    samples.push(ProbeSample { probe, iter, value });
+                }
+            }
+        }
+        if samples.len() >= PROBE_BATCH {
+            send_probes(&mut coordinator, &mut samples).await?;
+        }
+        if cb.is_output_iter(iter) {
+            coordinator
+                .submit(frame_id, &arr_buffers, &cb)
+                .await
+                .map_err(|error| TileError::Frame {
+                    frame: frame_id,
+                    error,
+                })?;
+            frame_id += 1;
+        }
+        {
+            let mut buffers = arr_buffers.lock().unwrap();
+            buffers.adv_buffers();
+        }
+
+        if cb.px * cb.py != 1 && cb.is_rebalance_iter(iter) {
+            let next = coordinator
+                .report_load(iter, tid, busy, &decomp)
+                .await
+                .map_err(|error| TileError::Load { error })?;
+            busy = Duration::ZERO;
+            // rows first, then columns along the already moved rows
+            for axis in [Axis::Rows, Axis::Cols] {
+                let target = match axis {
+                    Axis::Rows => Decomposition {
+                        row_starts: next.row_starts.clone(),
+                        ..decomp.clone()
+                    },
+                    Axis::Cols => next.clone(),
+                };
+                let chunks = migration_chunks(&decomp, &target, axis, transport.max_message_len());
+                if chunks > 0 {
+                    let (start, len) = match axis {
+                        Axis::Rows => target.tile_rows(tid),
+                        Axis::Cols => target.tile_cols(tid),
+                    };
+                    migrate(
+                        Arc::clone(&arr_buffers),
+                        &mut halo,
+                        &mut transport,
+                        round,
+                        axis,
+                        start..start + len,
+                        chunks,
+                    )
+                    .await
+                    .map_err(|error| TileError::Migrate { axis, error })?;
+                    round += chunks as u64;
+                }
+                decomp = target;
+            }
+        }
+
+        if cb.is_checkpoint_iter(iter) {
+            save_checkpoint(
+                &mut coordinator,
+                tid,
+                iter + 1,
+                frame_id,
+                &arr_buffers,
+                &s_list,
+                &mut samples,
+            )
+            .await?;
+        }
+        iter += 1;
+    }
+    // stopped early: keep what has been computed
+    if iter < cb.niters && !(iter > cb.first_iter && cb.is_checkpoint_iter(iter - 1)) {
+        save_checkpoint(
+            &mut coordinator,
+            tid,
+            iter,
+            frame_id,
+            &arr_buffers,
+            &s_list,
+            &mut samples,
+        )
+        .await?;
+    }
+    send_probes(&mut coordinator, &mut samples).await?;
+    transport.finish().await;
+    Ok(())
+}
@@ -3,6 +3,9 @@ use std::sync::{Arc, Mutex};
 
 use crate::buffer::ArrBuffer;
 
+/// Amplitude of a `sine` source whose config object gives none.
+pub const DEFAULT_AMPLITUDE: f64 = 10.0;
+
 #[derive(Debug)]
 pub struct Stimulus<'a> {
     buffers: Arc<Mutex<ArrBuffer<'a>>>,
@@ -11,7 +14,7 @@ pub struct Stimulus<'a> {
     tick: f64,
     row: usize,
     col: usize,
-    amplitude: i32,
+    amplitude: f64,
     period: i32,
 }
 
@@ -23,7 +26,9 @@ impl<'a> Stimulus<'a> {
         row: usize,
         col: usize,
         period: i32,
+        amplitude: f64,
     ) -> Self {
+        buffers.lock().unwrap().add_source(row, col);
         Stimulus {
             buffers,
             start_time,
@@ -31,10 +36,17 @@ impl<'a> Stimulus<'a> {
             tick: 0.0,
             row,
             col,
-            amplitude: 10,
+            amplitude,
             period,
         }
     }
+    /// Steps taken since the source started, which sets its phase.
+    pub fn tick(&self) -> f64 {
+        self.tick
+    }
+    pub fn set_tick(&mut self, tick: f64) {
+        self.tick = tick;
+    }
     pub fn trigger_if_available(&mut self, iter: i32) -> bool {
         if iter > self.start_time + self.duration {
             return false;
@@ -47,8 +59,7 @@ impl<'a> Stimulus<'a> {
         }
         let mut buffers = self.buffers.lock().unwrap();
         if buffers.check_bounds(self.row, self.col) {
-            let v: f64 =
-                self.amplitude as f64 * (2.0 * PI * self.tick / (self.period as f64)).sin();
+            let v: f64 = self.amplitude * (2.0 * PI * self.tick / (self.period as f64)).sin();
             let pair = buffers.map_to_local(self.row as i32, self.col as i32);
 
             if let Some(cv) = buffers.cur(pair.0, pair.1) {
@@ -57,6 +68,7 @@ impl<'a> Stimulus<'a> {
             if let Some(pv) = buffers.prev(pair.0, pair.1) {
                 *pv = v;
             }
+            buffers.mark_active(pair.0, pair.1);
         }
 
         self.tick += 1.0;
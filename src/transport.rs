@@ -0,0 +1,623 @@
+use crate::halo::{compute_neighbors, Direction, HaloError, HaloMessage};
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+
+/// Moves halo messages between a tile and its neighbours.
+///
+/// `send` routes a message to the neighbour it travels towards (`msg.dir`);
+/// `recv` yields the next message from any neighbour, in arrival order.
+pub trait HaloTransport: Send {
+    fn has_neighbour(&self, dir: Direction) -> bool;
+
+    fn send(&mut self, msg: HaloMessage) -> impl Future<Output = Result<(), HaloError>> + Send;
+
+    fn recv(&mut self) -> impl Future<Output = Result<HaloMessage, HaloError>> + Send;
+
+    /// Longest `data` a single message may carry.
+    fn max_message_len(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Waits until every message handed to `send` has left this tile.
+    fn finish(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Tiles running as tasks of one process, linked by tokio channels.
+pub struct ChannelTransport {
+    receiver: Receiver<HaloMessage>,
+    senders: [Option<Sender<HaloMessage>>; 4],
+}
+
+impl ChannelTransport {
+    /// Builds one transport per tile of a `px` x `py` layout, indexed by tile id.
+    pub fn mesh(px: usize, py: usize) -> Vec<ChannelTransport> {
+        let num_tiles = px * py;
+        let mut senders = vec![];
+        let mut receivers = vec![];
+        for _ in 0..num_tiles {
+            // up to 4 sides, each at most one step ahead of us
+            let (tx, rx) = mpsc::channel(8);
+            senders.push(tx);
+            receivers.push(rx);
+        }
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(tid, receiver)| {
+                let mut links: [Option<Sender<HaloMessage>>; 4] = Default::default();
+                for (dir, t_id) in neighbours(tid, px, py) {
+                    if t_id >= 0 {
+                        links[dir.index()] = Some(senders[t_id as usize].clone());
+                    }
+                }
+                ChannelTransport {
+                    receiver,
+                    senders: links,
+                }
+            })
+            .collect()
+    }
+}
+
+impl HaloTransport for ChannelTransport {
+    fn has_neighbour(&self, dir: Direction) -> bool {
+        self.senders[dir.index()].is_some()
+    }
+
+    async fn send(&mut self, msg: HaloMessage) -> Result<(), HaloError> {
+        let dir = msg.dir;
+        match &self.senders[dir.index()] {
+            Some(tx) => tx
+                .send(msg)
+                .await
+                .map_err(|_| HaloError::Disconnected { dir }),
+            None => Err(HaloError::NoNeighbour { dir, step: msg.step }),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<HaloMessage, HaloError> {
+        self.receiver.recv().await.ok_or(HaloError::Closed)
+    }
+}
+
+fn neighbours(tid: usize, px: usize, py: usize) -> [(Direction, i32); 4] {
+    let (top, bot, left, right) = compute_neighbors(tid as i32, px as i32, py as i32);
+    [
+        (Direction::Up, top),
+        (Direction::Down, bot),
+        (Direction::Left, left),
+        (Direction::Right, right),
+    ]
+}
+
+/// Where the processes of a multi-process run listen. Tile `t` listens on
+/// `base_port + 1 + t` (or `tile<t>.sock`); the launcher that gathers the
+/// output frames listens on `base_port` (or `gather.sock`).
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp { host: String, base_port: u16 },
+    Unix { dir: PathBuf },
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+impl Stream {
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        match self {
+            Stream::Tcp(s) => {
+                let (r, w) = s.into_split();
+                (Box::new(r), Box::new(w))
+            }
+            Stream::Unix(s) => {
+                let (r, w) = s.into_split();
+                (Box::new(r), Box::new(w))
+            }
+        }
+    }
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => {
+                let (s, _) = l.accept().await?;
+                s.set_nodelay(true)?;
+                Ok(Stream::Tcp(s))
+            }
+            Listener::Unix(l) => {
+                let (s, _) = l.accept().await?;
+                Ok(Stream::Unix(s))
+            }
+        }
+    }
+}
+
+impl Endpoint {
+    fn slot(&self, slot: usize) -> (String, PathBuf) {
+        match self {
+            Endpoint::Tcp { host, base_port } => {
+                (format!("{}:{}", host, *base_port as usize + slot), PathBuf::new())
+            }
+            Endpoint::Unix { dir } => {
+                let name = if slot == 0 {
+                    "gather.sock".to_string()
+                } else {
+                    format!("tile{}.sock", slot - 1)
+                };
+                (String::new(), dir.join(name))
+            }
+        }
+    }
+
+    pub async fn listen_gather(&self) -> io::Result<Listener> {
+        self.listen(0).await
+    }
+
+    pub async fn connect_gather(&self) -> io::Result<Stream> {
+        self.connect(0).await
+    }
+
+    async fn listen(&self, slot: usize) -> io::Result<Listener> {
+        let (addr, path) = self.slot(slot);
+        match self {
+            Endpoint::Tcp { .. } => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            Endpoint::Unix { dir } => {
+                std::fs::create_dir_all(dir)?;
+                let _ = std::fs::remove_file(&path);
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    /// Connects to `slot`, retrying for a while since the peer process may
+    /// not have bound its address yet.
+    async fn connect(&self, slot: usize) -> io::Result<Stream> {
+        let (addr, path) = self.slot(slot);
+        let mut attempts = 0;
+        loop {
+            let res = match self {
+                Endpoint::Tcp { .. } => TcpStream::connect(&addr).await.and_then(|s| {
+                    s.set_nodelay(true)?;
+                    Ok(Stream::Tcp(s))
+                }),
+                Endpoint::Unix { .. } => UnixStream::connect(&path).await.map(Stream::Unix),
+            };
+            match res {
+                Ok(s) => return Ok(s),
+                Err(e) if attempts >= 200 => return Err(e),
+                Err(_) => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Tiles running in separate processes, one socket per neighbour pair.
+///
+/// Each link has a writer task fed by a queue, so `send` never blocks on a
+/// full socket buffer, and a reader task that forwards decoded messages into
+/// a single inbound queue.
+pub struct SocketTransport {
+    inbound: Receiver<Result<HaloMessage, HaloError>>,
+    outbound: [Option<Sender<HaloMessage>>; 4],
+    writers: Vec<JoinHandle<()>>,
+}
+
+impl SocketTransport {
+    /// Binds this tile's address, connects to the right and lower neighbours
+    /// and accepts the left and upper ones.
+    pub async fn connect(endpoint: &Endpoint, tid: usize, px: usize, py: usize) -> io::Result<Self> {
+        let listener = endpoint.listen(tid + 1).await?;
+        let links = neighbours(tid, px, py);
+        let mut streams: [Option<Stream>; 4] = Default::default();
+        for &(dir, t_id) in &links {
+            if t_id > tid as i32 {
+                let mut s = endpoint.connect(t_id as usize + 1).await?;
+                write_handshake(&mut s, tid).await?;
+                streams[dir.index()] = Some(s);
+            }
+        }
+        let incoming = links
+            .iter()
+            .filter(|&&(_, t_id)| t_id >= 0 && t_id < tid as i32)
+            .count();
+        for _ in 0..incoming {
+            let mut s = listener.accept().await?;
+            let peer = read_handshake(&mut s).await?;
+            let Some(&(dir, _)) = links.iter().find(|&&(_, t_id)| t_id == peer as i32) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("tile {} is not a neighbour of tile {}", peer, tid),
+                ));
+            };
+            streams[dir.index()] = Some(s);
+        }
+
+        let (in_tx, inbound) = mpsc::channel(8);
+        let mut outbound: [Option<Sender<HaloMessage>>; 4] = Default::default();
+        let mut writers = vec![];
+        for dir in Direction::ALL {
+            let Some(stream) = streams[dir.index()].take() else {
+                continue;
+            };
+            let (mut reader, mut writer) = stream.split();
+            let (out_tx, mut out_rx) = mpsc::channel::<HaloMessage>(2);
+            writers.push(tokio::spawn(async move {
+                while let Some(msg) = out_rx.recv().await {
+                    if write_message(&mut writer, &msg).await.is_err() {
+                        break;
+                    }
+                }
+                let _ = writer.shutdown().await;
+            }));
+            let in_tx = in_tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    match read_message(&mut reader).await {
+                        Ok(Some(msg)) => {
+                            if in_tx.send(Ok(msg)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = in_tx.send(Err(HaloError::Io { dir, error: e })).await;
+                            break;
+                        }
+                    }
+                }
+            });
+            outbound[dir.index()] = Some(out_tx);
+        }
+        Ok(SocketTransport {
+            inbound,
+            outbound,
+            writers,
+        })
+    }
+}
+
+impl HaloTransport for SocketTransport {
+    fn has_neighbour(&self, dir: Direction) -> bool {
+        self.outbound[dir.index()].is_some()
+    }
+
+    async fn send(&mut self, msg: HaloMessage) -> Result<(), HaloError> {
+        let dir = msg.dir;
+        match &self.outbound[dir.index()] {
+            Some(tx) => tx
+                .send(msg)
+                .await
+                .map_err(|_| HaloError::Disconnected { dir }),
+            None => Err(HaloError::NoNeighbour { dir, step: msg.step }),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<HaloMessage, HaloError> {
+        self.inbound
+            .recv()
+            .await
+            .unwrap_or(Err(HaloError::Closed))
+    }
+
+    /// Closes the outgoing queues and waits for the writer tasks, so the
+    /// last ghost cells are on the wire before the process exits.
+    async fn finish(&mut self) {
+        self.outbound = Default::default();
+        for writer in self.writers.drain(..) {
+            let _ = writer.await;
+        }
+    }
+}
+
+/// Tiles running in separate processes on one host, exchanging halos through
+/// a memory-mapped file instead of sockets. Ghost cells are copied straight
+/// into the mapping, with no serialisation and no socket I/O; the tile
+/// itself still runs as a future on the process's tokio runtime, which
+/// carries its link to the launcher and the halo watchdog.
+///
+/// Every tile owns one link per direction it sends in. A link holds two
+/// slots, so the sender can publish step `s + 1` while the receiver still
+/// reads step `s`, plus a header of sequence counters:
+///
+/// ```text
+/// [published slot 0, published slot 1, consumed, len slot 0, len slot 1,
+///  stop slot 0, stop slot 1, unused]
+/// ```
+///
+/// A slot is published by storing `step + 1` after its data is written, and
+/// the receiver stores `step + 1` into `consumed` once it has copied the data
+/// out. The sender of step `s` waits until step `s - 2`, which used the same
+/// slot, has been consumed. Waiting spins, yields and finally sleeps the
+/// calling thread, so a tile using this transport is driven by `block_on`
+/// on a blocking thread rather than on a runtime worker.
+pub struct ShmTransport {
+    _map: MmapMut,
+    /// start of the mapping, taken once so every access shares its provenance
+    base: *mut u8,
+    words: usize,
+    link_len: usize,
+    tid: usize,
+    /// neighbour tile on each side, if any
+    sides: [Option<usize>; 4],
+    /// next step expected on each incoming direction
+    next_step: [u64; 4],
+    /// buffers of sent messages, reused for received ones
+    pool: Vec<Vec<f64>>,
+}
+
+const LINK_HEADER: usize = 8;
+const PUBLISHED: usize = 0;
+const CONSUMED: usize = 2;
+const LEN: usize = 3;
+const STOP: usize = 5;
+/// `HaloMessage::stop_at` of `None`, on the wire and in shared memory
+const NO_STOP: u64 = u64::MAX;
+
+impl ShmTransport {
+    /// Creates the shared file for `num_tiles` tiles whose edges hold at most
+    /// `max_len` values. Called once, before any tile opens it.
+    pub fn create_file(path: &Path, num_tiles: usize, max_len: usize) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((num_tiles * 4 * Self::link_words(max_len) * 8) as u64)
+    }
+
+    /// Maps the file made by `create_file` for tile `tid` of a `px` x `py`
+    /// layout.
+    pub fn open(path: &Path, tid: usize, px: usize, py: usize, max_len: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let link_len = Self::link_words(max_len);
+        if map.len() < px * py * 4 * link_len * 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is too small for {}x{} tiles", path.display(), px, py),
+            ));
+        }
+        let mut sides = [None; 4];
+        for (dir, t_id) in neighbours(tid, px, py) {
+            if t_id >= 0 {
+                sides[dir.index()] = Some(t_id as usize);
+            }
+        }
+        Ok(ShmTransport {
+            base: map.as_mut_ptr(),
+            words: map.len() / 8,
+            _map: map,
+            link_len,
+            tid,
+            sides,
+            next_step: [0; 4],
+            pool: Vec::with_capacity(4),
+        })
+    }
+
+    fn link_words(max_len: usize) -> usize {
+        LINK_HEADER + 2 * max_len
+    }
+
+    /// Start of the link that `tile` sends in direction `dir`, in f64 words.
+    fn link(&self, tile: usize, dir: Direction) -> usize {
+        (tile * 4 + dir.index()) * self.link_len
+    }
+
+    fn counter(&self, word: usize) -> &AtomicU64 {
+        assert!(word < self.words);
+        // the map is page aligned, so every word is aligned for AtomicU64
+        unsafe { &*(self.base.add(word * 8) as *const AtomicU64) }
+    }
+
+    fn slot_ptr(&self, link: usize, slot: usize) -> *mut f64 {
+        let max_len = (self.link_len - LINK_HEADER) / 2;
+        let word = link + LINK_HEADER + slot * max_len;
+        assert!(word + max_len <= self.words);
+        unsafe { (self.base as *mut f64).add(word) }
+    }
+
+    async fn write_link(&mut self, msg: HaloMessage) -> Result<(), HaloError> {
+        let dir = msg.dir;
+        if self.sides[dir.index()].is_none() {
+            return Err(HaloError::NoNeighbour { dir, step: msg.step });
+        }
+        let max_len = (self.link_len - LINK_HEADER) / 2;
+        if msg.data.len() > max_len {
+            return Err(HaloError::Length {
+                dir,
+                expected: max_len,
+                got: msg.data.len(),
+            });
+        }
+        let link = self.link(self.tid, dir);
+        let slot = (msg.step % 2) as usize;
+        // the slot last held step - 2; it must have been read
+        spin_until(|| self.counter(link + CONSUMED).load(Ordering::Acquire) + 1 >= msg.step).await;
+        unsafe {
+            std::ptr::copy_nonoverlapping(msg.data.as_ptr(), self.slot_ptr(link, slot), msg.data.len());
+        }
+        self.counter(link + LEN + slot)
+            .store(msg.data.len() as u64, Ordering::Relaxed);
+        self.counter(link + STOP + slot)
+            .store(msg.stop_at.unwrap_or(NO_STOP), Ordering::Relaxed);
+        self.counter(link + PUBLISHED + slot)
+            .store(msg.step + 1, Ordering::Release);
+        self.pool.push(msg.data);
+        Ok(())
+    }
+
+    /// Takes the next message of any neighbour whose next step is published.
+    fn try_read(&mut self) -> Option<HaloMessage> {
+        for dir in Direction::ALL {
+            let Some(from) = self.sides[dir.opposite().index()] else {
+                continue;
+            };
+            let step = self.next_step[dir.index()];
+            let link = self.link(from, dir);
+            let slot = (step % 2) as usize;
+            if self.counter(link + PUBLISHED + slot).load(Ordering::Acquire) != step + 1 {
+                continue;
+            }
+            let len = self.counter(link + LEN + slot).load(Ordering::Relaxed) as usize;
+            let stop_at = self.counter(link + STOP + slot).load(Ordering::Relaxed);
+            let mut data = self.pool.pop().unwrap_or_default();
+            data.clear();
+            data.reserve(len);
+            unsafe {
+                std::ptr::copy_nonoverlapping(self.slot_ptr(link, slot), data.as_mut_ptr(), len);
+                data.set_len(len);
+            }
+            self.counter(link + CONSUMED).store(step + 1, Ordering::Release);
+            self.next_step[dir.index()] = step + 1;
+            return Some(HaloMessage {
+                dir,
+                step,
+                stop_at: Some(stop_at).filter(|&s| s != NO_STOP),
+                data,
+            });
+        }
+        None
+    }
+}
+
+// `base` points into `_map`, which moves with the transport
+unsafe impl Send for ShmTransport {}
+// through `&self` the mapping is only read via atomics
+unsafe impl Sync for ShmTransport {}
+
+impl HaloTransport for ShmTransport {
+    fn has_neighbour(&self, dir: Direction) -> bool {
+        self.sides[dir.index()].is_some()
+    }
+
+    fn max_message_len(&self) -> usize {
+        (self.link_len - LINK_HEADER) / 2
+    }
+
+    async fn send(&mut self, msg: HaloMessage) -> Result<(), HaloError> {
+        self.write_link(msg).await
+    }
+
+    async fn recv(&mut self) -> Result<HaloMessage, HaloError> {
+        let mut msg = None;
+        spin_until(|| {
+            msg = self.try_read();
+            msg.is_some()
+        })
+        .await;
+        Ok(msg.unwrap())
+    }
+}
+
+/// Busy-waits for `done`, backing off to yielding and then short sleeps so
+/// that oversubscribed hosts still make progress. The sleeps block the
+/// thread, which is why shared memory tiles run on blocking threads; once
+/// sleeping, it also yields to the runtime, so timeouts and cancellation of
+/// the caller apply.
+async fn spin_until<F: FnMut() -> bool>(mut done: F) {
+    let mut spins = 0u32;
+    while !done() {
+        if spins < 64 {
+            std::hint::spin_loop();
+        } else if spins < 1024 {
+            std::thread::yield_now();
+        } else {
+            std::thread::sleep(Duration::from_micros(50));
+            tokio::task::yield_now().await;
+        }
+        spins = spins.saturating_add(1);
+    }
+}
+
+async fn write_handshake(s: &mut Stream, tid: usize) -> io::Result<()> {
+    match s {
+        Stream::Tcp(s) => s.write_u64_le(tid as u64).await,
+        Stream::Unix(s) => s.write_u64_le(tid as u64).await,
+    }
+}
+
+async fn read_handshake(s: &mut Stream) -> io::Result<usize> {
+    let tid = match s {
+        Stream::Tcp(s) => s.read_u64_le().await?,
+        Stream::Unix(s) => s.read_u64_le().await?,
+    };
+    Ok(tid as usize)
+}
+
+// wire format: dir u8, step u64, stop_at u64, len u64, then len f64 values,
+// all little endian
+
+async fn write_message<W: AsyncWrite + Unpin>(w: &mut W, msg: &HaloMessage) -> io::Result<()> {
+    let mut header = [0u8; 25];
+    header[0] = msg.dir.index() as u8;
+    header[1..9].copy_from_slice(&msg.step.to_le_bytes());
+    header[9..17].copy_from_slice(&msg.stop_at.unwrap_or(NO_STOP).to_le_bytes());
+    header[17..25].copy_from_slice(&(msg.data.len() as u64).to_le_bytes());
+    w.write_all(&header).await?;
+    w.write_all(&f64s_to_bytes(&msg.data)).await?;
+    w.flush().await
+}
+
+async fn read_message<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Option<HaloMessage>> {
+    let mut header = [0u8; 25];
+    match r.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let dir = *Direction::ALL.get(header[0] as usize).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "bad halo direction")
+    })?;
+    let step = u64::from_le_bytes(header[1..9].try_into().unwrap());
+    let stop_at = u64::from_le_bytes(header[9..17].try_into().unwrap());
+    let len = u64::from_le_bytes(header[17..25].try_into().unwrap()) as usize;
+    let mut bytes = vec![0u8; len * 8];
+    r.read_exact(&mut bytes).await?;
+    Ok(Some(HaloMessage {
+        dir,
+        step,
+        stop_at: Some(stop_at).filter(|&s| s != NO_STOP),
+        data: bytes_to_f64s(&bytes),
+    }))
+}
+
+pub fn f64s_to_bytes(values: &[f64]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub fn bytes_to_f64s(bytes: &[u8]) -> Vec<f64> {
+    bytes
+        .chunks_exact(8)
+        .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        .collect()
+}
@@ -0,0 +1,298 @@
+use crate::controlblock::ControlBlock;
+use crate::fields::Field;
+use crate::output::{FrameWriter, Region, WriteError};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Writes every frame as a VTK XML ImageData file, `<stem>/<stem>_NNNNNN.vti`
+/// next to the output, with one point-data array per field, and keeps the
+/// output itself a ParaView collection (.pvd) of the frames and their times.
+///
+/// Arrays are stored raw in the appended section, at offsets fixed by the
+/// grid size, so blocks go straight to their place in the frame's file.
+pub struct VtkWriter {
+    path: PathBuf,
+    /// directory of the .vti files
+    frames_dir: PathBuf,
+    stem: String,
+    rows: usize,
+    cols: usize,
+    fields: Vec<Field>,
+    /// `Float64` or `Float32`
+    precision: String,
+    /// position of the first point, x then y, and distance between points,
+    /// in metres
+    origin: [f64; 2],
+    spacing: f64,
+    dt: f64,
+    /// iteration each frame of this run shows, from `first_frame` on
+    frame_iters: Vec<usize>,
+    first_frame: usize,
+    /// frames still incomplete
+    open: BTreeMap<usize, OpenFrame>,
+    /// time and file name of every complete frame, in order
+    collection: Vec<(f64, String)>,
+    /// frames completed out of order, waiting for those before them
+    done: BTreeMap<usize, (f64, String)>,
+}
+
+struct OpenFrame {
+    file: File,
+    /// where the appended data starts
+    data_start: usize,
+    /// values written so far
+    filled: usize,
+}
+
+impl VtkWriter {
+    pub fn create(cb: &ControlBlock) -> io::Result<Self> {
+        Self::open(cb, vec![])
+    }
+
+    /// Continues the output of the run `cb` restarts: the collection keeps
+    /// its first `cb.first_frame` frames.
+    pub fn append(cb: &ControlBlock) -> io::Result<Self> {
+        let pvd = fs::read_to_string(&cb.output)?;
+        let mut collection = read_collection(&pvd).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is not a collection written by wave_2d",
+                    cb.output.display()
+                ),
+            )
+        })?;
+        if collection.len() < cb.first_frame {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} lists {} frames, the restart needs {}",
+                    cb.output.display(),
+                    collection.len(),
+                    cb.first_frame
+                ),
+            ));
+        }
+        collection.truncate(cb.first_frame);
+        Self::open(cb, collection)
+    }
+
+    fn open(cb: &ControlBlock, collection: Vec<(f64, String)>) -> io::Result<Self> {
+        let stem = cb
+            .output
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "output".to_string());
+        let frames_dir = cb.output.with_file_name(&stem);
+        fs::create_dir_all(&frames_dir)?;
+        let sampling = cb.sampling();
+        let writer = VtkWriter {
+            path: cb.output.clone(),
+            frames_dir,
+            stem,
+            rows: sampling.rows(),
+            cols: sampling.cols(),
+            fields: Field::selected(&cb.fields),
+            precision: match cb.precision.as_str() {
+                "f32" => "Float32".to_string(),
+                _ => "Float64".to_string(),
+            },
+            origin: [sampling.col_position(0), sampling.row_position(0)].map(|p| p * cb.dx),
+            spacing: sampling.stride as f64 * cb.dx,
+            dt: cb.dt,
+            frame_iters: cb.output_iters(),
+            first_frame: cb.first_frame,
+            open: BTreeMap::new(),
+            collection,
+            done: BTreeMap::new(),
+        };
+        writer.write_collection()?;
+        Ok(writer)
+    }
+
+    fn item_size(&self) -> usize {
+        if self.precision == "Float32" {
+            4
+        } else {
+            8
+        }
+    }
+
+    /// Bytes of one array in the appended section, its length prefix included.
+    fn array_len(&self) -> usize {
+        8 + self.rows * self.cols * self.item_size()
+    }
+
+    fn file_name(&self, frame_id: usize) -> String {
+        format!("{}_{:06}.vti", self.stem, frame_id)
+    }
+
+    /// XML up to the start of the appended data of a frame shown at `time`.
+    fn head(&self, time: f64, iter: usize) -> String {
+        let extent = format!("0 {} 0 {} 0 0", self.cols - 1, self.rows - 1);
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\"?>\n");
+        xml.push_str(
+            "<VTKFile type=\"ImageData\" version=\"1.0\" byte_order=\"LittleEndian\" header_type=\"UInt64\">\n",
+        );
+        xml.push_str(&format!(
+            "  <ImageData WholeExtent=\"{}\" Origin=\"{:?} {:?} 0\" Spacing=\"{:?} {:?} {:?}\">\n",
+            extent, self.origin[0], self.origin[1], self.spacing, self.spacing, self.spacing
+        ));
+        xml.push_str("    <FieldData>\n");
+        xml.push_str(&format!(
+            "      <DataArray type=\"Float64\" Name=\"TimeValue\" NumberOfTuples=\"1\" format=\"ascii\">{:?}</DataArray>\n",
+            time
+        ));
+        xml.push_str(&format!(
+            "      <DataArray type=\"UInt64\" Name=\"iteration\" NumberOfTuples=\"1\" format=\"ascii\">{}</DataArray>\n",
+            iter
+        ));
+        xml.push_str("    </FieldData>\n");
+        xml.push_str(&format!("    <Piece Extent=\"{}\">\n", extent));
+        xml.push_str("      <PointData Scalars=\"data\">\n");
+        for (k, field) in self.fields.iter().enumerate() {
+            xml.push_str(&format!(
+                "        <DataArray type=\"{}\" Name=\"{}\" format=\"appended\" offset=\"{}\"/>\n",
+                self.precision,
+                field.var_name(),
+                k * self.array_len()
+            ));
+        }
+        xml.push_str("      </PointData>\n");
+        xml.push_str("    </Piece>\n");
+        xml.push_str("  </ImageData>\n");
+        xml.push_str("  <AppendedData encoding=\"raw\">\n   _");
+        xml
+    }
+
+    /// Creates the file of frame `frame_id`, complete but for the values.
+    fn start_frame(&self, frame_id: usize) -> io::Result<OpenFrame> {
+        let iter = self.frame_iters[frame_id - self.first_frame];
+        let time = iter as f64 * self.dt;
+        let head = self.head(time, iter);
+        let mut file = File::create(self.frames_dir.join(self.file_name(frame_id)))?;
+        file.write_all(head.as_bytes())?;
+        let values = (self.rows * self.cols * self.item_size()) as u64;
+        for k in 0..self.fields.len() {
+            let at = head.len() + k * self.array_len();
+            file.seek(SeekFrom::Start(at as u64))?;
+            file.write_all(&values.to_le_bytes())?;
+        }
+        let end = head.len() + self.fields.len() * self.array_len();
+        file.seek(SeekFrom::Start(end as u64))?;
+        file.write_all(b"\n  </AppendedData>\n</VTKFile>\n")?;
+        Ok(OpenFrame {
+            file,
+            data_start: head.len(),
+            filled: 0,
+        })
+    }
+
+    fn put_block(&mut self, region: &Region, values: &[f64]) -> io::Result<()> {
+        let frame_id = region.frame_id;
+        if !self.open.contains_key(&frame_id) {
+            let frame = self.start_frame(frame_id)?;
+            self.open.insert(frame_id, frame);
+        }
+        let (array_len, item_size, cols) = (self.array_len(), self.item_size(), self.cols);
+        let single = self.precision == "Float32";
+        let frame = self.open.get_mut(&frame_id).unwrap();
+        let size = region.m * region.n;
+        for (k, plane) in values.chunks_exact(size).enumerate() {
+            for i in 0..region.m {
+                let cell = (region.start_row + i) * cols + region.start_col;
+                let at = frame.data_start + k * array_len + 8 + cell * item_size;
+                let mut bytes = Vec::with_capacity(region.n * item_size);
+                for &v in &plane[i * region.n..(i + 1) * region.n] {
+                    if single {
+                        bytes.extend_from_slice(&(v as f32).to_le_bytes());
+                    } else {
+                        bytes.extend_from_slice(&v.to_le_bytes());
+                    }
+                }
+                frame.file.seek(SeekFrom::Start(at as u64))?;
+                frame.file.write_all(&bytes)?;
+            }
+        }
+        frame.filled += size;
+        if frame.filled < self.rows * self.cols {
+            return Ok(());
+        }
+
+        self.open.remove(&frame_id);
+        let iter = self.frame_iters[frame_id - self.first_frame];
+        let entry = (iter as f64 * self.dt, self.file_name(frame_id));
+        self.done.insert(frame_id, entry);
+        let before = self.collection.len();
+        while let Some(entry) = self.done.remove(&self.collection.len()) {
+            self.collection.push(entry);
+        }
+        if self.collection.len() > before {
+            self.write_collection()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the .pvd with the complete frames, replacing it at once so
+    /// ParaView never reads half of it.
+    fn write_collection(&self) -> io::Result<()> {
+        let dir = self
+            .frames_dir
+            .file_name()
+            .map(|d| d.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\"?>\n");
+        xml.push_str("<VTKFile type=\"Collection\" version=\"1.0\" byte_order=\"LittleEndian\">\n");
+        xml.push_str("  <Collection>\n");
+        for (time, name) in &self.collection {
+            xml.push_str(&format!(
+                "    <DataSet timestep=\"{:?}\" part=\"0\" file=\"{}/{}\"/>\n",
+                time, dir, name
+            ));
+        }
+        xml.push_str("  </Collection>\n");
+        xml.push_str("</VTKFile>\n");
+        let mut part = self.path.as_os_str().to_owned();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        fs::write(&part, xml)?;
+        fs::rename(&part, &self.path)
+    }
+}
+
+/// Time and file name of the data sets a .pvd written by `VtkWriter` lists.
+fn read_collection(pvd: &str) -> Option<Vec<(f64, String)>> {
+    let attribute = |line: &str, name: &str| -> Option<String> {
+        let start = line.find(&format!(" {}=\"", name))? + name.len() + 3;
+        let len = line[start..].find('"')?;
+        Some(line[start..start + len].to_string())
+    };
+    pvd.lines()
+        .filter(|line| line.trim_start().starts_with("<DataSet "))
+        .map(|line| {
+            let time = attribute(line, "timestep")?.parse().ok()?;
+            let file = attribute(line, "file")?;
+            let name = Path::new(&file).file_name()?.to_string_lossy().into_owned();
+            Some((time, name))
+        })
+        .collect()
+}
+
+impl FrameWriter for VtkWriter {
+    fn write_block(&mut self, region: &Region, values: &[f64]) -> Result<(), WriteError> {
+        Ok(self.put_block(region, values)?)
+    }
+
+    fn close(self: Box<Self>) -> Result<(), WriteError> {
+        if let Some(&frame_id) = self.open.keys().next() {
+            return Err(
+                format!("{}: frame {} is incomplete", self.path.display(), frame_id).into(),
+            );
+        }
+        Ok(())
+    }
+}
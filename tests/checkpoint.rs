@@ -0,0 +1,337 @@
+//! A run restarted from a checkpoint must end in exactly the state of the
+//! same run left uninterrupted. Both save a checkpoint after their last
+//! iteration, and the two are compared as global fields.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use wave_2d::buffer::ArrBuffer;
+use wave_2d::checkpoint::{read_header, read_resliced, CheckpointWriter, TileState};
+use wave_2d::controlblock::ControlBlock;
+use wave_2d::decomposition::Decomposition;
+
+const N: &str = "300";
+const ITERS: usize = 60;
+/// Iterations of the runs that get interrupted.
+const LONG: &str = "3000";
+
+fn config() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/t500.config")
+}
+
+/// Fresh directory for one test's output.nc and checkpoints.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wave_2d-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(dir: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_wave_2d"))
+        .current_dir(dir)
+        .args(["-n", N, "-f", "5"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed:\n{}{}",
+        args,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Compares the global fields of two checkpoints, whatever their layouts.
+fn assert_same_state(expected: &Path, actual: &Path) {
+    let (a, _) = read_header(expected).unwrap();
+    let (b, _) = read_header(actual).unwrap();
+    assert_eq!((a.next_iter, a.next_frame), (b.next_iter, b.next_frame));
+    let whole = Decomposition::even(a.config.m, a.config.n, 1, 1).unwrap();
+    let a = read_resliced(expected, &whole, 0).unwrap();
+    let b = read_resliced(actual, &whole, 0).unwrap();
+    assert_eq!(a.ticks, b.ticks, "sources differ after restart");
+    for (name, x, y) in [
+        ("prev", &a.prev, &b.prev),
+        ("cur", &a.cur, &b.cur),
+        ("alpha", &a.alpha, &b.alpha),
+    ] {
+        assert!(
+            x.iter().zip(y).all(|(p, q)| p.to_bits() == q.to_bits()),
+            "{} plane differs after restart",
+            name
+        );
+    }
+}
+
+fn restart_matches(name: &str, layout: &[&str], restart_args: &[&str]) {
+    let dir = scratch(name);
+    let config = config();
+    let config = config.to_str().unwrap();
+    let (all, half) = (ITERS.to_string(), (ITERS / 2).to_string());
+    let every = ["--checkpoint-freq", half.as_str()];
+
+    run(
+        &dir,
+        &[
+            &["-c", config, "-i", &all, "--checkpoint", "full.ck"],
+            layout,
+            &every[..],
+        ]
+        .concat(),
+    );
+    run(
+        &dir,
+        &[
+            &["-c", config, "-i", &half, "--checkpoint", "half.ck"],
+            layout,
+            &every[..],
+        ]
+        .concat(),
+    );
+    run(
+        &dir,
+        &[
+            &[
+                "--restart",
+                "half.ck",
+                "-i",
+                &all,
+                "--checkpoint",
+                "resumed.ck",
+            ],
+            restart_args,
+            &every[..],
+        ]
+        .concat(),
+    );
+    assert_same_state(&dir.join("full.ck"), &dir.join("resumed.ck"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn restarted_serial_run_matches_uninterrupted_run() {
+    restart_matches("serial", &["-x", "1", "-y", "1"], &[]);
+}
+
+#[test]
+fn restarted_decomposed_run_matches_uninterrupted_run() {
+    restart_matches("decomposed", &["-x", "3", "-y", "2"], &[]);
+}
+
+/// Names of the part files left in `dir`.
+fn part_files(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".part"))
+        .collect()
+}
+
+#[test]
+fn tiles_may_hand_in_the_next_checkpoint_early() {
+    let dir = scratch("run-ahead");
+    let path = dir.join("ahead.ck");
+    let args = ["wave_2d", "-n", "64", "-x", "3", "-y", "1", "--checkpoint"];
+    let mut args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    args.push(path.to_str().unwrap().to_string());
+    let cb = ControlBlock::new(args);
+    let writer = CheckpointWriter::new(&cb);
+    let states: Vec<_> = (0..3)
+        .map(|tid| TileState::capture(tid, &ArrBuffer::new(&cb, tid as i32), Vec::new()))
+        .collect();
+
+    // the tiles nearer the start run one iteration ahead of the next one
+    let next_iter = |path: &Path| read_header(path).ok().map(|(h, _)| h.next_iter);
+    for (tid, iter) in [(0, 10), (0, 11), (1, 10), (1, 11), (0, 12)] {
+        writer.add_tile(iter, 0, &states[tid]).unwrap();
+    }
+    assert_eq!(next_iter(&path), None);
+    writer.add_tile(10, 0, &states[2]).unwrap();
+    assert_eq!(next_iter(&path), Some(10));
+    writer.add_tile(11, 0, &states[2]).unwrap();
+    assert_eq!(next_iter(&path), Some(11));
+    assert_eq!(part_files(&dir), ["ahead.ck.12.part"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkpoint_every_iteration_on_a_row_of_tiles() {
+    let dir = scratch("every-iteration");
+    let config = config();
+    let config = config.to_str().unwrap();
+    let all = ITERS.to_string();
+    let layout = ["-c", config, "-i", &all, "-x", "3", "-y", "1"];
+    run(
+        &dir,
+        &[
+            &layout[..],
+            &["--checkpoint", "full.ck", "--checkpoint-freq", &all],
+        ]
+        .concat(),
+    );
+    run(
+        &dir,
+        &[
+            &layout[..],
+            &["--checkpoint", "every.ck", "--checkpoint-freq", "1"],
+        ]
+        .concat(),
+    );
+    assert_same_state(&dir.join("full.ck"), &dir.join("every.ck"));
+    assert!(part_files(&dir).is_empty(), "{:?}", part_files(&dir));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn restart_may_switch_transport() {
+    restart_matches(
+        "transport",
+        &["-x", "2", "-y", "2"],
+        &["--transport", "unix"],
+    );
+}
+
+#[test]
+fn restart_may_change_layout() {
+    for (name, layout) in [
+        ("to-wider", ["-x", "3", "-y", "2"]),
+        ("to-serial", ["-x", "1", "-y", "1"]),
+    ] {
+        restart_matches(name, &["-x", "2", "-y", "2"], &layout);
+    }
+}
+
+#[test]
+fn restart_may_change_rebalanced_layout() {
+    restart_matches(
+        "rebalanced",
+        &[
+            "-x",
+            "3",
+            "-y",
+            "2",
+            "--rebalance",
+            "10",
+            "--rebalance-threshold",
+            "1.0",
+        ],
+        &["-x", "2", "-y", "3"],
+    );
+}
+
+/// Interrupts a long run with SIGINT once it has saved its first periodic
+/// checkpoint, `every` iterations in. It must stop with a checkpoint and
+/// exit status 130, and continue from there to the end.
+fn interrupted_run_resumes(name: &str, tiles: [&str; 2], transport: &str, every: &str) {
+    let dir = scratch(name);
+    let config = config();
+    let config = config.to_str().unwrap();
+    let layout = ["-c", config, "-i", LONG, "-x", tiles[0], "-y", tiles[1]];
+    let last = ["--checkpoint-freq", LONG];
+    run(
+        &dir,
+        &[&layout[..], &last, &["--checkpoint", "full.ck"]].concat(),
+    );
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wave_2d"))
+        .current_dir(&dir)
+        .args(["-n", N, "-f", "5"])
+        .args(layout)
+        .args(["--transport", transport, "--checkpoint", "stopped.ck"])
+        .args(["--checkpoint-freq", every])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let first = format!("Checkpoint before iteration {} written", every);
+    let mut started = String::new();
+    while !started.contains(&first) {
+        assert!(
+            stdout.read_line(&mut started).unwrap() > 0,
+            "no periodic checkpoint: {}",
+            started
+        );
+    }
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    let mut errors = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut errors).unwrap();
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(130), "{}{}{}", started, rest, errors);
+    let (stopped, _) = read_header(&dir.join("stopped.ck")).unwrap();
+    assert!(
+        stopped.next_iter > every.parse().unwrap(),
+        "stopped before iteration {}",
+        stopped.next_iter
+    );
+
+    run(
+        &dir,
+        &[
+            &["--restart", "stopped.ck", "--checkpoint", "resumed.ck"],
+            &last[..],
+        ]
+        .concat(),
+    );
+    assert_same_state(&dir.join("full.ck"), &dir.join("resumed.ck"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn interrupted_run_resumes_exactly() {
+    interrupted_run_resumes("interrupted", ["2", "2"], "channel", "100");
+}
+
+#[test]
+fn interrupted_tile_processes_resume_exactly() {
+    interrupted_run_resumes("interrupted-unix", ["2", "2"], "unix", "100");
+}
+
+#[test]
+fn interrupt_next_to_periodic_checkpoints_resumes_exactly() {
+    interrupted_run_resumes("interrupted-periodic", ["3", "1"], "channel", "2");
+}
+
+#[test]
+fn restart_continues_probe_traces() {
+    let dir = scratch("probes");
+    let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/probes.config");
+    let config = config.to_str().unwrap();
+    let (all, half) = (ITERS.to_string(), (ITERS / 2).to_string());
+    let layout = ["-x", "3", "-y", "2"];
+    run(
+        &dir,
+        &[&["-c", config, "-i", &all, "-o", "full.npy"], &layout[..]].concat(),
+    );
+    run(
+        &dir,
+        &[
+            &["-c", config, "-i", &half, "-o", "half.npy"],
+            &layout[..],
+            &["--checkpoint", "half.ck", "--checkpoint-freq", &half],
+        ]
+        .concat(),
+    );
+    // rows past the checkpoint, as an interrupted run may leave, are dropped
+    let mut traces = fs::read_to_string(dir.join("half_probes.csv")).unwrap();
+    traces.push_str("99,99,0.5,");
+    fs::write(dir.join("half_probes.csv"), traces).unwrap();
+    run(
+        &dir,
+        &["--restart", "half.ck", "-i", &all, "-x", "2", "-y", "2"],
+    );
+
+    let full = fs::read_to_string(dir.join("full_probes.csv")).unwrap();
+    let resumed = fs::read_to_string(dir.join("half_probes.csv")).unwrap();
+    assert_eq!(full.lines().count(), ITERS + 1);
+    assert!(full.starts_with("iteration,time,behind,centre,probe_140_99\n"));
+    assert_eq!(full, resumed, "probe traces differ after restart");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,155 @@
+//! How the settings of a run are put together from the command line and the
+//! config file, and what each feature rejects.
+
+use clap::error::ErrorKind;
+use std::fs;
+use wave_2d::controlblock::ControlBlock;
+
+fn try_settings(args: &[&str]) -> Result<ControlBlock, clap::Error> {
+    let args = ["wave_2d", "-n", "100"]
+        .iter()
+        .chain(args)
+        .map(|s| s.to_string())
+        .collect();
+    ControlBlock::try_new(args)
+}
+
+/// Checks that `args` are refused as `kind`, with a message saying `why`.
+fn refused(args: &[&str], kind: ErrorKind, why: &str) {
+    match try_settings(args) {
+        Ok(_) => panic!("{:?} was accepted", args),
+        Err(e) => {
+            assert_eq!(e.kind(), kind, "{:?}: {}", args, e);
+            assert!(e.to_string().contains(why), "{:?}: {}", args, e);
+        }
+    }
+}
+
+#[test]
+fn command_line_overrides_the_config_file() {
+    let path = std::env::temp_dir().join(format!("wave_2d-layers-{}.config", std::process::id()));
+    fs::write(
+        &path,
+        r#"{ "-n" : 60, "-f" : 7, "--dx" : 2.5, "--crop" : [1, 2, 30, 40] }"#,
+    )
+    .unwrap();
+    let args = ["wave_2d", "-c", path.to_str().unwrap(), "-f", "3"];
+    let cb = ControlBlock::try_new(args.iter().map(|s| s.to_string()).collect()).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!((cb.m, cb.n), (60, 60));
+    assert_eq!(cb.output_freq, 3);
+    assert_eq!(cb.dx, 2.5);
+    assert_eq!(cb.crop, Some([1, 2, 30, 40]));
+}
+
+#[test]
+fn output_window_without_interval_takes_only_snapshots() {
+    let cb = try_settings(&["--snapshots", "40,3,40"]).unwrap();
+    assert_eq!((cb.output_freq, cb.snapshots), (0, vec![3, 40]));
+    let cb = try_settings(&["--snapshots", "40", "-f", "10"]).unwrap();
+    assert_eq!(cb.output_freq, 10);
+}
+
+#[test]
+fn output_format_must_hold_what_is_asked() {
+    refused(
+        &["-o", "-", "--format", "npy"],
+        ErrorKind::ArgumentConflict,
+        "only y4m streams to stdout",
+    );
+    refused(
+        &["--format", "png", "--fields", "dudt"],
+        ErrorKind::ArgumentConflict,
+        "png frames hold `data` alone",
+    );
+    let cb = try_settings(&["-o", "run.npz"]).unwrap();
+    assert_eq!(cb.probe_output.to_str(), Some("run_probes.csv"));
+}
+
+#[test]
+fn image_settings_are_checked() {
+    refused(
+        &["--fps", "0"],
+        ErrorKind::InvalidValue,
+        "--fps must be at least 1",
+    );
+    refused(
+        &["--color-limits", "2,1"],
+        ErrorKind::InvalidValue,
+        "color limits need LOW < HIGH",
+    );
+    refused(
+        &["--source-color", "#12345"],
+        ErrorKind::InvalidValue,
+        "expected an RRGGBB hex color",
+    );
+    let cb = try_settings(&["--color-limits=-1,1", "--obstacle-color", "#ff8000"]).unwrap();
+    assert_eq!(cb.color_limits, Some((-1.0, 1.0)));
+    assert_eq!(cb.obstacle_color, [255, 128, 0]);
+}
+
+#[test]
+fn netcdf_storage_is_checked() {
+    refused(
+        &["--format", "npz", "--precision", "int16"],
+        ErrorKind::ArgumentConflict,
+        "--precision int16 needs netCDF's scale_factor",
+    );
+    refused(
+        &["--precision", "int16", "--pack-range", "0"],
+        ErrorKind::InvalidValue,
+        "--pack-range must be positive",
+    );
+    refused(
+        &["--chunk", "1,50"],
+        ErrorKind::InvalidValue,
+        "a chunk needs three non-zero sizes",
+    );
+    // chunks are laid over the sampled frame, not the grid
+    let cb = try_settings(&["--stride", "2", "--chunk", "frame"]).unwrap();
+    assert_eq!(cb.chunk, Some(vec![1, 50, 50]));
+}
+
+#[test]
+fn sampling_is_checked() {
+    refused(
+        &["--stride", "0"],
+        ErrorKind::InvalidValue,
+        "--stride must be at least 1",
+    );
+    refused(
+        &["--crop", "90,0,20,10"],
+        ErrorKind::InvalidValue,
+        "must be a non-empty part of the 100x100 grid",
+    );
+    refused(
+        &["--crop", "0,0,3,3", "--stride", "4", "--average"],
+        ErrorKind::InvalidValue,
+        "holds no whole 4x4 block",
+    );
+}
+
+#[test]
+fn averaged_blocks_must_not_be_cut_by_tiles() {
+    refused(
+        &["--stride", "40", "--average", "-x", "4", "-y", "1"],
+        ErrorKind::ValueValidation,
+        "cannot be cut along the 40x40 blocks",
+    );
+}
+
+#[test]
+fn probes_must_be_on_the_grid() {
+    let path = std::env::temp_dir().join(format!("wave_2d-probe-{}.config", std::process::id()));
+    fs::write(
+        &path,
+        r#"{ "objects" : [ { "type" : "probe", "row" : 5, "col" : 100 } ] }"#,
+    )
+    .unwrap();
+    refused(
+        &["-c", path.to_str().unwrap()],
+        ErrorKind::InvalidValue,
+        "off the 100x100 grid",
+    );
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,89 @@
+//! Which px x py layout `-x auto` / `-y auto` pick for a number of cores.
+
+use wave_2d::decomposition::{check_layout, choose_layout, DecompositionError, MIN_TILE};
+
+#[test]
+fn auto_layout_minimises_halo_length() {
+    // 100 rows of 400 columns: cutting columns costs 100 cells a cut,
+    // cutting rows 400
+    assert_eq!(choose_layout(100, 400, 4, None, None), Ok((4, 1)));
+    assert_eq!(choose_layout(400, 100, 4, None, None), Ok((1, 4)));
+    assert_eq!(choose_layout(300, 300, 4, None, None), Ok((2, 2)));
+    assert_eq!(choose_layout(100, 100, 1, None, None), Ok((1, 1)));
+}
+
+#[test]
+fn auto_layout_ties_go_to_more_columns() {
+    // 3 x 2 and 2 x 3 both cut 300 cells
+    assert_eq!(choose_layout(100, 100, 6, None, None), Ok((3, 2)));
+    assert_eq!(choose_layout(50, 50, 2, None, None), Ok((2, 1)));
+}
+
+#[test]
+fn layouts_leave_min_tile_cells() {
+    assert_eq!(check_layout(10, 10, 5, 5), Ok(()));
+    assert_eq!(
+        check_layout(10, 10, 1, 10 / MIN_TILE + 1),
+        Err(DecompositionError::TooManyTiles {
+            axis: "y",
+            tiles: 6,
+            cells: 10
+        })
+    );
+    assert_eq!(
+        check_layout(10, 3, 2, 1),
+        Err(DecompositionError::TooManyTiles {
+            axis: "x",
+            tiles: 2,
+            cells: 3
+        })
+    );
+    assert_eq!(
+        choose_layout(3, 7, 4, Some(4), None),
+        Err(DecompositionError::TooManyTiles {
+            axis: "x",
+            tiles: 4,
+            cells: 7
+        })
+    );
+    // 3 x 1 is out, as 5 columns cannot hold 3 tiles
+    assert_eq!(choose_layout(40, 5, 3, None, None), Ok((1, 3)));
+}
+
+#[test]
+fn no_layout_fits() {
+    assert_eq!(
+        choose_layout(3, 7, 4, None, None),
+        Err(DecompositionError::NoLayout {
+            cores: 4,
+            m: 3,
+            n: 7
+        })
+    );
+}
+
+#[test]
+fn fixed_axis_takes_a_divisor_of_the_cores() {
+    assert_eq!(choose_layout(100, 100, 6, Some(2), None), Ok((2, 3)));
+    assert_eq!(choose_layout(100, 100, 6, None, Some(3)), Ok((2, 3)));
+    assert_eq!(
+        choose_layout(100, 100, 6, Some(4), None),
+        Err(DecompositionError::Indivisible {
+            axis: "x",
+            tiles: 4,
+            cores: 6
+        })
+    );
+    assert_eq!(
+        choose_layout(100, 100, 2, None, Some(8)),
+        Err(DecompositionError::Indivisible {
+            axis: "y",
+            tiles: 8,
+            cores: 2
+        })
+    );
+    assert_eq!(
+        choose_layout(100, 100, 6, Some(0), None),
+        Err(DecompositionError::Empty { px: 0, py: 6 })
+    );
+}
@@ -0,0 +1,145 @@
+//! A failing tile must bring the whole run down promptly with its own
+//! message, and a stuck halo exchange must be reported rather than hang.
+
+mod common;
+
+use common::TempDir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn config() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/t500.config")
+}
+
+fn wave_2d(dir: &TempDir) -> Command {
+    let mut command = dir.command();
+    command.env("RUST_BACKTRACE", "0").args(["-n", "300"]);
+    command
+}
+
+/// Restarting from a checkpoint whose last block is cut short passes the
+/// header check but makes the tiles fail reading their state; the run must
+/// exit with an error instead of waiting on the others.
+fn truncated_restart_fails(name: &str, transport: &str) {
+    let dir = TempDir::new(name);
+    let config = config();
+    let run = wave_2d(&dir)
+        .args(["-c", config.to_str().unwrap(), "-i", "20", "-f", "5"])
+        .args(["-x", "2", "-y", "2", "--checkpoint-freq", "20"])
+        .output()
+        .unwrap();
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    let saved = fs::read(dir.join("checkpoint.bin")).unwrap();
+    fs::write(dir.join("trunc.ck"), &saved[..saved.len() - 8]).unwrap();
+
+    let start = Instant::now();
+    let failed: Output = wave_2d(&dir)
+        .args([
+            "--restart",
+            "trunc.ck",
+            "-i",
+            "40",
+            "--transport",
+            transport,
+        ])
+        .output()
+        .unwrap();
+    let errors = String::from_utf8_lossy(&failed.stderr);
+    assert!(!failed.status.success(), "{}", errors);
+    assert!(
+        errors.contains("failed: reading the restart checkpoint failed"),
+        "{}",
+        errors
+    );
+    assert!(!errors.contains("panicked"), "{}", errors);
+    assert!(start.elapsed() < Duration::from_secs(30), "{}", errors);
+}
+
+#[test]
+fn failing_tile_stops_channel_run() {
+    truncated_restart_fails("truncated", "channel");
+}
+
+#[test]
+fn failing_tile_stops_tile_processes() {
+    truncated_restart_fails("truncated-shm", "shm");
+}
+
+/// A checkpoint that cannot be written fails the run with an error naming
+/// it, not with a panic.
+#[test]
+fn unwritable_checkpoint_is_an_error() {
+    let dir = TempDir::new("unwritable");
+    let config = config();
+    let failed = wave_2d(&dir)
+        .args(["-c", config.to_str().unwrap(), "-i", "20", "-f", "5"])
+        .args(["-x", "2", "-y", "1", "--checkpoint-freq", "10"])
+        .args(["--checkpoint", "missing/checkpoint.bin"])
+        .output()
+        .unwrap();
+    let errors = String::from_utf8_lossy(&failed.stderr);
+    assert!(!failed.status.success(), "{}", errors);
+    assert!(
+        errors.contains("failed: checkpoint before iteration 10 failed"),
+        "{}",
+        errors
+    );
+    assert!(!errors.contains("panicked"), "{}", errors);
+}
+
+/// Freezes one tile process; its neighbours must give up on the exchange
+/// after the halo timeout and name the direction they were waiting on.
+#[test]
+fn stalled_exchange_is_reported() {
+    let dir = TempDir::new("stalled");
+    let config = config();
+    let child = wave_2d(&dir)
+        .args(["-c", config.to_str().unwrap(), "-i", "1000000", "-f", "0"])
+        .args(["-x", "2", "-y", "2", "--transport", "unix"])
+        .args(["--halo-timeout", "1"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // tile processes are started from any of the runtime's threads
+    let tasks = PathBuf::from(format!("/proc/{}/task", child.id()));
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let tile = loop {
+        let pids: Vec<String> = fs::read_dir(&tasks)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|task| fs::read_to_string(task.path().join("children")).ok())
+            .flat_map(|pids| {
+                pids.split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        if pids.len() == 4 {
+            break pids[3].parse::<libc::pid_t>().unwrap();
+        }
+        assert!(Instant::now() < deadline, "tile processes did not start");
+        thread::sleep(Duration::from_millis(50));
+    };
+    thread::sleep(Duration::from_millis(300));
+    unsafe {
+        libc::kill(tile, libc::SIGSTOP);
+    }
+
+    let output = child.wait_with_output().unwrap();
+    unsafe {
+        libc::kill(tile, libc::SIGKILL);
+    }
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", errors);
+    assert!(errors.contains("stalled: no"), "{}", errors);
+    assert!(!errors.contains("panicked"), "{}", errors);
+}
@@ -0,0 +1,120 @@
+//! How a tile treats halo messages that arrive out of turn: the middle tile
+//! of a 3 x 1 layout exchanges with neighbours whose messages the tests
+//! send by hand.
+
+use std::sync::{Arc, Mutex};
+use wave_2d::buffer::ArrBuffer;
+use wave_2d::controlblock::ControlBlock;
+use wave_2d::halo::{exchange_ghost_cells, Direction, HaloError, HaloMessage, HaloState};
+use wave_2d::transport::{ChannelTransport, HaloTransport};
+
+fn control_block() -> ControlBlock {
+    let args = ["wave_2d", "-n", "30", "-x", "3", "-y", "1"];
+    ControlBlock::new(args.iter().map(|s| s.to_string()).collect())
+}
+
+/// The left and right neighbours of tile 1, and tile 1 itself.
+fn mesh() -> (ChannelTransport, ChannelTransport, ChannelTransport) {
+    let mut mesh = ChannelTransport::mesh(3, 1).into_iter();
+    let left = mesh.next().unwrap();
+    let middle = mesh.next().unwrap();
+    (left, mesh.next().unwrap(), middle)
+}
+
+/// Sends tile 1 a column of `grid_m` cells for `step`, as a neighbour would.
+async fn send(from: &mut ChannelTransport, dir: Direction, step: u64, grid_m: usize, value: f64) {
+    let data = vec![value; grid_m];
+    let msg = HaloMessage {
+        dir,
+        step,
+        stop_at: None,
+        data,
+    };
+    from.send(msg).await.unwrap();
+}
+
+#[tokio::test]
+async fn stale_message_is_rejected() {
+    let cb = control_block();
+    let buffers = Arc::new(Mutex::new(ArrBuffer::new(&cb, 1)));
+    let grid_m = buffers.lock().unwrap().grid_m;
+    let (mut left, mut right, mut middle) = mesh();
+    let mut state = HaloState::new();
+    send(&mut left, Direction::Right, 0, grid_m, 1.0).await;
+    send(&mut right, Direction::Left, 0, grid_m, 2.0).await;
+    exchange_ghost_cells(Arc::clone(&buffers), &mut state, &mut middle, 0)
+        .await
+        .unwrap();
+
+    send(&mut left, Direction::Right, 0, grid_m, 3.0).await;
+    send(&mut right, Direction::Left, 1, grid_m, 4.0).await;
+    let err = exchange_ghost_cells(Arc::clone(&buffers), &mut state, &mut middle, 1).await;
+    assert!(
+        matches!(
+            err,
+            Err(HaloError::WrongStep {
+                dir: Direction::Right,
+                expected: 1,
+                got: 0
+            })
+        ),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn duplicate_message_is_rejected() {
+    let cb = control_block();
+    let buffers = Arc::new(Mutex::new(ArrBuffer::new(&cb, 1)));
+    let grid_m = buffers.lock().unwrap().grid_m;
+    let (mut left, mut right, mut middle) = mesh();
+    let mut state = HaloState::new();
+    send(&mut left, Direction::Right, 0, grid_m, 1.0).await;
+    send(&mut left, Direction::Right, 0, grid_m, 1.0).await;
+    send(&mut right, Direction::Left, 0, grid_m, 2.0).await;
+    let err = exchange_ghost_cells(Arc::clone(&buffers), &mut state, &mut middle, 0).await;
+    assert!(
+        matches!(
+            err,
+            Err(HaloError::Duplicate {
+                dir: Direction::Right,
+                step: 0
+            })
+        ),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn next_step_message_waits_for_its_round() {
+    let cb = control_block();
+    let buffers = Arc::new(Mutex::new(ArrBuffer::new(&cb, 1)));
+    let (grid_m, grid_n) = {
+        let u = buffers.lock().unwrap();
+        (u.grid_m, u.grid_n)
+    };
+    let (mut left, mut right, mut middle) = mesh();
+    let mut state = HaloState::new();
+    // the left neighbour runs a round ahead of the right one
+    send(&mut left, Direction::Right, 0, grid_m, 1.0).await;
+    send(&mut left, Direction::Right, 1, grid_m, 3.0).await;
+    send(&mut right, Direction::Left, 0, grid_m, 2.0).await;
+    exchange_ghost_cells(Arc::clone(&buffers), &mut state, &mut middle, 0)
+        .await
+        .unwrap();
+    {
+        let u = buffers.lock().unwrap();
+        assert_eq!(u.extract_col(0), vec![1.0; grid_m]);
+        assert_eq!(u.extract_col(grid_n - 1), vec![2.0; grid_m]);
+    }
+
+    send(&mut right, Direction::Left, 1, grid_m, 4.0).await;
+    exchange_ghost_cells(Arc::clone(&buffers), &mut state, &mut middle, 1)
+        .await
+        .unwrap();
+    let u = buffers.lock().unwrap();
+    assert_eq!(u.extract_col(0), vec![3.0; grid_m]);
+    assert_eq!(u.extract_col(grid_n - 1), vec![4.0; grid_m]);
+}
@@ -0,0 +1,122 @@
+//! The kernel only visits the active box and skips masked blocks; neither
+//! shortcut may change a single bit of the result.
+
+use std::sync::{Arc, Mutex};
+use wave_2d::buffer::ArrBuffer;
+use wave_2d::controlblock::ControlBlock;
+use wave_2d::kernel::{compute_edge_u, compute_u};
+use wave_2d::obstacle::clear_alpha_region;
+use wave_2d::stimulus::Stimulus;
+
+const STEPS: usize = 120;
+/// Step from which the neighbours start sending non-zero halo cells.
+const HALO_FROM: usize = 15;
+
+/// Every interior cell, as the kernel would compute it without an active
+/// box or block mask.
+fn full_sweep(buffers: &Arc<Mutex<ArrBuffer>>) {
+    let mut u = buffers.lock().unwrap();
+    for r in 2..u.grid_m - 2 {
+        for c in 2..u.grid_n - 2 {
+            let nv = u.alp_v(r, c)
+                * (u.cur_v(r - 1, c) + u.cur_v(r + 1, c) + u.cur_v(r, c - 1) + u.cur_v(r, c + 1)
+                    - 4.0 * u.cur_v(r, c))
+                + 2.0 * u.cur_v(r, c)
+                - u.prev_v(r, c);
+            *u.nxt(r, c).unwrap() = nv;
+        }
+    }
+}
+
+/// The bottom and right ghost cells the neighbouring tiles would send: a
+/// wave reaching a stretch of the edge from step `HALO_FROM` on.
+fn halo(step: usize, len: usize, phase: f64) -> Vec<f64> {
+    (0..len)
+        .map(|i| {
+            if step < HALO_FROM || !(4..12).contains(&i) {
+                0.0
+            } else {
+                (0.37 * i as f64 + 0.21 * step as f64 + phase).sin()
+            }
+        })
+        .collect()
+}
+
+/// Checks that every non-zero value of the three planes lies in the active
+/// box.
+fn assert_inside_active_box(u: &ArrBuffer, step: usize) {
+    for (plane, data) in u.memory_pool.chunks(u.grid_m * u.grid_n).enumerate() {
+        for (i, v) in data.iter().enumerate() {
+            if v.to_bits() == 0 {
+                continue;
+            }
+            let (r, c) = (i / u.grid_n, i % u.grid_n);
+            let inside = u
+                .active
+                .is_some_and(|b| b.r0 <= r && r < b.r1 && b.c0 <= c && c < b.c1);
+            assert!(
+                inside,
+                "step {}: plane {} has {} at ({}, {}) outside {:?}",
+                step, plane, v, r, c, u.active
+            );
+        }
+    }
+}
+
+#[test]
+fn active_box_and_block_mask_match_full_sweep() {
+    let args = ["wave_2d", "-n", "100", "-x", "2", "-y", "2"];
+    let cb = ControlBlock::new(args.iter().map(|s| s.to_string()).collect());
+    let fast = Arc::new(Mutex::new(ArrBuffer::new(&cb, 0)));
+    let full = Arc::new(Mutex::new(ArrBuffer::new(&cb, 0)));
+    let mut stimuli = vec![];
+    for buffers in [&fast, &full] {
+        // a source on the edge of an obstacle: its block stays unmasked
+        // while the blocks of the obstacle around it are masked
+        clear_alpha_region(Arc::clone(buffers), 15, 15, 33, 33);
+        stimuli.push(Stimulus::new(Arc::clone(buffers), 0, 60, 15, 20, 12, 10.0));
+        // a wall the waves from the halo run into
+        clear_alpha_region(Arc::clone(buffers), 0, 30, 18, 50);
+        buffers.lock().unwrap().build_block_mask();
+    }
+    {
+        let u = fast.lock().unwrap();
+        assert!(u.is_masked_block(0, 2) && u.is_masked_block(2, 2));
+        assert!(!u.is_masked_block(1, 1), "the source's block is masked");
+    }
+
+    for step in 0..STEPS {
+        for stimulus in &mut stimuli {
+            stimulus.trigger_if_available(step as i32);
+        }
+        for buffers in [&fast, &full] {
+            let mut u = buffers.lock().unwrap();
+            let (grid_m, grid_n) = (u.grid_m, u.grid_n);
+            u.update_row(grid_m - 1, &halo(step, grid_n, 0.0));
+            u.update_col(grid_n - 1, &halo(step, grid_m, 1.3));
+        }
+        compute_u(Arc::clone(&fast));
+        full_sweep(&full);
+        for buffers in [&fast, &full] {
+            compute_edge_u(Arc::clone(buffers), true, false, true, false);
+        }
+
+        {
+            let (a, b) = (fast.lock().unwrap(), full.lock().unwrap());
+            assert_inside_active_box(&a, step);
+            for (name, x, y) in [
+                ("u", &a.memory_pool, &b.memory_pool),
+                ("alpha", &a.alpha, &b.alpha),
+            ] {
+                let differ = x
+                    .iter()
+                    .zip(y)
+                    .position(|(x, y)| x.to_bits() != y.to_bits());
+                assert_eq!(differ, None, "step {}: {} planes differ", step, name);
+            }
+        }
+        for buffers in [&fast, &full] {
+            buffers.lock().unwrap().adv_buffers();
+        }
+    }
+}
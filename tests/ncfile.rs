@@ -0,0 +1,194 @@
+//! What a netCDF reader finds in the files runs write.
+
+use netcdf::types::{IntType, NcVariableType};
+use netcdf::AttributeValue;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A single source of amplitude 3 on a 100 x 100 grid.
+const SCENE: &str = r#"{
+    "-n" : 100,
+    "objects" : [
+        { "type" : "sine", "row" : 40, "col" : 40, "start" : 0,
+          "duration" : 200, "period" : 20, "amplitude" : 3 }
+    ]
+}"#;
+
+/// A fresh directory holding the scene's config.
+fn scene_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wave_2d-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("scene.config"), SCENE).unwrap();
+    dir
+}
+
+/// Runs the scene in `dir` and returns what it printed to stderr.
+fn run(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_wave_2d"))
+        .current_dir(dir)
+        .args(["-c", "scene.config", "-i", "60", "-x", "2", "-y", "1"])
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(output.status.success(), "{:?}: {}", args, stderr);
+    stderr
+}
+
+/// Packed values of `data` and its scale factor.
+fn packed(path: &Path) -> (Vec<i16>, f64) {
+    let file = netcdf::open(path).unwrap();
+    let data = file.variable("data").unwrap();
+    assert_eq!(data.vartype(), NcVariableType::Int(IntType::I16));
+    assert_eq!(data.fill_value::<i16>().unwrap(), Some(i16::MIN));
+    assert_eq!(
+        data.attribute_value("add_offset").unwrap().unwrap(),
+        AttributeValue::Double(0.0)
+    );
+    assert_eq!(
+        data.attribute_value("valid_range").unwrap().unwrap(),
+        AttributeValue::Shorts(vec![-32767, 32767])
+    );
+    let scale = match data.attribute_value("scale_factor").unwrap().unwrap() {
+        AttributeValue::Double(scale) => scale,
+        other => panic!("scale_factor is {:?}", other),
+    };
+    (data.get_values::<i16, _>(..).unwrap(), scale)
+}
+
+#[test]
+fn int16_output_packs_the_source_amplitude() {
+    let dir = scene_dir("int16");
+    let stderr = run(
+        &dir,
+        &["--precision", "int16", "--deflate", "4", "--shuffle"],
+    );
+    assert!(!stderr.contains("clipped"), "{}", stderr);
+    run(
+        &dir,
+        &["--precision", "int16", "--deflate", "0", "-o", "plain.nc"],
+    );
+
+    // twice the amplitude of the one source
+    let (values, scale) = packed(&dir.join("output.nc"));
+    assert_eq!(scale, 6.0 / 32767.0);
+    let peak = values
+        .iter()
+        .map(|&v| (v as f64 * scale).abs())
+        .fold(0.0, f64::max);
+    assert!(2.5 < peak && peak <= 3.0 + scale, "peak {}", peak);
+    assert_eq!(packed(&dir.join("plain.nc")).0, values);
+
+    let file = netcdf::open(dir.join("output.nc")).unwrap();
+    assert!(file.variable("data").unwrap().chunking().unwrap().is_some());
+    let size = |name: &str| fs::metadata(dir.join(name)).unwrap().len();
+    assert!(
+        size("output.nc") * 4 < size("plain.nc"),
+        "deflated {} bytes, plain {}",
+        size("output.nc"),
+        size("plain.nc")
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn int16_output_clips_beyond_the_pack_range() {
+    let dir = scene_dir("clip");
+    let stderr = run(&dir, &["--precision", "int16", "--pack-range", "1"]);
+    assert!(
+        stderr.contains("beyond ±1 were clipped in output.nc"),
+        "{}",
+        stderr
+    );
+    let (values, scale) = packed(&dir.join("output.nc"));
+    assert_eq!(scale, 1.0 / 32767.0);
+    assert_eq!(values.iter().map(|v| v.abs()).max(), Some(i16::MAX));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The value of a global or variable attribute.
+fn attribute(value: Option<netcdf::Result<AttributeValue>>) -> AttributeValue {
+    value.unwrap().unwrap()
+}
+
+/// A text attribute.
+fn text(value: &str) -> AttributeValue {
+    AttributeValue::Str(value.to_string())
+}
+
+#[test]
+fn frames_carry_cf_coordinates() {
+    let dir = scene_dir("coordinates");
+    run(&dir, &["-f", "20", "--dx", "0.5", "--dt", "0.01"]);
+    let file = netcdf::open(dir.join("output.nc")).unwrap();
+    let global = |name: &str| file.attribute(name).map(|a| a.value());
+
+    assert_eq!(attribute(global("Conventions")), text("CF-1.8"));
+    assert_eq!(
+        attribute(global("source")),
+        text(concat!("wave_2d ", env!("CARGO_PKG_VERSION")))
+    );
+    match attribute(global("history")) {
+        AttributeValue::Str(history) => {
+            assert!(history.ends_with("--dx 0.5 --dt 0.01"), "{}", history)
+        }
+        other => panic!("history is {:?}", other),
+    }
+    match attribute(global("wave_2d_settings")) {
+        AttributeValue::Str(settings) => {
+            let settings: serde_json::Value = serde_json::from_str(&settings).unwrap();
+            assert_eq!(
+                (settings["dx"].as_f64(), settings["dt"].as_f64()),
+                (Some(0.5), Some(0.01))
+            );
+        }
+        other => panic!("wave_2d_settings is {:?}", other),
+    }
+    assert_eq!(
+        attribute(global("tile_row_starts")),
+        AttributeValue::Ulonglongs(vec![0, 100])
+    );
+    assert_eq!(
+        attribute(global("tile_col_starts")),
+        AttributeValue::Ulonglongs(vec![0, 50, 100])
+    );
+
+    // frames run along time, not the old frame dimension
+    let data = file.variable("data").unwrap();
+    let dims: Vec<_> = data
+        .dimensions()
+        .iter()
+        .map(|d| (d.name(), d.len()))
+        .collect();
+    assert_eq!(
+        dims,
+        [
+            ("time".to_string(), 3),
+            ("y".to_string(), 100),
+            ("x".to_string(), 100)
+        ]
+    );
+    assert_eq!(
+        attribute(data.attribute_value("coordinates")),
+        text("iteration")
+    );
+    let iteration = file.variable("iteration").unwrap();
+    assert_eq!(iteration.get_values::<u64, _>(..).unwrap(), [0, 20, 40]);
+    let time = file.variable("time").unwrap();
+    assert_eq!(
+        time.get_values::<f64, _>(..).unwrap(),
+        [0.0, 20.0 * 0.01, 40.0 * 0.01]
+    );
+    assert_eq!(attribute(time.attribute_value("units")), text("s"));
+    assert_eq!(attribute(time.attribute_value("axis")), text("T"));
+
+    let metres: Vec<f64> = (0..100).map(|i| i as f64 * 0.5).collect();
+    for (name, axis) in [("y", "Y"), ("x", "X")] {
+        let coord = file.variable(name).unwrap();
+        assert_eq!(coord.get_values::<f64, _>(..).unwrap(), metres, "{}", name);
+        assert_eq!(attribute(coord.attribute_value("units")), text("m"));
+        assert_eq!(attribute(coord.attribute_value("axis")), text(axis));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
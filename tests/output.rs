@@ -0,0 +1,492 @@
+//! Which iterations a run writes out as frames, and how it stores them.
+
+mod common;
+
+use common::TempDir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::Instant;
+use wave_2d::controlblock::ControlBlock;
+use wave_2d::fields::Field;
+
+const T500: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/t500.config");
+const PROBES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/probes.config");
+
+/// Runs wave_2d once in a fresh directory `name`, which keeps its output.
+fn run_with(name: &str, args: &[&[&str]]) -> (TempDir, Output) {
+    let dir = TempDir::new(name);
+    let output = dir.run(args);
+    (dir, output)
+}
+
+fn frames(args: &[&str]) -> Vec<usize> {
+    let args = ["wave_2d", "-n", "50", "-i", "100"]
+        .iter()
+        .chain(args)
+        .map(|s| s.to_string())
+        .collect();
+    ControlBlock::new(args).output_iters()
+}
+
+#[test]
+fn every_iteration_by_default() {
+    assert_eq!(frames(&[]), (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn interval_counts_from_window_start() {
+    assert_eq!(frames(&["-f", "25"]), [0, 25, 50, 75]);
+    assert_eq!(
+        frames(&["-f", "20", "--output-start", "10", "--output-stop", "70"]),
+        [10, 30, 50]
+    );
+}
+
+#[test]
+fn snapshots_alone_replace_the_interval() {
+    assert_eq!(frames(&["--snapshots", "99,3,40,3"]), [3, 40, 99]);
+}
+
+#[test]
+fn snapshots_add_to_an_interval() {
+    assert_eq!(
+        frames(&["-f", "0", "--snapshots", "5"]),
+        [5],
+        "-f 0 leaves only the snapshots"
+    );
+    assert_eq!(
+        frames(&["-f", "40", "--output-start", "50", "--snapshots", "1,60"]),
+        [1, 50, 60, 90]
+    );
+}
+
+fn settings(args: &[&str]) -> ControlBlock {
+    let args = ["wave_2d", "-c", T500, "-n", "50"]
+        .iter()
+        .chain(args)
+        .map(|s| s.to_string())
+        .collect();
+    ControlBlock::new(args)
+}
+
+#[test]
+fn chunk_shapes() {
+    assert_eq!(settings(&[]).chunk, None);
+    assert_eq!(settings(&["--chunk", "frame"]).chunk, Some(vec![1, 50, 50]));
+    assert_eq!(
+        settings(&["--chunk", "4,25,50"]).chunk,
+        Some(vec![4, 25, 50])
+    );
+}
+
+#[test]
+fn packing_covers_twice_the_sources_by_default() {
+    // t500.config has a single source of amplitude 10
+    assert_eq!(settings(&["--precision", "int16"]).pack_range, 20.0);
+    let cb = settings(&["--precision", "int16", "--pack-range", "3.5"]);
+    assert_eq!(cb.pack_range, 3.5);
+}
+
+#[test]
+fn fields_keep_a_fixed_order() {
+    let cb = settings(&["--fields", "alpha,dudt,alpha"]);
+    assert_eq!(cb.fields, ["dudt", "alpha"]);
+    assert_eq!(
+        Field::selected(&cb.fields),
+        [Field::U, Field::Dudt, Field::Alpha]
+    );
+}
+
+/// Bits of every value of `name` in the netCDF file at `path`.
+fn nc_bits(path: &Path, name: &str) -> Vec<u64> {
+    let file = netcdf::open(path).unwrap();
+    let var = file.variable(name).unwrap();
+    let values = var.get_values::<f64, _>(..).unwrap();
+    values.iter().map(|v| v.to_bits()).collect()
+}
+
+#[test]
+fn tile_writes_run_on_every_transport() {
+    let dir = TempDir::new("tile-writes");
+    let run = |args: &[&str]| {
+        dir.run(&[
+            &["-c", T500, "-n", "200", "-i", "40", "-f", "10"],
+            &["-x", "3", "-y", "2", "--fields", "dudt,alpha"],
+            args,
+        ])
+    };
+    run(&["-o", "gathered.nc"]);
+    for transport in ["channel", "unix", "shm"] {
+        let output = format!("{}.nc", transport);
+        run(&["-o", &output, "--tile-writes", "--transport", transport]);
+        for name in ["data", "dudt", "alpha", "iteration", "time"] {
+            assert!(
+                nc_bits(&dir.join(&output), name) == nc_bits(&dir.join("gathered.nc"), name),
+                "{}: tile-written {} differs from the gathered one",
+                transport,
+                name
+            );
+        }
+    }
+}
+
+/// Wall time per step of `program` on a 500 x 500 grid writing a frame
+/// every `every` iterations: the difference between a run of 250 and one of
+/// 50 iterations, which leaves out start-up and the last flush, median of
+/// five.
+fn ms_per_step(dir: &TempDir, program: &Path, every: &str) -> f64 {
+    let time = |iters: &str| {
+        let started = Instant::now();
+        let status = Command::new(program)
+            .current_dir(dir.path())
+            .args(["-c", T500, "-n", "500", "-x", "2", "-y", "1"])
+            .args(["-f", every, "-i", iters])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "{}", program.display());
+        started.elapsed().as_secs_f64()
+    };
+    let mut runs: Vec<f64> = (0..5)
+        .map(|_| (time("250") - time("50")) / 200.0 * 1e3)
+        .collect();
+    runs.sort_by(f64::total_cmp);
+    runs[2]
+}
+
+/// Time per step with frames every iteration, every 5th, every 20th and
+/// none. `WAVE_2D_BASELINE` names another build of wave_2d to time
+/// alongside this one:
+///
+///     WAVE_2D_BASELINE=<path> cargo test --release --test output -- --ignored --nocapture
+#[test]
+#[ignore = "timing; run in release with --ignored --nocapture"]
+fn frame_output_time_per_step() {
+    let dir = TempDir::new("timing");
+    let mut programs = vec![("this build", PathBuf::from(env!("CARGO_BIN_EXE_wave_2d")))];
+    if let Some(baseline) = std::env::var_os("WAVE_2D_BASELINE") {
+        programs.push(("baseline", PathBuf::from(baseline)));
+    }
+    for every in ["1", "5", "20", "0"] {
+        for (name, program) in &programs {
+            let ms = ms_per_step(&dir, program, every);
+            println!("-f {:>2}  {:<10}  {:6.2} ms/step", every, name, ms);
+        }
+    }
+}
+
+#[test]
+fn format_follows_the_output_extension() {
+    assert_eq!(settings(&[]).format, "netcdf");
+    assert_eq!(settings(&["-o", "u.npz"]).format, "npz");
+    let cb = settings(&["--format", "npy"]);
+    assert_eq!(cb.output.to_str(), Some("output.npy"));
+}
+
+/// Header dict and data of a .npy file.
+fn npy(bytes: &[u8]) -> (String, &[u8]) {
+    assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+    let len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+    let dict = String::from_utf8(bytes[10..10 + len].to_vec()).unwrap();
+    assert_eq!((10 + len) % 64, 0, "data is not aligned");
+    (dict.trim().to_string(), &bytes[10 + len..])
+}
+
+/// Members of a stored zip archive, read through its local headers.
+fn unzip(bytes: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+    let mut members = vec![];
+    let mut at = 0;
+    while bytes[at..at + 4] == [0x50, 0x4b, 0x03, 0x04] {
+        assert_eq!(u16_at(at + 8), 0, "member is compressed");
+        let (name_len, extra_len) = (u16_at(at + 26), u16_at(at + 28));
+        let name = String::from_utf8(bytes[at + 30..at + 30 + name_len].to_vec()).unwrap();
+        // sizes are in the zip64 extra field
+        let extra = at + 30 + name_len;
+        let size = u64::from_le_bytes(bytes[extra + 4..extra + 12].try_into().unwrap()) as usize;
+        let data = extra + extra_len;
+        members.push((name, bytes[data..data + size].to_vec()));
+        at = data + size;
+    }
+    members
+}
+
+#[test]
+fn numpy_outputs_hold_the_frames() {
+    let dir = TempDir::new("numpy");
+    let run = |args: &[&str]| {
+        dir.run(&[
+            &[
+                "-c", T500, "-n", "300", "-i", "30", "-f", "10", "-x", "3", "-y", "2",
+            ],
+            args,
+        ])
+    };
+    run(&["-o", "u.npy"]);
+    run(&["-o", "u.npz", "--fields", "dudt,alpha", "--tile-writes"]);
+
+    let single = fs::read(dir.join("u.npy")).unwrap();
+    let (dict, data) = npy(&single);
+    assert_eq!(
+        dict,
+        "{'descr': '<f8', 'fortran_order': False, 'shape': (3, 300, 300), }"
+    );
+    assert!(
+        data.chunks_exact(8).any(|v| v != [0; 8]),
+        "frames are empty"
+    );
+
+    let members = unzip(&fs::read(dir.join("u.npz")).unwrap());
+    let names: Vec<&str> = members.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "data.npy",
+            "dudt.npy",
+            "alpha.npy",
+            "iteration.npy",
+            "time.npy"
+        ]
+    );
+    assert_eq!(
+        npy(&members[0].1).1,
+        data,
+        "gathered and tile writes differ"
+    );
+    assert!(npy(&members[2].1).0.contains("'shape': (300, 300)"));
+    let (dict, iters) = npy(&members[3].1);
+    assert!(dict.contains("'descr': '<u8'") && dict.contains("'shape': (3,)"));
+    let iters: Vec<u64> = iters
+        .chunks_exact(8)
+        .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+        .collect();
+    assert_eq!(iters, [0, 10, 20]);
+    assert!(!dir.join("u.npz.arrays").exists());
+}
+
+#[test]
+fn sampling_shapes_the_frames() {
+    let cb = settings(&["--crop", "5,10,20,23", "--stride", "3"]);
+    let sampling = cb.sampling();
+    assert_eq!((sampling.rows(), sampling.cols()), (7, 8));
+    assert_eq!((sampling.row_of(1), sampling.col_of(2)), (8, 16));
+    // rows 5, 8, ... 23 of the crop; a tile of rows 0..12 holds 5, 8 and 11
+    assert_eq!(sampling.owned_rows(0, 12), 0..3);
+    assert_eq!(sampling.owned_rows(12, 38), 3..7);
+    assert_eq!(sampling.owned_cols(40, 10), 8..8);
+
+    let cb = settings(&["--crop", "5,10,20,23", "--stride", "3", "--average"]);
+    let sampling = cb.sampling();
+    assert_eq!((sampling.rows(), sampling.cols()), (6, 7));
+    assert_eq!(sampling.row_position(0), 6.0);
+}
+
+#[test]
+fn cropped_frames_are_sampled_from_the_grid() {
+    let dir = TempDir::new("sampling");
+    let run = |args: &[&str]| {
+        dir.run(&[&["-c", T500, "-n", "300", "-i", "40", "-f", "20"], args]);
+        let bytes = fs::read(dir.join(args[1])).unwrap();
+        let (dict, data) = npy(&bytes);
+        let values: Vec<f64> = data
+            .chunks_exact(8)
+            .map(|v| f64::from_le_bytes(v.try_into().unwrap()))
+            .collect();
+        (dict, values)
+    };
+    let (_, full) = run(&["-o", "full.npy"]);
+    let crop = ["--crop", "60,90,130,101", "--stride", "4"];
+    let (dict, picked) = run(&[&["-o", "picked.npy", "-x", "3", "-y", "2"], &crop[..]].concat());
+    assert!(dict.contains("'shape': (2, 33, 26)"), "{}", dict);
+    let (dict, averaged) = run(&[
+        &["-o", "averaged.npy", "-x", "3", "-y", "2", "--tile-writes"],
+        &crop[..],
+        &["--average"],
+    ]
+    .concat());
+    assert!(dict.contains("'shape': (2, 32, 25)"), "{}", dict);
+
+    let cell = |frame: usize, r: usize, c: usize| full[(frame * 300 + r) * 300 + c];
+    for frame in 0..2 {
+        for i in 0..33 {
+            for j in 0..26 {
+                let value = picked[(frame * 33 + i) * 26 + j];
+                assert_eq!(value, cell(frame, 60 + 4 * i, 90 + 4 * j));
+            }
+        }
+        for i in 0..32 {
+            for j in 0..25 {
+                let mean = (0..16)
+                    .map(|k| cell(frame, 60 + 4 * i + k / 4, 90 + 4 * j + k % 4))
+                    .sum::<f64>()
+                    / 16.0;
+                let value = averaged[(frame * 32 + i) * 25 + j];
+                assert!((value - mean).abs() <= 1e-12 * mean.abs().max(1.0));
+            }
+        }
+    }
+    assert!(picked.iter().any(|&v| v != 0.0), "frames are empty");
+}
+
+#[test]
+fn vtk_collection_lists_every_frame() {
+    let (dir, _) = run_with(
+        "vtk",
+        &[
+            &[
+                "-c", T500, "-n", "60", "-i", "30", "-f", "10", "-x", "2", "-y", "2",
+            ],
+            &["-o", "run.pvd", "--fields", "energy,alpha", "--dt", "0.5"],
+            &["--tile-writes"],
+        ],
+    );
+
+    let pvd = fs::read_to_string(dir.join("run.pvd")).unwrap();
+    let sets: Vec<&str> = pvd
+        .lines()
+        .filter(|line| line.contains("<DataSet "))
+        .collect();
+    assert_eq!(sets.len(), 3);
+    assert!(
+        sets[2].contains("timestep=\"10.0\"") && sets[2].contains("file=\"run/run_000002.vti\"")
+    );
+
+    let vti = fs::read(dir.join("run/run_000002.vti")).unwrap();
+    let start = vti.windows(5).position(|w| w == b"\n   _").unwrap() + 5;
+    let head = String::from_utf8(vti[..start].to_vec()).unwrap();
+    assert!(head.contains("WholeExtent=\"0 59 0 59 0 0\""));
+    let array = 8 + 60 * 60 * 8;
+    for (k, name) in ["data", "energy", "alpha"].iter().enumerate() {
+        assert!(head.contains(&format!(
+            "Name=\"{}\" format=\"appended\" offset=\"{}\"",
+            name,
+            k * array
+        )));
+        let len = &vti[start + k * array..start + k * array + 8];
+        assert_eq!(u64::from_le_bytes(len.try_into().unwrap()), 60 * 60 * 8);
+    }
+    assert!(vti[start + 3 * array..].starts_with(b"\n  </AppendedData>"));
+}
+
+#[test]
+fn png_frames_show_obstacles_and_sources() {
+    let (dir, _) = run_with(
+        "png",
+        &[
+            &[
+                "-c", T500, "-n", "200", "-i", "20", "-f", "10", "-x", "2", "-y", "2",
+            ],
+            &[
+                "-o",
+                "frames/u.png",
+                "--colormap",
+                "viridis",
+                "--tile-writes",
+            ],
+            &["--obstacle-color", "ff00ff", "--source-color", "#00ffff"],
+        ],
+    );
+    assert!(!dir.join("frames/u_000002.png").exists());
+
+    let png = fs::read(dir.join("frames/u_000001.png")).unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..24], [0, 0, 0, 200, 0, 0, 0, 200]);
+    let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+    let len = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+    let rows =
+        miniz_oxide::inflate::decompress_to_vec_zlib(&png[idat + 4..idat + 4 + len]).unwrap();
+    let pixel = |r: usize, c: usize| &rows[r * (1 + 3 * 200) + 1 + 3 * c..][..3];
+    // t500.config has an obstacle at rows 70..95 of columns 150..155 and a
+    // source at (100, 140)
+    assert_eq!(pixel(80, 152), [255, 0, 255]);
+    assert_eq!(pixel(100, 140), [0, 255, 255]);
+    assert_eq!(pixel(100, 142), [0, 255, 255]);
+    assert_ne!(pixel(150, 20), [255, 0, 255]);
+}
+
+#[test]
+fn y4m_streams_to_stdout() {
+    let (_, output) = run_with(
+        "y4m",
+        &[
+            &[
+                "-c", T500, "-n", "200", "-i", "20", "-f", "5", "-x", "2", "-y", "2",
+            ],
+            &["--transport", "unix", "-o", "-", "--fps", "12"],
+        ],
+    );
+    // the run's messages stay out of the stream
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Decomposition"));
+
+    let stream = output.stdout;
+    let header = b"YUV4MPEG2 W200 H200 F12:1 Ip A1:1 C444 XCOLORRANGE=LIMITED\n";
+    assert!(stream.starts_with(header));
+    let frame = 6 + 3 * 200 * 200;
+    assert_eq!(stream.len(), header.len() + 4 * frame);
+    let last = &stream[header.len() + 3 * frame..];
+    assert!(last.starts_with(b"FRAME\n"));
+    // the source at (100, 140) is marked in green, 00ff00
+    let cell = 100 * 200 + 140;
+    let planes = &last[6..];
+    let yuv = [0, 1, 2].map(|k| planes[k * 200 * 200 + cell]);
+    assert_eq!(yuv, [145, 54, 34]);
+}
+
+#[test]
+fn probes_record_every_iteration() {
+    let (dir, _) = run_with(
+        "probes",
+        &[
+            &["-c", PROBES, "-i", "120", "-f", "40", "-x", "3", "-y", "2"],
+            &["-o", "u.npy", "--tile-writes"],
+        ],
+    );
+
+    let traces = fs::read_to_string(dir.join("u_probes.csv")).unwrap();
+    let mut lines = traces.lines();
+    assert_eq!(
+        lines.next(),
+        Some("iteration,time,behind,centre,probe_140_99")
+    );
+    let rows: Vec<Vec<f64>> = lines
+        .map(|line| line.split(',').map(|v| v.parse().unwrap()).collect())
+        .collect();
+    assert_eq!(rows.len(), 120);
+    assert!(rows.iter().enumerate().all(|(i, row)| row[0] == i as f64));
+
+    // the traces hold the values the frames show at the probes' cells
+    let frames = fs::read(dir.join("u.npy")).unwrap();
+    let (_, data) = npy(&frames);
+    let cell = |frame: usize, r: usize, c: usize| {
+        let at = ((frame * 300 + r) * 300 + c) * 8;
+        f64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    };
+    for (frame, iter) in [(1, 40), (2, 80)] {
+        let expected = [
+            cell(frame, 100, 170),
+            cell(frame, 150, 150),
+            cell(frame, 140, 99),
+        ];
+        assert_eq!(rows[iter][2..], expected);
+    }
+    assert!(rows[80][2] != 0.0, "the wave has not reached a probe");
+}
+
+#[test]
+fn probes_must_lie_on_the_grid() {
+    let cb = settings(&[]);
+    assert!(cb.probes.is_empty());
+    assert_eq!(cb.probe_output.to_str(), Some("output_probes.csv"));
+    let run = |n: &str| {
+        Command::new(env!("CARGO_BIN_EXE_wave_2d"))
+            .args(["-c", PROBES, "-n", n, "-i", "1", "--verify"])
+            .output()
+            .unwrap()
+    };
+    let output = run("120");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("off the 120x120 grid"));
+}
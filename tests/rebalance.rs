@@ -0,0 +1,218 @@
+//! Moving tile boundaries at runtime must carry every cell over unchanged:
+//! the tiles of a 2 x 1 layout of a 64 x 64 grid against one tile holding
+//! the whole grid.
+
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use wave_2d::buffer::{ActiveBox, ArrBuffer, Axis, MASK_BLOCK};
+use wave_2d::controlblock::ControlBlock;
+use wave_2d::halo::{migrate, Direction, HaloError, HaloMessage, HaloState};
+use wave_2d::transport::{ChannelTransport, HaloTransport};
+
+/// Longest message the chunked transport carries.
+const MAX_LEN: usize = 50;
+/// Grid columns from this one on are an obstacle.
+const WALL: usize = 33;
+/// Grid columns of the patch of wave, which straddles the tile boundary.
+const PATCH: Range<usize> = 27..38;
+
+fn control_block(px: &str) -> ControlBlock {
+    let args = ["wave_2d", "-n", "64", "-x", px, "-y", "1"];
+    ControlBlock::new(args.iter().map(|s| s.to_string()).collect())
+}
+
+/// Value of `plane` (prev, cur, alpha) at row `r` and column `c` of the
+/// whole grid, ghost cells included: a patch of wave in front of a wall.
+fn value(plane: usize, r: usize, c: usize) -> f64 {
+    match plane {
+        2 if c >= WALL => 0.0,
+        2 => 0.25,
+        _ if (11..21).contains(&r) && PATCH.contains(&c) => {
+            (plane + 1) as f64 + 1e-3 * (r * 100 + c) as f64
+        }
+        _ => 0.0,
+    }
+}
+
+/// Fills the tile with `value`, its ghost ring at rest.
+fn fill(u: &mut ArrBuffer) {
+    let (grid_m, grid_n) = (u.grid_m, u.grid_n);
+    let planes: Vec<Vec<f64>> = (0..3)
+        .map(|plane| {
+            (0..grid_m * grid_n)
+                .map(|i| {
+                    let (r, c) = (i / grid_n, i % grid_n);
+                    let ghost = r == 0 || r == grid_m - 1 || c == 0 || c == grid_n - 1;
+                    if ghost && plane < 2 {
+                        0.0
+                    } else {
+                        value(plane, r, u.start_col + c)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    u.restore(&planes[0], &planes[1], &planes[2]);
+}
+
+/// Checks that the tile holds the whole grid's cells of its columns, a
+/// cleared next plane, and the active box and block mask of those cells.
+fn assert_holds_its_columns(u: &ArrBuffer, whole: &ArrBuffer) {
+    let tile = format!("tile at columns {}..{}", u.start_col, u.start_col + u.n);
+    for r in 0..u.grid_m {
+        for c in 1..u.grid_n - 1 {
+            let (wr, wc) = (r, u.start_col + c);
+            let got = [u.prev_v(r, c), u.cur_v(r, c), u.alp_v(r, c), u.nxt_v(r, c)];
+            let expected = [
+                whole.prev_v(wr, wc),
+                whole.cur_v(wr, wc),
+                whole.alp_v(wr, wc),
+                0.0,
+            ];
+            assert_eq!(
+                got.map(f64::to_bits),
+                expected.map(f64::to_bits),
+                "{}: cell ({}, {})",
+                tile,
+                r,
+                c
+            );
+        }
+    }
+    // the patch of wave, clipped to the tile's interior columns
+    let (c0, c1) = (
+        PATCH.start.max(u.start_col + 1),
+        PATCH.end.min(u.start_col + u.n + 1),
+    );
+    let active = (c0 < c1).then(|| ActiveBox {
+        r0: 11,
+        r1: 21,
+        c0: c0 - u.start_col,
+        c1: c1 - u.start_col,
+    });
+    assert_eq!(u.active, active, "{}", tile);
+    for br in 0..u.grid_m.div_ceil(MASK_BLOCK) {
+        for bc in 0..u.grid_n.div_ceil(MASK_BLOCK) {
+            // a block is masked when its first interior column is in the wall
+            let first = u.start_col + (bc * MASK_BLOCK).max(1);
+            assert_eq!(
+                u.is_masked_block(br, bc),
+                first >= WALL,
+                "{}: block ({}, {})",
+                tile,
+                br,
+                bc
+            );
+        }
+    }
+}
+
+#[test]
+fn reslice_keeps_the_planes_of_a_shifted_box() {
+    let cb = control_block("2");
+    let whole_cb = control_block("1");
+    let mut whole = ArrBuffer::new(&whole_cb, 0);
+    fill(&mut whole);
+    // tile 0 gives up columns 0..3 and takes 32..51 in; tile 1 takes 20..32
+    // in and gives up 60..64
+    for (tid, new_start, new_end) in [(0, 3, 51), (1, 20, 60)] {
+        let mut u = ArrBuffer::new(&cb, tid);
+        fill(&mut u);
+        let old_end = u.start_col + u.n;
+        let low = whole.strip(Axis::Cols, new_start, u.start_col.max(new_start));
+        let high = whole.strip(Axis::Cols, old_end.min(new_end), new_end);
+        u.reslice(Axis::Cols, new_start, new_end - new_start, &low, &high);
+        assert_eq!(
+            (u.start_col, u.n, u.grid_n),
+            (new_start, new_end - new_start, new_end - new_start + 2)
+        );
+        assert_holds_its_columns(&u, &whole);
+    }
+}
+
+/// A channel link carrying at most `MAX_LEN` values a message, as a shared
+/// memory one would.
+struct Chunked(ChannelTransport);
+
+impl HaloTransport for Chunked {
+    fn has_neighbour(&self, dir: Direction) -> bool {
+        self.0.has_neighbour(dir)
+    }
+
+    async fn send(&mut self, msg: HaloMessage) -> Result<(), HaloError> {
+        if msg.data.len() > MAX_LEN {
+            return Err(HaloError::Length {
+                dir: msg.dir,
+                expected: MAX_LEN,
+                got: msg.data.len(),
+            });
+        }
+        self.0.send(msg).await
+    }
+
+    async fn recv(&mut self) -> Result<HaloMessage, HaloError> {
+        self.0.recv().await
+    }
+
+    fn max_message_len(&self) -> usize {
+        MAX_LEN
+    }
+}
+
+#[tokio::test]
+async fn chunked_migration_round_trip_is_exact() {
+    let cb = control_block("2");
+    let whole_cb = control_block("1");
+    let mut whole = ArrBuffer::new(&whole_cb, 0);
+    fill(&mut whole);
+    let tiles: Vec<_> = (0..2)
+        .map(|tid| {
+            let mut u = ArrBuffer::new(&cb, tid);
+            fill(&mut u);
+            Arc::new(Mutex::new(u))
+        })
+        .collect();
+    let planes = |tile: &Arc<Mutex<ArrBuffer>>| {
+        let u = tile.lock().unwrap();
+        u.planes()
+            .map(|plane| plane.iter().map(|v| v.to_bits()).collect::<Vec<_>>())
+    };
+    let before: Vec<_> = tiles.iter().map(planes).collect();
+    let mut mesh = ChannelTransport::mesh(2, 1).into_iter().map(Chunked);
+    let mut links = [mesh.next().unwrap(), mesh.next().unwrap()];
+    let mut states = [HaloState::new(), HaloState::new()];
+
+    // columns 32..40 go over to tile 0 and back, 3 planes of 66 rows each
+    let chunks = (3 * 8 * 66usize).div_ceil(MAX_LEN);
+    for (step, boundary) in [(0, 40), (chunks as u64, 32)] {
+        let [link0, link1] = &mut links;
+        let [state0, state1] = &mut states;
+        let (left, right) = tokio::join!(
+            migrate(
+                Arc::clone(&tiles[0]),
+                state0,
+                link0,
+                step,
+                Axis::Cols,
+                0..boundary,
+                chunks
+            ),
+            migrate(
+                Arc::clone(&tiles[1]),
+                state1,
+                link1,
+                step,
+                Axis::Cols,
+                boundary..64,
+                chunks
+            ),
+        );
+        left.unwrap();
+        right.unwrap();
+        for tile in &tiles {
+            assert_holds_its_columns(&tile.lock().unwrap(), &whole);
+        }
+    }
+    let after: Vec<_> = tiles.iter().map(planes).collect();
+    assert!(after == before, "the round trip changed the tiles");
+}
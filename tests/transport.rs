@@ -0,0 +1,79 @@
+//! Halo messages must arrive exactly as they were sent, whatever carries
+//! them.
+
+use std::path::PathBuf;
+use std::thread;
+use wave_2d::halo::{Direction, HaloError, HaloMessage};
+use wave_2d::transport::{HaloTransport, ShmTransport};
+
+const MAX_LEN: usize = 37;
+const STEPS: u64 = 200;
+
+/// The message tile `tid` sends for `step`: lengths up to the limit and
+/// values whose bits a lossy copy would change.
+fn message(tid: usize, step: u64) -> HaloMessage {
+    let len = (step as usize * 7 + tid) % (MAX_LEN + 1);
+    let data = (0..len)
+        .map(|i| match (i + step as usize) % 5 {
+            0 => f64::from_bits(0x7ff8_0000_dead_beef),
+            1 => -0.0,
+            2 => f64::MIN_POSITIVE / 3.0,
+            _ => (i as f64 + 0.1 * step as f64).sin() * 1e-3,
+        })
+        .collect();
+    HaloMessage {
+        dir: if tid == 0 {
+            Direction::Right
+        } else {
+            Direction::Left
+        },
+        step,
+        stop_at: step.is_multiple_of(3).then_some(step + 40),
+        data,
+    }
+}
+
+/// Sends every step to the other tile of a 2 x 1 layout and checks what
+/// comes back, bit for bit.
+fn exchange(path: PathBuf, tid: usize) {
+    let mut transport = ShmTransport::open(&path, tid, 2, 1, MAX_LEN).unwrap();
+    assert_eq!(transport.max_message_len(), MAX_LEN);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        for step in 0..STEPS {
+            transport.send(message(tid, step)).await.unwrap();
+            let got = transport.recv().await.unwrap();
+            let sent = message(1 - tid, step);
+            assert_eq!(
+                (got.dir, got.step, got.stop_at),
+                (sent.dir, sent.step, sent.stop_at)
+            );
+            let bits = |data: &[f64]| data.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+            assert_eq!(bits(&got.data), bits(&sent.data), "step {}", step);
+        }
+        let mut long = message(tid, STEPS);
+        long.data = vec![1.0; MAX_LEN + 1];
+        assert!(matches!(
+            transport.send(long).await,
+            Err(HaloError::Length { expected: MAX_LEN, got, .. }) if got == MAX_LEN + 1
+        ));
+    });
+}
+
+#[test]
+fn shm_messages_arrive_bit_for_bit() {
+    let path = std::env::temp_dir().join(format!("wave_2d-shm-{}", std::process::id()));
+    ShmTransport::create_file(&path, 2, MAX_LEN).unwrap();
+    let tiles: Vec<_> = (0..2)
+        .map(|tid| {
+            let path = path.clone();
+            thread::spawn(move || exchange(path, tid))
+        })
+        .collect();
+    for tile in tiles {
+        tile.join().unwrap();
+    }
+    std::fs::remove_file(&path).unwrap();
+}
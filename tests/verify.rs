@@ -0,0 +1,139 @@
+//! Decomposed runs must reproduce the serial run bit for bit. Every test
+//! drives the binary in `--verify` mode, which fails on the first frame that
+//! differs from a single-tile run of the same configuration.
+
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use wave_2d::decomposition::check_layout;
+
+const LAYOUTS: [(usize, usize); 5] = [(2, 1), (1, 3), (2, 2), (3, 2), (4, 3)];
+/// Larger scenes are cut down to this size to keep the runs short.
+const MAX_N: usize = 1200;
+const ITERS: &str = "60";
+const OUTPUT_FREQ: &str = "5";
+
+fn configs() -> Vec<PathBuf> {
+    let mut configs: Vec<PathBuf> = fs::read_dir("tests")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "config"))
+        .collect();
+    configs.sort();
+    assert!(!configs.is_empty(), "no tests/*.config found");
+    configs
+}
+
+fn grid_size(config: &Path) -> usize {
+    let value: Value = serde_json::from_str(&fs::read_to_string(config).unwrap()).unwrap();
+    let n = value.get("-n").and_then(Value::as_u64).unwrap_or(100) as usize;
+    n.min(MAX_N)
+}
+
+fn verify(config: &Path, px: usize, py: usize, extra: &[&str]) {
+    let n = grid_size(config);
+    let output = Command::new(env!("CARGO_BIN_EXE_wave_2d"))
+        .arg("-c")
+        .arg(config)
+        .args(["-n", &n.to_string(), "-i", ITERS, "-f", OUTPUT_FREQ])
+        .args(["-x", &px.to_string(), "-y", &py.to_string(), "--verify"])
+        .args(extra)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{} on {}x{} {:?} does not match the serial run:\n{}{}",
+        config.display(),
+        px,
+        py,
+        extra,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn decomposed_runs_match_serial_runs() {
+    for config in configs() {
+        let n = grid_size(&config);
+        for (px, py) in LAYOUTS {
+            if check_layout(n, n, px, py).is_ok() {
+                verify(&config, px, py, &[]);
+            }
+        }
+    }
+}
+
+#[test]
+fn weighted_split_matches_serial_run() {
+    verify(Path::new("tests/t500.config"), 3, 2, &["--balance"]);
+}
+
+#[test]
+fn rebalanced_run_matches_serial_run() {
+    verify(
+        Path::new("tests/t500.config"),
+        3,
+        2,
+        &["--rebalance", "10", "--rebalance-threshold", "1.0"],
+    );
+}
+
+#[test]
+fn process_transports_match_serial_run() {
+    for transport in ["unix", "shm"] {
+        verify(
+            Path::new("tests/t20.config"),
+            2,
+            2,
+            &["--transport", transport],
+        );
+    }
+}
+
+#[test]
+fn derived_fields_match_serial_run() {
+    let fields = ["--fields", "dudt,gradient,energy,alpha"];
+    verify(Path::new("tests/t500.config"), 3, 2, &fields);
+    verify(
+        Path::new("tests/t500.config"),
+        2,
+        2,
+        &[&fields[..], &["--transport", "unix"]].concat(),
+    );
+}
+
+#[test]
+fn averaged_output_matches_serial_run() {
+    let sampling = ["--crop", "10,20,250,270", "--stride", "4", "--average"];
+    verify(Path::new("tests/t500.config"), 3, 2, &sampling);
+    verify(
+        Path::new("tests/t500.config"),
+        3,
+        2,
+        &[
+            &sampling[..],
+            &["--rebalance", "10", "--rebalance-threshold", "1.0"],
+            &["--transport", "unix"],
+        ]
+        .concat(),
+    );
+}
+
+#[test]
+fn probe_traces_match_serial_run() {
+    verify(
+        Path::new("tests/probes.config"),
+        3,
+        2,
+        &[
+            "--rebalance",
+            "10",
+            "--rebalance-threshold",
+            "1.0",
+            "--transport",
+            "unix",
+        ],
+    );
+}